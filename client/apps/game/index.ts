@@ -1,4 +1,4 @@
-import { App, Engine, Data, post_stringify, SystemLogger, DistModel  } from "../../khameleon-core";
+import { App, Engine, Data, SystemLogger, DistModel  } from "../../khameleon-core";
 import { Markov } from "../../khameleon-core/predictor/markov";
 import * as d3 from "d3";
 import * as _ from 'underscore';
@@ -16,6 +16,7 @@ export class Game implements App {
   public appName: string = "Game";
   private future: number;
   private nactions: number;
+  private tickMs: number;
   private time: number;
   private lastMoves: Array<number>;
   private predictor: Markov;
@@ -28,6 +29,7 @@ export class Game implements App {
     this.path = (sysconfig && sysconfig.path) ? sysconfig.path : "static/data/";
     this.future = 3;
     this.nactions = 5;
+    this.tickMs = (sysconfig && sysconfig.tick_ms) ? sysconfig.tick_ms : 100;
     this.time = 0;
     this.lastMoves = new Array<number>(0);
     this.moved = false;
@@ -54,12 +56,17 @@ export class Game implements App {
     }
 
     getState() {
-     let appstate =  { "future": this.future,
-                       "nactions": this.nactions
+     let appstate: any =  { "future": this.future,
+                       "nactions": this.nactions,
+                       "tick_ms": this.tickMs
      };
+      // the server learns the transitions of each player across sessions
+      if (this.sysconfig && this.sysconfig.user)
+        appstate["user"] = this.sysconfig.user;
 
       let state=  { "appname": this.appName,
                    "cachesize": this.sysconfig.cachesize,
+                   "cachebytes": this.sysconfig.cachebytes,
                    "state": appstate
       };
 
@@ -81,17 +88,12 @@ export class Game implements App {
       else {
         this.moved = false;
       }
+      // frames are keyed by tick and the actions that lead to them, in order
       var tempMoves = this.lastMoves.slice(-this.future);
-      tempMoves.sort();
       var action = this.lastMoves[this.lastMoves.length - 1];
       var prevaction = this.lastMoves[this.lastMoves.length - 2];
       this.predictor.updatestate(action, prevaction);
-      var num = 0;
-      for (let i=0; i<this.future; i++) {
-        num += Math.pow(this.nactions, this.future - 1 - i) * tempMoves[i];
-      }
-      console.log("NUM IS " + num);
-      var qid = (this.time * Math.pow(10, this.future) + num).toString();
+      var qid = this.time + ":" + tempMoves.join(",");
       // FOR TESTING ONLY
       // @Harrison vary the accuracy across .2, .4, .6, .8, 1.0
       var accuracy = 0.2;
@@ -99,7 +101,7 @@ export class Game implements App {
       if (rand < accuracy) {
         // no action frame, always returned
         // ensure cache hit
-        qid = (this.time * Math.pow(10, this.future) + Math.pow(this.nactions, this.future) - 1).toString();
+        qid = this.time + ":" + new Array(this.future).fill(this.nactions - 1).join(",");
       } else {
         // force cache miss
         qid = "";
@@ -113,7 +115,11 @@ export class Game implements App {
       }
       var dists = { model: DistModel.Markov, data: serverQuery };
       console.log("send dist", dists)
-      post_stringify("/post_dist", dists);
+      if (window.gsyslogger && window.session_config.logTrace)
+        window.gsyslogger.addSessionEvent("dist", {data: dists, time: Date.now()});
+      this.engine.sendDist(dists, [["tick", {tag: "int", value: serverQuery.tick}],
+                                   ["action", {tag: "int", value: serverQuery.action}],
+                                   ["dist", {tag: "dense", value: serverQuery.dist}]]);
       this.time = this.time + 1;
     }
 
@@ -167,7 +173,7 @@ export class Game implements App {
             .attr("height", dim)
             .attr("preserveAspectRatio", "xMidYMin slice");
 
-        setInterval(this.tick.bind(this), 100);
+        setInterval(this.tick.bind(this), this.tickMs);
 
         let that = this;
 
@@ -217,30 +223,39 @@ export class Game implements App {
   }
 
   decodeBlock(block: any) {
+    // the block id is in the header, see khameleon-core/utils/ws.ts
     let offset = 0;
-    let block_id = new Uint32Array(block, 0, 1)[0]; offset += 4;  // u32
+    // 0: concatenate the prefix of blocks, 1: render the last block (progressive layer)
+    let render = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
+    let width = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
+    let height = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
     let content_len = new Uint32Array(block, offset, 1)[0]; offset += 8; // u64
     let content = new Uint8Array(block, offset, content_len);
 
-    //console.log("storeData: ", key, block_id, nblocks);
-    let decodedblock = {"block_id": block_id, "content": content };
+    let decodedblock = {"render": render, "width": width,
+                        "height": height, "content": content };
     return decodedblock;
   }
 
   construct(req, blocks, nblocks) : Data {
     let image_data: any[] = [];
+    let render = 0;
     for (var i = 0; i < blocks.size; i++) {
       if ( blocks.has(i) ) {
         let {data} = blocks.get(i);
         let block = this.decodeBlock(data);
         if (block == undefined) break;
         image_data.push( new Uint8Array( block.content ) );
+        render = block.render;
       } else {
         break;
       }
     }
 
-    let img_dir  = URL.createObjectURL(new Blob( image_data ));
+    // every progressive layer is a complete image, the last one is the best
+    let blob = (render == 1) ? new Blob( image_data.slice(-1) ) : new Blob( image_data );
+
+    let img_dir  = URL.createObjectURL(blob);
 
     d3.select("#utility")
       .text(req+" has "+image_data.length + " blocks out of "+nblocks);
@@ -22,6 +22,7 @@ export class TestApp implements App {
   let appstate = {}
   let state = {"appname": this.appName, // used to communicate to the server which app to load
                "cachesize": this.sysconfig.cachesize,
+               "cachebytes": this.sysconfig.cachebytes,
                "state": appstate, // if any specific app data need to be passed to the backend
               };
 
@@ -85,13 +86,17 @@ export class TestApp implements App {
   
  // decode binary data recieved from the server
  decodeBlock(block: any) {
+    // the block id is in the header, see khameleon-core/utils/ws.ts
     let offset = 0;
-    let block_id = new Uint32Array(block, 0, 1)[0]; offset += 4;  // u32
+    // 0: concatenate the prefix of blocks, 1: render the last block (progressive layer)
+    let render = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
+    let width = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
+    let height = new Uint32Array(block, offset, 1)[0]; offset += 4; // u32
     let content_len = new Uint32Array(block, offset, 1)[0]; offset += 8; // u64
     let content = new Uint8Array(block, offset, content_len);
-    
-    //console.log("storeData: ", key, block_id, nblocks);
-    let decodedblock = {"block_id": block_id, "content": content };
+
+    let decodedblock = {"render": render, "width": width,
+                        "height": height, "content": content };
     return decodedblock;
   }
   
@@ -100,18 +105,23 @@ export class TestApp implements App {
  // data for rendering
  construct(req, blocks, nblocks: number) : Data {
     let image_data: any[] = [];
+    let render = 0;
     for (var i = 0; i < blocks.size; i++) {
       if ( blocks.has(i) ) {
         let {data} = blocks.get(i);
         let block = this.decodeBlock(data);
         if (block == undefined) break; 
         image_data.push( new Uint8Array( block.content ) );
+        render = block.render;
       } else {
         break;
       }
     }
+
+    // every progressive layer is a complete image, the last one is the best
+    let blob = (render == 1) ? new Blob( image_data.slice(-1) ) : new Blob( image_data );
     
-    let img_dir  = URL.createObjectURL(new Blob( image_data ));
+    let img_dir  = URL.createObjectURL(blob);
     
     d3.select("#utility")
       .text(req+" has "+image_data.length + " blocks out of "+nblocks);
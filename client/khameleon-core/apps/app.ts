@@ -1,6 +1,8 @@
 import { Engine } from "../engine";
 
 export interface Header {
+  // block sequence number in the session, acked to the server
+  seq: number;
   blockid: number;
   nblocks: number;
   key: string;
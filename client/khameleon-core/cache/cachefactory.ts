@@ -8,7 +8,7 @@ export class CacheFactory {
         if (cacheConfig == undefined) { cacheConfig = {}; }
         const cacheSize = cacheConfig.cacheSize || 10;
         if (cacheConfig.cache === "ringIndex") {
-          return new RingIndex(cacheSize);
+          return new RingIndex(cacheSize, cacheConfig.cacheBytes);
         } else {
           return new LRUcache(cacheSize);
         }
@@ -5,32 +5,44 @@ import { Header } from "../apps";
 interface slot {
   key: string;
   blockid: number;
+  size: number;
 };
 
 export class RingIndex extends EventEmitter implements Cache {
   private cacheSize: number;
+  private cacheBytes: number | undefined;
+  private usedBytes: number = 0;
   
   private blocksIndex: Map<string, IndexData> = new Map();
   private ringIndex: Array<slot> = []
   
   /**
    * @cacheSize how many blocks the cache can hold
+   * @cacheBytes optional: how many bytes the cache can hold, for blocks of different sizes
    */
-  constructor(cacheSize: number) {
+  constructor(cacheSize: number, cacheBytes?: number) {
     super();
     this.cacheSize = cacheSize;
+    this.cacheBytes = cacheBytes;
   }
 
   write(data: any, header: Header, blockIdx: number) {
     
     let { key, blockid, nblocks } = header;
 
-    if (this.ringIndex.length + 1 > this.cacheSize) {
+    let size = data.byteLength || 0;
+    if (this.cacheBytes) {
+      while (this.ringIndex.length > 0 && this.usedBytes + size > this.cacheBytes) {
+        let slot = this.ringIndex.shift()
+        if (slot) this.evict(slot.key, slot.blockid, slot.size);
+      }
+    } else if (this.ringIndex.length + 1 > this.cacheSize) {
       let slot = this.ringIndex.shift()
-      if (slot) this.evict(slot.key, slot.blockid);
+      if (slot) this.evict(slot.key, slot.blockid, slot.size);
     }
 
-    this.ringIndex.push({key, blockid});
+    this.ringIndex.push({key, blockid, size});
+    this.usedBytes += size;
     
     let dataindex = this.blocksIndex.get(key);
     let blocks: Blocks;
@@ -72,7 +84,8 @@ export class RingIndex extends EventEmitter implements Cache {
     }
   }
 
-  evict(key: string, blockid: number) {
+  evict(key: string, blockid: number, size: number) {
+    this.usedBytes -= size;
     let dataindex = this.blocksIndex.get(key);
     if (dataindex) {
       let { blocks, nblocks} = dataindex;
@@ -6,11 +6,13 @@ export class GuassianDistribution implements Distribution {
     private dist: {} = {};
     private gaussianX;
     private gaussianY;
+    private rho: number;
 
-    constructor(keyFunc, gaussianX?, gaussianY?) {
+    constructor(keyFunc, gaussianX?, gaussianY?, rho?: number) {
         this.keyFunc = keyFunc || qToKey;
         this.gaussianX = gaussianX;
         this.gaussianY = gaussianY;
+        this.rho = rho || 0;
     }
 
     from(q, keyFunc) {
@@ -53,7 +55,8 @@ export class GuassianDistribution implements Distribution {
                 xmu: this.gaussianX.mean,
                 xsigma: this.gaussianX.standardDeviation,
                 ymu: this.gaussianY.mean,
-                ysigma: this.gaussianY.standardDeviation
+                ysigma: this.gaussianY.standardDeviation,
+                rho: this.rho
             };
     };
 }
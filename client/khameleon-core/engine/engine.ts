@@ -1,6 +1,6 @@
 import { CacheFactory, Cache } from "../cache";
 import { EventEmitter } from "events";
-import { EventType, WS } from "../utils";
+import { EventType, WS, Field, encodeDist } from "../utils";
 import { Predictor } from "../predictor";
 import { App } from "../apps";
 import { post_stringify, SystemLogger } from "../utils";
@@ -11,6 +11,9 @@ export class Engine extends EventEmitter {
     public app: App;
     public predictor: Predictor;
     public requestManager: RequestManager = new RequestManager();
+    private ws: WS;
+    // encoding of predictor states negotiated at /initapp
+    public encoding: string = "json";
 
     public total_requests: number = 0;
     constructor(app: App, predictor: Predictor, config) {
@@ -35,16 +38,36 @@ export class Engine extends EventEmitter {
     //   }
     // }
 
+    // send a predictor state on the websocket, over http until it is open.
+    // `fields` is the same data in the compact binary encoding, used if the server accepts it
+    sendDist(dists: {model: string, data: {}}, fields?: [string, Field][]) {
+      if (this.ws && fields && this.encoding === "binary" &&
+          this.ws.sendBinary(encodeDist(dists.model, fields))) {
+        return;
+      }
+      if (!this.ws || !this.ws.send({type: "Dist", model: dists.model, data: dists.data})) {
+        post_stringify("/post_dist", dists);
+      }
+    }
+
     // establish connection with the server
     async connect(appstate: {}, onopen, onmessage) {
-      const wsUri = (window.location.protocol === 'https:' && 'wss://' || 'ws://') + window.location.host + "/ws/";
+      let wsUri = (window.location.protocol === 'https:' && 'wss://' || 'ws://') + window.location.host + "/ws/";
+      // the server logs the block delivery of the session under its experiment
+      if (window.session_config.experiment)
+        wsUri += "?experiment=" + encodeURIComponent(window.session_config.experiment);
       this.cache = new CacheFactory().createCache(window.session_config.cacheConfig);
       this.cache.on("onblock", this.onblock.bind(this));
       let instance = new WS(wsUri, onmessage);
+      this.ws = instance;
 
       if (await instance.running === false ) {
         setTimeout( () => {
-          post_stringify("/initapp", appstate, onopen);
+          let state = Object.assign({encodings: ["binary", "json"]}, appstate);
+          post_stringify("/initapp", state, (data, xhr) => {
+            this.encoding = (xhr && xhr.getResponseHeader("X-Predictor-Encoding")) || "json";
+            onopen(data);
+          });
         }, 100);
       }
     }
@@ -60,6 +83,9 @@ export class Engine extends EventEmitter {
           qid: req.ridx,
           dtime: Date.now()
         });
+        // replayed by the offline simulator
+        if (window.session_config.logTrace)
+          window.gsyslogger.addSessionEvent("query", {query: qid, time: Date.now()});
       }
 
       let data = this.cache.get(qid);
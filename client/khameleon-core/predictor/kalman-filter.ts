@@ -261,7 +261,10 @@ export class KalmanFilter extends EventEmitter implements Predictor {
                 let vy = (P_time.e(2, 2) < 1) ? 1 : P_time.e(2, 2).toFixed(3) * this.sigma;
                 let distributionX = gaussian(mouseX, vx);
                 let distributionY = gaussian(mouseY, vy);
-                let gdist = new GuassianDistribution(mouseToKey, distributionX, distributionY);
+                // correlation between x and y from the state covariance
+                let pxy = Math.sqrt(P_time.e(1, 1) * P_time.e(2, 2));
+                let rho = (pxy > 0) ? P_time.e(1, 2) / pxy : 0;
+                let gdist = new GuassianDistribution(mouseToKey, distributionX, distributionY, rho);
                 mydists[delta] = gdist;
             }
         }
@@ -276,8 +279,8 @@ export class KalmanFilter extends EventEmitter implements Predictor {
             dist = {};
             // todo: make interfaces that makes it better to manage
             // prediction resutls and encode them
-            for (let [time, dist] of Object.entries(this.state)) {
-                let state = dist.toWire();
+            for (let [time, d] of Object.entries(this.state)) {
+                let state = d.toWire();
                 if (state === false)  continue;
                 dist[time] = state;
             }
@@ -14,7 +14,8 @@ export class Markov {
     }
 
     /**
-     * update the left stochastic matrix (columns sum to 1) of transition probabilities based on counts
+     * update the right stochastic matrix (rows sum to 1) of transition probabilities based on counts,
+     * row i holds the probability of each action after action i
      * @param action 
      * @param prevaction 
      */
@@ -28,10 +29,10 @@ export class Markov {
             }
         }
         this._counts[prevaction][action] += weight;
-        this._margins[action] += weight;
+        this._margins[prevaction] += weight;
         for (let i=0; i<this._nactions; i++) {
             for (let j=0; j<this._nactions; j++) {
-                this._tmatrix[i][j] = this._counts[i][j] / this._margins[j];
+                this._tmatrix[i][j] = this._counts[i][j] / this._margins[i];
             }
         }
     }
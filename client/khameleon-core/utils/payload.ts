@@ -0,0 +1,97 @@
+// compact binary encoding of predictor states, see scheduler::payload on the server
+
+export type Field =
+  {tag: "int", value: number} |
+  {tag: "float", value: number} |
+  {tag: "indices", value: number[]} |
+  {tag: "vector", value: number[]} |
+  {tag: "dense", value: number[][]} |
+  {tag: "sparse", indices: number[], probs: number[]} |
+  {tag: "quantized", indices: number[], probs: number[]} |
+  {tag: "json", value: any};
+
+const TAGS = {int: 0, float: 1, indices: 2, vector: 3, dense: 4, sparse: 5, quantized: 6, json: 7};
+// binary websocket message of a predictor state in this encoding
+const BINARY_DIST_COMPACT = 3;
+
+class Writer {
+  private bytes: number[] = [];
+
+  u8(v: number) { this.bytes.push(v & 0xff); }
+  u16(v: number) { this.u8(v); this.u8(v >>> 8); }
+  u32(v: number) { this.u16(v & 0xffff); this.u16(v >>> 16); }
+  f32(v: number) {
+    let view = new DataView(new ArrayBuffer(4));
+    view.setFloat32(0, v, true);
+    for (let i = 0; i < 4; i++) this.u8(view.getUint8(i));
+  }
+  f64(v: number) {
+    let view = new DataView(new ArrayBuffer(8));
+    view.setFloat64(0, v, true);
+    for (let i = 0; i < 8; i++) this.u8(view.getUint8(i));
+  }
+  i64(v: number) {
+    this.u32(v >>> 0);
+    this.u32(Math.floor(v / 4294967296) >>> 0);
+  }
+  utf8(s: string) {
+    new TextEncoder().encode(s).forEach((b) => this.u8(b));
+  }
+
+  buffer() : ArrayBuffer {
+    return new Uint8Array(this.bytes).buffer;
+  }
+}
+
+function writeFields(w: Writer, fields: [string, Field][]) {
+  w.u16(fields.length);
+  for (let [name, field] of fields) {
+    let encoded = new TextEncoder().encode(name);
+    w.u8(encoded.length);
+    w.utf8(name);
+    w.u8(TAGS[field.tag]);
+    switch (field.tag) {
+      case "int": w.i64(field.value); break;
+      case "float": w.f64(field.value); break;
+      case "indices": w.u32(field.value.length); field.value.forEach((v) => w.u32(v)); break;
+      case "vector": w.u32(field.value.length); field.value.forEach((v) => w.f32(v)); break;
+      case "dense":
+        w.u32(field.value.length);
+        w.u32(field.value.length > 0 ? field.value[0].length : 0);
+        field.value.forEach((row) => row.forEach((v) => w.f32(v)));
+        break;
+      case "sparse":
+        w.u32(field.indices.length);
+        field.indices.forEach((v) => w.u32(v));
+        field.probs.forEach((v) => w.f32(v));
+        break;
+      case "quantized":
+        w.u32(field.indices.length);
+        field.indices.forEach((v) => w.u32(v));
+        field.probs.forEach((v) => w.u16(Math.round(Math.min(Math.max(v, 0), 1) * 65535)));
+        break;
+      case "json":
+        let json = new TextEncoder().encode(JSON.stringify(field.value));
+        w.u32(json.length);
+        json.forEach((b) => w.u8(b));
+        break;
+    }
+  }
+}
+
+// PredictorState.data encoded as named fields
+export function encodePayload(fields: [string, Field][]) : ArrayBuffer {
+  let w = new Writer();
+  writeFields(w, fields);
+  return w.buffer();
+}
+
+// websocket message carrying a predictor state of `model`
+export function encodeDist(model: string, fields: [string, Field][]) : ArrayBuffer {
+  let w = new Writer();
+  w.u8(BINARY_DIST_COMPACT);
+  w.u16(new TextEncoder().encode(model).length);
+  w.utf8(model);
+  writeFields(w, fields);
+  return w.buffer();
+}
@@ -54,11 +54,11 @@ export class SystemLogger {
 
   writeTrace() {
     let user_name = (window.session_config && window.session_config.name)? window.session_config.name : "anon";
-    let fname = `session-${user_name}-${Date.now()}.log`;
+    let fname = `session-${user_name}-${Date.now()}`;
 
     let session = this.trace;
     
-    let trace_wrapper = {file: fname, data: session};
+    let trace_wrapper = {file: fname, experiment: window.session_config.experiment, data: session};
     console.log("trace: ", trace_wrapper);
     this.writeEvents();
     post_stringify("/log/trace", trace_wrapper, () => { alert("done trace"); });
@@ -120,7 +120,7 @@ export class SystemLogger {
         missed: this.blocksmissed, 
         total:this.blockscount }];
 
-    let wrapper = {data: this.events};
+    let wrapper = {experiment: window.session_config.experiment, data: this.events};
     post_stringify("/log/write", wrapper, () => { alert("done events"); });
 
   }
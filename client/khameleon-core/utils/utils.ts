@@ -44,8 +44,8 @@ export function post_stringify(url: string, json: {}, cb?) {
         contentType: "application/json; charset=utf-8",
         url: url,
         data: JSON.stringify(json),
-        success: function (data) {
-            if (cb) cb(data);
+        success: function (data, _status, xhr) {
+            if (cb) cb(data, xhr);
         },
         error: function (error) {
             console.log("error @", url, "->", error);
@@ -2,6 +2,7 @@ import { Header } from "../apps";
 
 export class WS {
   private _running: boolean = false;
+  private socket: WebSocket;
 
   
   constructor(private wsUri, private onmessage) {
@@ -12,8 +13,23 @@ export class WS {
     return this._running;
   }
 
+  // send a typed message, e.g. {type: "Dist", model, data}, false if the socket isn't open
+  send(msg: {}) : boolean {
+    if (!this._running || this.socket.readyState !== WebSocket.OPEN) return false;
+    this.socket.send(JSON.stringify(msg));
+    return true;
+  }
+
+  // send a binary message, e.g. encodeDist(...), false if the socket isn't open
+  sendBinary(msg: ArrayBuffer) : boolean {
+    if (!this._running || this.socket.readyState !== WebSocket.OPEN) return false;
+    this.socket.send(msg);
+    return true;
+  }
+
   setup() {
     let socket = new WebSocket(this.wsUri);
+    this.socket = socket;
     socket.binaryType = "arraybuffer";
     socket.onopen= () => {
       console.log("connected webworker websocket");
@@ -21,8 +37,21 @@ export class WS {
     }
 
     socket.onmessage = (event) => {
+      // text messages report errors, e.g. a predictor model the app doesn't accept
+      if (typeof event.data === "string") {
+        console.error("server error:", JSON.parse(event.data).error);
+        return;
+      }
+
       // one block currently
-      let { header,blockbuffer,  blockIdx } = this.decode_bytebuffer(event.data);
+      let decoded;
+      try {
+        decoded = this.decode_bytebuffer(event.data);
+      } catch (e) {
+        console.error("dropped block:", e.message);
+        return;
+      }
+      let { header, blockbuffer, blockIdx } = decoded;
       if (blockIdx > 0) {
           socket.send(blockIdx+" "+Date.now());
       }
@@ -41,30 +70,53 @@ export class WS {
     console.log("start websocket here", socket);
   }
   
+  // block header, see ds::BlockHeader on the server
   decode_bytebuffer(buffer) {
-    let offset = 0;
-    var blockIdx = new Uint32Array(buffer, offset, 1)[0];  offset += 4; // u32
-    var blockid = new Uint32Array(buffer, offset, 1)[0];  offset += 4; // u32
-    var nblocks = new Uint32Array(buffer, offset, 1)[0];  offset += 4; // u32
-
-    // u64, assumption: key len is u32 so it doesnt overflow
-    var key_len = new Uint32Array(buffer, offset, 1)[0];     offset += 8;
-    console.log("blockIdx: ", blockIdx);
-    console.log("blockid: ", blockid);
-    console.log("nblocks: ", nblocks);
-    console.log("key_len: ", key_len);
-    var keybuf = new Uint8Array(buffer, offset, key_len); offset += key_len;
-    console.log("keybuf: ", keybuf);
-    var enc = new TextDecoder("utf-8");
-    var key = enc.decode( keybuf );
-    console.log("key: ", key);
-    
+    let view = new DataView(buffer);
+    let magic = String.fromCharCode(view.getUint8(0), view.getUint8(1), view.getUint8(2), view.getUint8(3));
+    let version = view.getUint8(4);
+    if (magic != BLOCK_MAGIC || version != BLOCK_VERSION) {
+      throw new Error("unsupported block " + magic + " version " + version);
+    }
+
+    let flags = view.getUint8(5);
+    let key_len = view.getUint16(6, true);
+    let seq = view.getUint32(8, true);
+    let blockid = view.getUint32(12, true);
+    let nblocks = view.getUint32(16, true);
+    let payload_len = view.getUint32(20, true);
+    let offset = 24;
+    let checksum: number | undefined = undefined;
+    if (flags & FLAG_CHECKSUM) {
+      checksum = view.getUint32(offset, true); offset += 4;
+    }
+
+    let key = new TextDecoder("utf-8").decode(new Uint8Array(buffer, offset, key_len)); offset += key_len;
+
     // pass this to the application as a blob
-    let blockbuffer = buffer.slice(offset, buffer.byteLength);
+    let blockbuffer = buffer.slice(offset, offset + payload_len);
+    if (checksum !== undefined && crc32(new Uint8Array(blockbuffer)) != checksum) {
+      throw new Error("block " + seq + " of " + key + " is corrupted");
+    }
 
-    let header: Header = {blockid: blockid, nblocks: nblocks, key: key};
-    return { header, blockbuffer , blockIdx};
+    let header: Header = {seq: seq, blockid: blockid, nblocks: nblocks, key: key};
+    return { header, blockbuffer, blockIdx: seq };
   }
 
 }
 
+const BLOCK_MAGIC = "KHBL";
+const BLOCK_VERSION = 1;
+const FLAG_CHECKSUM = 1;
+
+// CRC-32 (IEEE 802.3), same as ds::crc32
+function crc32(bytes: Uint8Array) : number {
+  let crc = 0xFFFFFFFF;
+  for (let i = 0; i < bytes.length; i++) {
+    crc ^= bytes[i];
+    for (let k = 0; k < 8; k++) {
+      crc = (crc >>> 1) ^ (0xEDB88320 & -(crc & 1));
+    }
+  }
+  return (~crc) >>> 0;
+}
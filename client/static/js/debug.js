@@ -0,0 +1,118 @@
+// dashboard of the debug events of a session, see manager::debug on the server
+(function() {
+  // rows kept in the blocks table
+  const MAX_BLOCKS = 50;
+  // slots of the plan shown
+  const MAX_PLAN = 200;
+
+  let socket = null;
+  let plan = [];
+  let sent = 0;
+  // seq -> row of the block waiting for its ack
+  let pending = {};
+  let rtts = [];
+
+  function $(id) { return document.getElementById(id); }
+
+  function setStatus(text) { $("status").textContent = text; }
+
+  function renderTopK(queries) {
+    let topk = $("topk");
+    topk.innerHTML = "";
+    let max = queries.length > 0 ? queries[0][1] : 1;
+    queries.forEach(([query, prob]) => {
+      let bar = document.createElement("div");
+      bar.className = "bar";
+      bar.innerHTML = `<span class="label">${query}</span>` +
+        `<span class="fill" style="width: ${Math.round(200 * prob / (max || 1))}px"></span>` +
+        `<span>${prob.toFixed(3)}</span>`;
+      topk.appendChild(bar);
+    });
+  }
+
+  function renderPlan() {
+    let el = $("plan");
+    el.innerHTML = "";
+    plan.slice(0, MAX_PLAN).forEach((query, i) => {
+      let slot = document.createElement("span");
+      slot.textContent = query;
+      if (i < sent) slot.className = "sent";
+      el.appendChild(slot);
+    });
+  }
+
+  function addBlock(event) {
+    let row = document.createElement("tr");
+    row.innerHTML = `<td>${event.seq}</td><td>${event.key}</td>` +
+      `<td>${event.block_id + 1}/${event.nblocks}</td><td>${event.bytes}</td><td>-</td>`;
+    let body = $("blocks").tBodies[0];
+    body.insertBefore(row, body.firstChild);
+    pending[event.seq] = row;
+
+    while (body.rows.length > MAX_BLOCKS) {
+      body.deleteRow(body.rows.length - 1);
+    }
+  }
+
+  function ack(event) {
+    let row = pending[event.seq];
+    if (row) {
+      row.cells[4].textContent = event.rtt_ms;
+      delete pending[event.seq];
+    }
+
+    rtts.push(event.rtt_ms);
+    if (rtts.length > MAX_BLOCKS) rtts.shift();
+    let mean = rtts.reduce((a, b) => a + b, 0) / rtts.length;
+    $("rtt").textContent = `(mean rtt ${mean.toFixed(1)} ms)`;
+  }
+
+  function onEvent(event) {
+    switch (event.type) {
+      case "Distribution":
+        $("distribution").textContent = `round ${event.round}: ${event.model}`;
+        break;
+      case "TopK":
+        renderTopK(event.queries);
+        break;
+      case "Plan":
+        plan = event.plan;
+        sent = 0;
+        $("plan").title = `${event.scheduler} in ${event.elapsed_ms.toFixed(1)} ms`;
+        renderPlan();
+        break;
+      case "BlockSent":
+        sent += 1;
+        addBlock(event);
+        if (sent <= MAX_PLAN) renderPlan();
+        break;
+      case "Ack":
+        ack(event);
+        break;
+      case "SessionEnded":
+        setStatus("session ended");
+        break;
+      default:
+        if (event.error) setStatus(event.error);
+    }
+  }
+
+  function connect() {
+    if (socket) socket.close();
+
+    let session = $("session").value;
+    let protocol = window.location.protocol === "https:" ? "wss" : "ws";
+    socket = new WebSocket(`${protocol}://${window.location.host}/debug/ws/${session}`);
+    socket.onopen = () => setStatus(`session ${session}`);
+    socket.onclose = () => { if ($("status").textContent === `session ${session}`) setStatus("disconnected"); };
+    socket.onmessage = (msg) => {
+      try {
+        onEvent(JSON.parse(msg.data));
+      } catch (e) {
+        console.error("invalid debug event", msg.data, e);
+      }
+    };
+  }
+
+  $("connect").addEventListener("click", connect);
+})();
@@ -5,6 +5,8 @@ import { TestApp } from "../apps";  // import specific app
 let config = {
  cachesize: 100,
  cachetype: "ringIndex",
+ // optional: cache size in bytes, apps with blocks of different sizes
+ // cachebytes: 8 * 1024 * 1024,
 
  // logger config
  minResolution: 2,
@@ -20,6 +22,7 @@ let predictor = new KalmanFilter(logger);
 config["cacheConfig"] = {
        cache: config.cachetype,
        cacheSize: config.cachesize,
+       cacheBytes: config["cachebytes"],
 };
 
 
@@ -1,24 +1,64 @@
 use serde_derive::{Deserialize, Serialize};
 use std::io::prelude::*;
 use std::mem::size_of;
+use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, VecDeque};
 
 use super::AppTrait;
 use super::gm::{GameManager};
 use crate::ds;
+use crate::error;
 use crate::scheduler;
 use crate::backend;
+use crate::predictor::markov::TransitionCounts;
 
-#[derive(Clone)]
 pub struct Game {
     blocks_per_query: indexmap::IndexMap<String, usize>,
     utility: Vec<f32>,
+    /// measured utility curves, falls back to 'utility' for unmeasured queries
+    utility_curves: scheduler::UtilityCurves,
+    /// average block size in bytes, and the size of each block
     blocksize: usize,
+    block_sizes: scheduler::BlockSizes,
+    /// progressive layers per frame
+    nlayers: usize,
     backend: backend::inmem::InMemBackend,
     game_manager: GameManager,
     future: u32,
-    num_actions: usize
+    num_actions: usize,
+    /// client tick interval in ms, frames are stale once their tick passed
+    tick_ms: usize,
+    /// frames predicted by the markov model, indexed by query id
+    sequences: Arc<RwLock<scheduler::SequenceIndex>>,
+    /// encoded blocks of the frames rendered last, oldest first in frame_order
+    frames: HashMap<String, Vec<FrameBlock>>,
+    frame_order: VecDeque<String>,
+    max_frames: usize,
+    /// transitions between actions learned across sessions, by all players and
+    /// by the player of this session if the client sent a user id
+    transitions: TransitionCounts,
+    user: Option<(String, TransitionCounts)>,
+    /// (tick, action) of the last observed action
+    last_action: Option<(u64, usize)>,
+    /// transitions observed since the counts were stored, added to the stored counts
+    /// of all players and of the player
+    pending: TransitionCounts,
+    unsaved: usize,
+    /// accepted predictor models
+    decoders: scheduler::DecoderRegistry,
 }
 
+/// most likely action sequences kept by the markov decoder
+const DEFAULT_BEAM: usize = 25;
+/// ticks of predicted frames that keep their query id
+const SEQUENCE_TICKS: usize = 4;
+/// the transition matrix sent by the client is worth this many observed transitions
+const CLIENT_MODEL_WEIGHT: f64 = 20.0;
+/// store the learned transitions every SAVE_EVERY observed transitions
+const SAVE_EVERY: usize = 50;
+/// key of the learned transitions of all players in the backend
+const TRANSITIONS_KEY: &str = "Game:transitions";
+
 /// appstate: specific data passed at initialization state from the client
 /// config: configuration data passed from the server
 pub fn new(_appstate: &ds::AppState, _config: serde_json::Value) -> Game {
@@ -33,28 +73,50 @@ pub fn new(_appstate: &ds::AppState, _config: serde_json::Value) -> Game {
 
     info!("2) create an index of how many blocks/query");
     let blocks_per_query = backend.collect_blocks_per_query(Game::count_blocks);
-    let blocksize = match backend.get_iter().next() {
-        Some(Ok((_k, v))) => {
-            let value: Vec<FrameBlock> = bincode::deserialize(&v).unwrap();
-            let size = match value.iter().next() {
-                Some(v) => v.size(),
-                None => 0
-            };
-
-            size
-
-        }, _ => 0,
-    };
+    let block_sizes = backend.collect_block_sizes(&blocks_per_query, Game::block_sizes);
+    let blocksize = block_sizes.unit();
 
     let max_blocks_count: usize = blocks_per_query.iter().map(|(_, v)| *v).max().unwrap_or_else(|| 0 );
     let utility: Vec<f32> = (0..max_blocks_count).enumerate().map(|(i, _)| (1.0 / max_blocks_count as f32)*(i as f32+1.0) ).collect();
+    let utility_curves = backend.collect_utility_curves(&blocks_per_query, utility.clone());
     let (future, num_actions): (u32, usize) = match _appstate.state.as_object() {
         Some(obj) => (obj["future"].clone().as_u64().unwrap() as u32, obj["nactions"].clone().as_u64().unwrap() as usize),
         _ => (3, 5)
     };
-    let game_manager = GameManager::new("spingame".to_owned());
+    let tick_ms: usize = match _appstate.state.get("tick_ms") {
+        Some(v) => v.as_u64().unwrap_or(100) as usize,
+        None => 100,
+    };
+    let beam: usize = match _appstate.state.get("beam") {
+        Some(v) => v.as_u64().unwrap_or(DEFAULT_BEAM as u64) as usize,
+        None => DEFAULT_BEAM,
+    };
+    let transitions = Game::load_transitions(&backend, TRANSITIONS_KEY, num_actions);
+    let user = match _appstate.state.get("user").and_then(|u| u.as_str()) {
+        Some(user) => {
+            let key = format!("{}:{}", TRANSITIONS_KEY, user);
+            let counts = Game::load_transitions(&backend, &key, num_actions);
+            Some((key, counts))
+        },
+        None => None,
+    };
+    info!("learned transitions: {} all players, {:?} this player", transitions.total(),
+          user.as_ref().map(|(_, counts)| counts.total()));
 
-    Game{blocks_per_query, utility, blocksize, backend, game_manager, future, num_actions}
+    let game_manager = GameManager::new("spingame".to_owned());
+    // the ids of the predicted frames are the queries of the scheduler
+    let total_queries = blocks_per_query.len();
+    if total_queries < beam * SEQUENCE_TICKS {
+        warn!("{} queries for {} predicted frames, frame ids are recycled sooner", total_queries, beam * SEQUENCE_TICKS);
+    }
+    let sequences = Arc::new(RwLock::new(scheduler::SequenceIndex::new(total_queries)));
+    let mut decoders = scheduler::DecoderRegistry::new();
+    decoders.register(scheduler::MARKOV_MODEL,
+                      Box::new(scheduler::MarkovDecoder::new(future, num_actions, beam, sequences.clone())));
+
+    Game{blocks_per_query, utility, utility_curves, blocksize, block_sizes, nlayers: max_blocks_count, backend, game_manager, future, num_actions, tick_ms, sequences,
+         frames: HashMap::new(), frame_order: VecDeque::new(), max_frames: total_queries.max(1),
+         transitions, user, last_action: None, pending: TransitionCounts::new(num_actions, 1), unsaved: 0, decoders}
 }
 
 // app specific
@@ -63,12 +125,17 @@ struct FrameBlock {
     block_id: u32,
     // TODO: possibly use to encode tick # in frame block
     // tick: u64,
+    /// how the client renders a prefix of blocks, and the size of this block's layer
+    render: backend::progressive::Render,
+    width: u32,
+    height: u32,
     content: Vec<u8>,
 }
 
 impl FrameBlock {
-    fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    /// payload of the streamed block, the header carries the block id
+    fn payload(&self) -> Vec<u8> {
+        bincode::serialize(&(self.render, self.width, self.height, &self.content)).unwrap()
     }
 
     fn size(&self) -> usize {
@@ -78,40 +145,192 @@ impl FrameBlock {
 
 
 impl Game {
+    /// blocks of a value stored in the backend, none if it is corrupted
+    fn decode_blocks(v: &[u8]) -> Vec<FrameBlock> {
+        match bincode::deserialize(v) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                error!("corrupted value of {} bytes: {}", v.len(), err);
+                Vec::new()
+            }
+        }
+    }
+
     fn count_blocks(v: &Vec<u8>) -> usize {
-        let value: Vec<FrameBlock> = bincode::deserialize(&v).unwrap();
+        let value = Game::decode_blocks(&v);
         let blocks_count = value.len();
 
         blocks_count
     }
 
-    fn create_blocks(fname: String, blocksize: usize) -> Vec<FrameBlock> {
+    /// size in bytes of each block of a value
+    fn block_sizes(v: &Vec<u8>) -> Vec<usize> {
+        let value = Game::decode_blocks(&v);
+        value.iter().map(|b| b.size()).collect()
+    }
+
+    /// render mode and content of each block of a value, used to measure utility curves
+    pub fn block_contents(v: &Vec<u8>) -> (backend::progressive::Render, Vec<Vec<u8>>) {
+        let value = Game::decode_blocks(&v);
+        let render = match value.first() {
+            Some(b) => b.render,
+            None => backend::progressive::Render::Concat,
+        };
+
+        (render, value.into_iter().map(|b| b.content).collect())
+    }
+
+    /// encode the frame at @fname into @nlayers progressive blocks, see backend::progressive
+    fn create_blocks(fname: String, nlayers: usize) -> Vec<FrameBlock> {
         let mut file = std::fs::File::open(&fname).unwrap();
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).unwrap();
-        let img = buffer;
 
-        let mut blocks = Vec::new();
-        let mut start = 0;
+        let layers = backend::progressive::encode_layers(&buffer, nlayers).unwrap();
+        layers.into_iter().enumerate().map(|(bid, layer)| {
+            FrameBlock{block_id: bid as u32, render: backend::progressive::Render::Replace,
+                       width: layer.width, height: layer.height, content: layer.content}
+        }).collect()
+    }
 
-        let blocksize = if blocksize > img.len() { img.len() } else { blocksize };
-        let mut end = blocksize;
+    /// render the frame after @actions and encode it into progressive blocks, none if
+    /// the rendered frame can't be read or encoded
+    fn render_frame(&mut self, actions: Vec<usize>) -> Option<Vec<FrameBlock>> {
+        self.game_manager.get(actions);
+        // TODO: avoid the intermediate file written by the game manager
+        let buffer = match std::fs::read("/tmp/square.png") {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                error!("couldn't read the rendered frame: {}", e);
+                return None;
+            }
+        };
 
-        debug!("blocksize: {:?} end: {:?}", blocksize, end);
-        let mut bid = 0;
+        match backend::progressive::encode_layers(&buffer, self.nlayers) {
+            Ok(layers) => Some(layers.into_iter().enumerate().map(|(bid, layer)| {
+                FrameBlock{block_id: bid as u32, render: backend::progressive::Render::Replace,
+                           width: layer.width, height: layer.height, content: layer.content}
+            }).collect()),
+            Err(e) => {
+                error!("couldn't encode the rendered frame: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// encoded blocks of the frame @key, rendered at its first block request
+    fn frame_blocks(&mut self, key: &str, actions: Vec<usize>) -> Option<&Vec<FrameBlock>> {
+        if !self.frames.contains_key(key) {
+            let blocks = self.render_frame(actions)?;
+            self.frames.insert(key.to_owned(), blocks);
+            self.frame_order.push_back(key.to_owned());
+            while self.frame_order.len() > self.max_frames {
+                if let Some(old) = self.frame_order.pop_front() {
+                    self.frames.remove(&old);
+                }
+            }
+        }
+
+        self.frames.get(key)
+    }
+
+    /// learned transitions stored at @key, empty if there are none for this action space
+    fn load_transitions(backend: &backend::inmem::InMemBackend, key: &str, num_actions: usize) -> TransitionCounts {
+        Game::decode_transitions(backend.get_model(key).as_ref().map(|bytes| bytes.as_slice()), num_actions)
+    }
+
+    fn decode_transitions(bytes: Option<&[u8]>, num_actions: usize) -> TransitionCounts {
+        match bytes.and_then(|bytes| bincode::deserialize::<TransitionCounts>(bytes).ok()) {
+            Some(counts) if counts.num_actions == num_actions && counts.order == 1 => counts,
+            _ => TransitionCounts::new(num_actions, 1),
+        }
+    }
+
+    /// add @pending to the transitions stored at @key and return them. Sessions of the
+    /// same game or player add theirs concurrently, so the stored counts are updated
+    /// in place rather than replaced by a copy
+    fn add_transitions(backend: &mut backend::inmem::InMemBackend, key: &str, pending: &TransitionCounts,
+                       num_actions: usize) -> Option<TransitionCounts> {
+        let bytes = backend.update_model(key, |stored| {
+            let mut counts = Game::decode_transitions(stored, num_actions);
+            counts.merge(pending);
+            bincode::serialize(&counts).ok()
+        })?;
+        bincode::deserialize(&bytes).ok()
+    }
 
-        while end <= img.len() {
-            if end > img.len() {
-                end = img.len();
+    fn save_transitions(&mut self) {
+        if self.unsaved == 0 {
+            return;
+        }
+
+        // also picks up what other sessions learned meanwhile
+        if let Some(counts) = Game::add_transitions(&mut self.backend, TRANSITIONS_KEY, &self.pending, self.num_actions) {
+            self.transitions = counts;
+        }
+        if let Some((key, counts)) = &mut self.user {
+            if let Some(stored) = Game::add_transitions(&mut self.backend, key, &self.pending, self.num_actions) {
+                *counts = stored;
             }
+        }
+        self.backend.flush();
+        self.pending = TransitionCounts::new(self.num_actions, 1);
+        self.unsaved = 0;
+    }
 
-            blocks.push( FrameBlock{block_id: bid, content: img[start..end].to_vec()} );
-            bid += 1;
-            start = end;
-            end += blocksize;
+    /// count the transition to the action of a new tick
+    fn learn(&mut self, action: usize, tick: u64) {
+        match self.last_action {
+            Some((last_tick, _)) if tick <= last_tick => return,
+            Some((_, last)) => {
+                self.transitions.add(&[last], action);
+                self.pending.add(&[last], action);
+                if let Some((_, counts)) = &mut self.user {
+                    counts.add(&[last], action);
+                }
+                self.unsaved += 1;
+            },
+            None => (),
         }
+        self.last_action = Some((tick, action));
 
-        blocks
+        if self.unsaved >= SAVE_EVERY {
+            self.save_transitions();
+        }
+    }
+
+    /// learn from the markov state of the client, and replace its transition matrix
+    /// with the learned one, using the client's as a prior if it sent one
+    fn learned_state(&mut self, mut userstate: ds::PredictorState) -> ds::PredictorState {
+        if userstate.model.trim() != scheduler::MARKOV_MODEL || userstate.data.get_u64("order").unwrap_or(1) != 1 {
+            return userstate;
+        }
+
+        if let (Some(action), Some(tick)) = (userstate.data.get_u64("action"), userstate.data.get_u64("tick")) {
+            self.learn(action as usize, tick);
+        }
+
+        let counts = match &self.user {
+            Some((_, counts)) => counts,
+            None => &self.transitions,
+        };
+        let table = match userstate.data.get_matrix("dist") {
+            Some(dist) => match dist {
+                Ok(model) => counts.posterior(Some(&model), CLIENT_MODEL_WEIGHT),
+                // let the decoder report it
+                Err(_) => return userstate,
+            },
+            None => counts.table(1.0),
+        };
+
+        userstate.data.set_matrix("dist", &table);
+        userstate
+    }
+
+    /// client delta (ms) until the tick after the frame displayed at `tick` starts,
+    /// blocks arriving later are stale
+    fn frame_deadline(&self, tick: u64, cur_tick: u64) -> usize {
+        ((tick + 1).saturating_sub(cur_tick) as usize) * self.tick_ms
     }
 
     // TODO: rewrite to take in sequence of actions and tick # as input
@@ -120,24 +339,14 @@ impl Game {
         // TODO: remove this
         if let Some(blocks_bytes) = self.backend.get(key.as_bytes().to_vec()) {
             let mut sblocks: Vec<ds::StreamBlock> = Vec::new();
-            let blocks: Vec<FrameBlock> = bincode::deserialize(&blocks_bytes).unwrap();
+            let blocks = Game::decode_blocks(&blocks_bytes);
             let nblocks: u32 = blocks.len() as u32;
 
             let end = if incache + count > blocks.len() { blocks.len() } else { incache + count };
             for i in incache..end {
                 let block = &blocks[i];
-                let mut bytebuffer: Vec<u8> = Vec::new();
-                let mut block_byte = block.serialize();
-                let mut block_id = bincode::serialize(&block.block_id).unwrap();
-                let mut nblock = bincode::serialize(&nblocks).unwrap();
-                let mut key_byte = bincode::serialize(&key).unwrap();
-
-                bytebuffer.append( &mut block_id );
-                bytebuffer.append( &mut nblock );
-                bytebuffer.append( &mut key_byte );
-                bytebuffer.append( &mut block_byte );
-
-                sblocks.push(ds::StreamBlock::Binary(bytebuffer));
+                let block = ds::Block::new(key, block.block_id, nblocks, block.payload());
+                sblocks.push(ds::StreamBlock::Block(block));
             }
 
             Some(sblocks)
@@ -152,50 +361,32 @@ impl AppTrait for Game {
         (self.blocks_per_query.clone(), self.utility.clone())
     }
 
+    fn get_utility_curves(&self) -> scheduler::UtilityCurves {
+        self.utility_curves.clone()
+    }
+
     fn get_nblocks_byindex(&mut self, index: usize, count: usize,
                            incache: usize) -> Option::<Vec<ds::StreamBlock>> {
         debug!("get_nblocks_byindex");
-        // parse tick # and action sequence
-        let index_str = index.to_string();
+        // tick # and action sequence of the frame
+        let (tick, actions) = match error::read(&self.sequences).get(index) {
+            Some(frame) => frame.clone(),
+            None => {
+                error!("no predicted frame for query {}", index);
+                return None;
+            }
+        };
+        let index_str = scheduler::SequenceIndex::key(tick, &actions);
         debug!("index_str: {}", index_str);
-        let d = 10usize.pow(self.future);
-        let tick = index / d;
-        let mut qid = index % d;
-        let mut actions: Vec<usize> = Vec::new();
-        for d in (0..self.future).rev() {
-            actions.push(qid / self.num_actions.pow(d));
-            qid = qid % self.num_actions.pow(d);
-        }
         // TODO: simulate actions on parallel game instances and return frame as vec of blocks with index (tick|qid) encoded in each block
         debug!("THE ACTIONS ARE: {:?}", actions);
-        self.game_manager.get(actions);
-        // TODO: remove this after finishing game manager get() or else the temp file accessed below won't exist
-        // return None;
-
-        let mut file = std::fs::File::open("/tmp/square.png").unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        let img = buffer;
-
-        let mut blocks = Vec::new();
-        let mut start = 0;
-
-        let blocksize = if self.blocksize > img.len() { img.len() } else { self.blocksize };
-        let mut end = blocksize;
-
-        debug!("blocksize: {:?} end: {:?}", blocksize, end);
-        let mut bid = 0;
-
-        while end <= img.len() {
-            if end > img.len() {
-                end = img.len();
+        let blocks = match self.frame_blocks(&index_str, actions) {
+            Some(blocks) => blocks,
+            None => {
+                error!("couldn't render frame {}", index);
+                return None;
             }
-
-            blocks.push( FrameBlock{block_id: bid, content: img[start..end].to_vec()} );
-            bid += 1;
-            start = end;
-            end += blocksize;
-        }
+        };
 
         let mut sblocks: Vec<ds::StreamBlock> = Vec::new();
         // let blocks: Vec<FrameBlock> = bincode::deserialize(&blocks_bytes).unwrap();
@@ -204,61 +395,72 @@ impl AppTrait for Game {
         let end = if incache + count > blocks.len() { blocks.len() } else { incache + count };
         for i in incache..end {
             let block = &blocks[i];
-            let mut bytebuffer: Vec<u8> = Vec::new();
             // start ring cache that checks if the client cache has already got rid of this
             // block, should we send this block or a new one?
             // for key and count and decision, construct the message to stream to the client
+            let block = ds::Block::new(&index_str, block.block_id, nblocks, block.payload());
+            info!("FrameBlock i {} {}, incache: {} nblocks: {:?} block#: {:?} size: {:?}, blocksize: {:?}",
+                  i, index, incache, nblocks, block.header.block_id, block.len(), block.payload.len());
 
-            let mut block_byte = block.serialize();
-            let size: u32 = block_byte.len() as u32;
-            let mut block_id = bincode::serialize(&block.block_id).unwrap();
-            let mut nblock = bincode::serialize(&nblocks).unwrap();
-            let mut key_len = bincode::serialize(&(index_str.len() as u64)).unwrap();
-            let mut key_byte = index_str.clone().into_bytes();
-
-            bytebuffer.append( &mut block_id );
-            bytebuffer.append( &mut nblock );
-            bytebuffer.append( &mut key_len );
-            bytebuffer.append( &mut key_byte );
-            bytebuffer.append( &mut block_byte );
-            info!("FrameBlock i {} {}, incache: {} nblocks: {:?} block#: {:?} size: {:?}, blocksize: {:?}",  i, index, incache,nblocks, block.block_id, bytebuffer.len(), size);
-
-            sblocks.push(ds::StreamBlock::Binary(bytebuffer));
+            sblocks.push(ds::StreamBlock::Block(block));
         }
 
         Some(sblocks)
     }
 
-    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+    fn get_decoders(&mut self) -> &mut scheduler::DecoderRegistry {
+        &mut self.decoders
+    }
+
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> Result<scheduler::Prob, scheduler::DecodeError> {
         debug!("decode_dist: {:?}", userstate);
-        let total_queries = self.num_actions.pow(self.future);
-        let mut prob = scheduler::Prob::new(total_queries);
-        match userstate.model.trim() {
-            "MM" => {
-                match userstate.data.as_object() {
-                    // obj is a 5x5 transition matrix
-                    Some(obj) => {
-                        let action_id = obj["action"].clone().as_u64().unwrap() as usize;
-                        // debug!("ACTION: {}", action_id);
-                        // Send action to game instances
-                        self.game_manager.set(action_id);
-
-                        let tick = obj["tick"].clone().as_u64().unwrap() + self.future as u64;
-                        // debug!("TICK: {}", tick);
-
-                        let dist = obj["dist"].clone();
-                        scheduler::decode_markov(&dist, self.future, self.num_actions, total_queries, action_id, tick, &mut prob);
-                    }, _ => (),
-                }
-            },
-            _ => panic!("no match routine to decode this {}", userstate.model)
-        };
-        prob
+        let userstate = self.learned_state(userstate);
+        let mut prob = self.decoders.decode(&userstate)?;
+
+        // the markov model carries the last action of the player and the current tick
+        if let Some(action_id) = userstate.data.get_u64("action") {
+            // Send action to game instances
+            self.game_manager.set(action_id as usize);
+        }
+
+        let cur_tick = userstate.data.get_u64("tick").unwrap_or(0);
+        let tick = cur_tick + self.future as u64;
+        let mut sequences = error::write(&self.sequences);
+        // the frame without action is always worth sending
+        let idle = sequences.id(tick, &vec![self.num_actions - 1; self.future as usize]);
+        if !prob.pin(idle) {
+            error!("couldn't pin the idle frame {}", idle);
+        }
+
+        for qid in prob.get_k() {
+            if let Some(&(tick, _)) = sequences.get(qid) {
+                let deadline = self.frame_deadline(tick, cur_tick);
+                prob.set_deadline(qid, deadline);
+            }
+        }
+
+        Ok(prob)
+    }
+
+    fn take_recycled_queries(&mut self) -> Vec<usize> {
+        error::write(&self.sequences).take_recycled()
     }
 
     fn get_block_size(&self) -> usize {
         self.blocksize
     }
+
+    fn get_block_sizes(&self) -> scheduler::BlockSizes {
+        self.block_sizes.clone()
+    }
+
+    fn check_backend(&self) -> Result<(), String> {
+        self.backend.check()
+    }
+
+    fn shutdown(&mut self) {
+        self.save_transitions();
+    }
 }
 
 #[cfg(test)]
@@ -271,16 +473,18 @@ mod tests {
     fn test_game_preprocess_backend() {
         // probably should start testing with 1 block
         // i.e. hard code to size of game frames
-        let blocksize = 20*1024;
+        let nlayers = 4;
         let image_path = "data/img_5_30_11.jpg";
         let db_path = "data/game_data";
-        let blocks = Game::create_blocks(image_path.to_string(), blocksize);
+        let blocks = Game::create_blocks(image_path.to_string(), nlayers);
         // create backend key/value store
         let mut backend = backend::inmem::InMemBackend::new(db_path.to_string());
         let bytes = bincode::serialize(&blocks).unwrap();
         let query = "0";
         let key  = query.as_bytes().to_vec();
         backend.set(key, bytes.clone());
+        backend.set_block_sizes(query, &blocks.iter().map(|b| b.size()).collect());
+        backend.measure_utility_curves(Game::block_contents, backend::quality::Metric::SSIM);
         backend.flush();
     }
 }
@@ -6,6 +6,7 @@ pub mod testapp;
 pub mod game;
 use crate::ds;
 use crate::scheduler;
+use crate::backend;
 
 /// AppType: an enum that has the different types of apps supported
 ///          to add a new app, add here name of the app, and in the
@@ -26,6 +27,20 @@ pub fn new(appstate: &ds::AppState, config: serde_json::Value, _state_change_fla
     }
 }
 
+/// apps::measure_utility: measure the utility curve of each query stored in an app's
+///                        backend from the quality of its progressively decoded blocks.
+///                        returns the number of queries measured
+pub fn measure_utility(appname: AppType, backend: &mut backend::inmem::InMemBackend,
+                       metric: backend::quality::Metric) -> usize {
+    let count = match appname {
+        AppType::TestApp => backend.measure_utility_curves(testapp::TestApp::block_contents, metric),
+        AppType::Game => backend.measure_utility_curves(game::Game::block_contents, metric),
+    };
+    backend.flush();
+
+    count
+}
+
 /// AppTrait: apps need to supprt this trait, it recieves distrubtion from client
 ///           and run scheduler  to decide  list of blocks to stream using 'get_decisions',
 ///           and the actual blocks as a vector of blocks to stream to the client using
@@ -35,17 +50,53 @@ pub trait AppTrait: Send + Sync {
     /// Returns data needed by the scheduler:
     /// (1) blocks per query. We use 'indexmap' because each query is identified
     ///     by both a unique integer ID and a String key
-    /// (2) utility function
+    /// (2) utility function shared by all queries, see 'get_utility_curves'
     /// 
     /// # Example
     /// let (blocks_per_query, utility) = app.get_scheduler_config();
     fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>);
+
+    /// optional: utility curves per query or per class of queries, indexed the same
+    ///           as blocks per query. By default all queries share the utility
+    ///           function returned by 'get_scheduler_config'
+    fn get_utility_curves(&self) -> scheduler::UtilityCurves {
+        let (_, utility) = self.get_scheduler_config();
+        scheduler::UtilityCurves::Shared(utility)
+    }
     
-    /// decode received distribution from the client and return information in Prob object
-    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob;
+    /// decoders of the predictor models the app accepts, keyed by 'PredictorState.model'
+    fn get_decoders(&mut self) -> &mut scheduler::DecoderRegistry;
+
+    /// decode received distribution from the client and return information in Prob object.
+    /// By default the state is decoded by the decoder registered for its model
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> Result<scheduler::Prob, scheduler::DecodeError> {
+        self.get_decoders().decode(&userstate)
+    }
+
+    /// optional: queries whose ids were given to new content since the last call, the
+    ///           blocks cached under them belong to the old content
+    fn take_recycled_queries(&mut self) -> Vec<usize> {
+        Vec::new()
+    }
 
-    /// return size of a block in Bytes
+    /// optional: prior distribution over queries {query index: prob} used by the scheduler
+    ///           before the first predictor state arrives, and blended with the later ones
+    fn get_prior(&self) -> Option<indexmap::IndexMap<usize, f32>> {
+        None
+    }
+
+    /// optional: a query requested by the client, e.g. to learn the popularity of queries
+    fn record_request(&mut self, _key: &str) {
+    }
+
+    /// return size of a block in Bytes, the average size if blocks differ in size
     fn get_block_size(&self) -> usize;
+
+    /// optional: size in Bytes of each block of each query, indexed the same as
+    ///           blocks per query. By default every block is 'get_block_size' Bytes
+    fn get_block_sizes(&self) -> scheduler::BlockSizes {
+        scheduler::BlockSizes::Fixed(self.get_block_size())
+    }
     
     
     /// since scheduler uses assigned IDs to queries, this used to
@@ -59,9 +110,14 @@ pub trait AppTrait: Send + Sync {
         None
     }
 
-    /// optional: cleanup before app closes
+    /// optional: error of the app's backend if it can't serve blocks, reported by /readyz
+    fn check_backend(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// optional: cleanup at the end of a session, the app may serve the next one
     fn shutdown(&mut self) {
-        error!("received Ctrl+C!");
+        debug!("app shutdown");
     }
 
     /// optional: data to initialize client's state
@@ -6,12 +6,17 @@ use crate::ds;
 use crate::scheduler;
 use crate::backend;
 
-#[derive(Clone)]
 pub struct TestApp {
     blocks_per_query: indexmap::IndexMap<String, usize>,
     utility: Vec<f32>,
+    /// measured utility curves, falls back to 'utility' for unmeasured queries
+    utility_curves: scheduler::UtilityCurves,
+    /// average block size in bytes, and the size of each block
     blocksize: usize,
+    block_sizes: scheduler::BlockSizes,
     backend: backend::inmem::InMemBackend,
+    /// accepted predictor models
+    decoders: scheduler::DecoderRegistry,
 }
 
 /// appstate: specific data passed at initialization state from the client
@@ -28,34 +33,34 @@ pub fn new(_appstate: &ds::AppState, _config: serde_json::Value) -> TestApp {
 
     info!("2) create an index  of how many blocks/query");
     let blocks_per_query = backend.collect_blocks_per_query(TestApp::count_blocks);
-    let blocksize = match backend.get_iter().next() {
-        Some(Ok((_k, v))) => {
-            let value: Vec<ImageBlock> = bincode::deserialize(&v).unwrap();
-            let size = match value.iter().next() {
-                Some(v) => v.size(),
-                None => 0
-            };
-
-            size
-
-        }, _ => 0,
-    };
+    let block_sizes = backend.collect_block_sizes(&blocks_per_query, TestApp::block_sizes);
+    let blocksize = block_sizes.unit();
 
     let max_blocks_count: usize = blocks_per_query.iter().map(|(_, v)| *v).max().unwrap_or_else(|| 0 );
     let utility: Vec<f32> = (0..max_blocks_count).enumerate().map(|(i, _)| (1.0 / max_blocks_count as f32)*(i as f32+1.0) ).collect();
-    TestApp{blocks_per_query, utility, blocksize, backend}
+    let utility_curves = backend.collect_utility_curves(&blocks_per_query, utility.clone());
+    let mut decoders = scheduler::DecoderRegistry::new();
+    decoders.set_queries(blocks_per_query.clone());
+    decoders.register(scheduler::GAUSSIAN_MODEL, Box::new(scheduler::GaussianDecoder::new(blocks_per_query.clone())));
+    decoders.register(scheduler::LINEAR_POINT_MODEL, Box::new(scheduler::LinearPointDecoder::new(blocks_per_query.clone())));
+    TestApp{blocks_per_query, utility, utility_curves, blocksize, block_sizes, backend, decoders}
 }
 
 // app specific
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct ImageBlock {
     block_id: u32,
+    /// how the client renders a prefix of blocks, and the size of this block's layer
+    render: backend::progressive::Render,
+    width: u32,
+    height: u32,
     content: Vec<u8>,
 }
 
 impl ImageBlock {
-    fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    /// payload of the streamed block, the header carries the block id
+    fn payload(&self) -> Vec<u8> {
+        bincode::serialize(&(self.render, self.width, self.height, &self.content)).unwrap()
     }
 
     fn size(&self) -> usize {
@@ -65,63 +70,65 @@ impl ImageBlock {
 
 
 impl TestApp {
+    /// blocks of a value stored in the backend, none if it is corrupted
+    fn decode_blocks(v: &[u8]) -> Vec<ImageBlock> {
+        match bincode::deserialize(v) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                error!("corrupted value of {} bytes: {}", v.len(), err);
+                Vec::new()
+            }
+        }
+    }
+
     fn count_blocks(v: &Vec<u8>) -> usize {
-        let value: Vec<ImageBlock> = bincode::deserialize(&v).unwrap();
+        let value = TestApp::decode_blocks(&v);
         let blocks_count = value.len();
 
         blocks_count
     }
 
-    fn create_blocks(fname: String, blocksize: usize) -> Vec<ImageBlock> {
-        let mut file = std::fs::File::open(&fname).unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        let img = buffer;
-
-        let mut blocks = Vec::new();
-        let mut start = 0;
-
-        let blocksize = if blocksize > img.len() { img.len() } else { blocksize };
-        let mut end = blocksize;
+    /// size in bytes of each block of a value
+    fn block_sizes(v: &Vec<u8>) -> Vec<usize> {
+        let value = TestApp::decode_blocks(&v);
+        value.iter().map(|b| b.size()).collect()
+    }
 
-        debug!("blocksize: {:?} end: {:?}", blocksize, end);
-        let mut bid = 0;
+    /// render mode and content of each block of a value, used to measure utility curves
+    pub fn block_contents(v: &Vec<u8>) -> (backend::progressive::Render, Vec<Vec<u8>>) {
+        let value = TestApp::decode_blocks(&v);
+        let render = match value.first() {
+            Some(b) => b.render,
+            None => backend::progressive::Render::Concat,
+        };
 
-        while end <= img.len() {
-            if end > img.len() {
-                end = img.len();
-            }
+        (render, value.into_iter().map(|b| b.content).collect())
+    }
 
-            blocks.push( ImageBlock{block_id: bid, content: img[start..end].to_vec()} );
-            bid += 1;
-            start = end;
-            end += blocksize;
-        }
+    /// encode the image at @fname into @nlayers progressive blocks, see backend::progressive
+    fn create_blocks(fname: String, nlayers: usize) -> Vec<ImageBlock> {
+        let mut file = std::fs::File::open(&fname).unwrap();
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).unwrap();
 
-        blocks
+        let layers = backend::progressive::encode_layers(&buffer, nlayers).unwrap();
+        layers.into_iter().enumerate().map(|(bid, layer)| {
+            ImageBlock{block_id: bid as u32, render: backend::progressive::Render::Replace,
+                       width: layer.width, height: layer.height, content: layer.content}
+        }).collect()
     }
     
     fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option::<Vec<ds::StreamBlock>> {
         if let Some(blocks_bytes) = self.backend.get(key.as_bytes().to_vec()) {
             let mut sblocks: Vec<ds::StreamBlock> = Vec::new();
-            let blocks: Vec<ImageBlock> = bincode::deserialize(&blocks_bytes).unwrap();
+            let blocks = TestApp::decode_blocks(&blocks_bytes);
             let nblocks: u32 = blocks.len() as u32;
 
             let end = if incache + count > blocks.len() { blocks.len() } else { incache + count };
             for i in incache..end {
                 let block = &blocks[i];
-                let mut bytebuffer: Vec<u8> = Vec::new();
-                let mut block_byte = block.serialize();
-                let mut block_id = bincode::serialize(&block.block_id).unwrap();
-                let mut nblock = bincode::serialize(&nblocks).unwrap();
-                let mut key_byte = bincode::serialize(&key).unwrap();
-
-                bytebuffer.append( &mut block_id );
-                bytebuffer.append( &mut nblock );
-                bytebuffer.append( &mut key_byte );
-                bytebuffer.append( &mut block_byte );
-
-                sblocks.push(ds::StreamBlock::Binary(bytebuffer));
+                let block = ds::Block::new(key, block.block_id, nblocks, block.payload());
+                sblocks.push(ds::StreamBlock::Block(block));
             }
 
             Some(sblocks)
@@ -136,6 +143,10 @@ impl AppTrait for TestApp {
         (self.blocks_per_query.clone(), self.utility.clone())
     }
 
+    fn get_utility_curves(&self) -> scheduler::UtilityCurves {
+        self.utility_curves.clone()
+    }
+
     fn get_nblocks_byindex(&mut self, index: usize, count: usize,
                            incache: usize) -> Option::<Vec<ds::StreamBlock>> {
         let kv = self.blocks_per_query.get_index(index);
@@ -148,17 +159,29 @@ impl AppTrait for TestApp {
         }
     }
 
-    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
-        debug!("decode_dist: {:?}", userstate);
-        let total_queries = 1;
-        let prob = scheduler::Prob::new(total_queries);
-        prob
+    fn get_decoders(&mut self) -> &mut scheduler::DecoderRegistry {
+        &mut self.decoders
+    }
 
+    fn get_prior(&self) -> Option<indexmap::IndexMap<usize, f32>> {
+        self.backend.collect_popularity(&self.blocks_per_query)
+    }
+
+    fn record_request(&mut self, key: &str) {
+        self.backend.add_request(key);
     }
 
     fn get_block_size(&self) -> usize {
         self.blocksize
     }
+
+    fn get_block_sizes(&self) -> scheduler::BlockSizes {
+        self.block_sizes.clone()
+    }
+
+    fn check_backend(&self) -> Result<(), String> {
+        self.backend.check()
+    }
 }
 
 #[cfg(test)]
@@ -169,16 +192,18 @@ mod tests {
     // create kv store with single image data (key=R1) with blocks of size 20KB
     // $ cargo test test_testapp_prepreocess_backend -- --nocapture
     fn test_testapp_preprocess_backend() {
-        let blocksize = 20*1024;
+        let nlayers = 4;
         let image_path = "data/img_5_30_11.jpg";
         let db_path = "data/test_data";
-        let blocks = TestApp::create_blocks(image_path.to_string(), blocksize);
+        let blocks = TestApp::create_blocks(image_path.to_string(), nlayers);
         // create backend key/value store
         let mut backend = backend::inmem::InMemBackend::new(db_path.to_string());
         let bytes = bincode::serialize(&blocks).unwrap();
         let query = "R1";
         let key  = query.as_bytes().to_vec();
         backend.set(key, bytes.clone());
+        backend.set_block_sizes(query, &blocks.iter().map(|b| b.size()).collect());
+        backend.measure_utility_curves(TestApp::block_contents, backend::quality::Metric::SSIM);
         backend.flush();
     }
 }
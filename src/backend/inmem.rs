@@ -2,10 +2,28 @@ extern crate image;
 use sled::{Db};
 extern crate base64;
 
+use std::sync::Arc;
+use super::{progressive, quality};
+use crate::scheduler;
+
+/// measured utility curves are kept apart from the blocks so iterating
+/// the default tree only returns queries
+const UTILITY_TREE: &str = "utility";
+/// size in bytes of each block of a query
+const SIZES_TREE: &str = "sizes";
+/// predictor models learned across sessions, e.g. markov transition counts
+const MODELS_TREE: &str = "models";
+/// number of requests of each query, from static metadata or recorded by the server
+const POPULARITY_TREE: &str = "popularity";
+
 #[derive(Clone)]
 pub struct InMemBackend {
     dbname: String,
     db: sled::Db,
+    utility: Arc<sled::Tree>,
+    sizes: Arc<sled::Tree>,
+    models: Arc<sled::Tree>,
+    popularity: Arc<sled::Tree>,
 }
 
 impl InMemBackend {
@@ -15,7 +33,13 @@ impl InMemBackend {
                 .path(&dbname)
                 .build();
 
-        InMemBackend{dbname: dbname, db:Db::start(config).unwrap()}
+        let db = Db::start(config).unwrap();
+        let utility = db.open_tree(UTILITY_TREE).unwrap();
+        let sizes = db.open_tree(SIZES_TREE).unwrap();
+        let models = db.open_tree(MODELS_TREE).unwrap();
+        let popularity = db.open_tree(POPULARITY_TREE).unwrap();
+        InMemBackend{dbname: dbname, db: db, utility: utility, sizes: sizes, models: models,
+                     popularity: popularity}
     }
 
     pub fn set(&mut self, key:Vec<u8>, val: Vec<u8>) {
@@ -37,6 +61,11 @@ impl InMemBackend {
         }
     }
 
+    /// whether the db can be read
+    pub fn check(&self) -> Result<(), String> {
+        self.db.get(b"").map(|_| ()).map_err(|e| e.to_string())
+    }
+
     pub fn get_iter(&self) -> sled::Iter {
         self.db.iter()
     }
@@ -46,6 +75,204 @@ impl InMemBackend {
             Ok(result) => debug!("flushed successfully {:?}", result),
             Err(err) => error!("flush error: {:?}", err),
         };
+
+        if let Err(err) = self.utility.flush() {
+            error!("flush error: {:?}", err);
+        }
+
+        if let Err(err) = self.sizes.flush() {
+            error!("flush error: {:?}", err);
+        }
+
+        if let Err(err) = self.models.flush() {
+            error!("flush error: {:?}", err);
+        }
+
+        if let Err(err) = self.popularity.flush() {
+            error!("flush error: {:?}", err);
+        }
+    }
+
+    /// store the number of requests of query @key
+    pub fn set_popularity(&mut self, key: &str, count: u64) {
+        let bytes = bincode::serialize(&count).unwrap();
+        let _ = self.popularity.set(key.as_bytes().to_vec(), bytes);
+    }
+
+    /// number of requests of query @key, 0 if it was never requested
+    pub fn get_popularity(&self, key: &str) -> u64 {
+        match self.popularity.get(key.as_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(&bytes).unwrap_or(0),
+            Ok(None) => 0,
+            Err(err) => {
+                error!("popularity of {:?}: {:?}", key, err);
+                0
+            }
+        }
+    }
+
+    /// count a request of query @key, concurrent requests are all counted
+    pub fn add_request(&self, key: &str) {
+        let increment = |old: Option<&[u8]>| {
+            let count: u64 = old.and_then(|bytes| bincode::deserialize(bytes).ok()).unwrap_or(0);
+            Some(bincode::serialize(&(count + 1)).unwrap())
+        };
+        if let Err(err) = self.popularity.update_and_fetch(key.as_bytes(), increment) {
+            error!("popularity of {:?}: {:?}", key, err);
+        }
+    }
+
+    /// share of the requests of each query indexed the same as @blocks_per_query,
+    /// None if no query was ever requested
+    pub fn collect_popularity(&self, blocks_per_query: &indexmap::IndexMap<String, usize>)
+                              -> Option<indexmap::IndexMap<usize, f32>> {
+        let counts: Vec<(usize, u64)> = blocks_per_query.iter().enumerate()
+            .map(|(index, (key, _))| (index, self.get_popularity(key)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        let total: u64 = counts.iter().map(|&(_, count)| count).sum();
+        if total == 0 {
+            return None;
+        }
+
+        Some(counts.into_iter().map(|(index, count)| (index, count as f32 / total as f32)).collect())
+    }
+
+    /// store the serialized predictor model @name
+    pub fn set_model(&mut self, name: &str, model: Vec<u8>) {
+        let _ = self.models.set(name.as_bytes().to_vec(), model);
+    }
+
+    /// serialized predictor model @name, None if it was never stored
+    pub fn get_model(&self, name: &str) -> Option<Vec<u8>> {
+        match self.models.get(name.as_bytes()) {
+            Ok(Some(bytes)) => Some(bytes.to_vec()),
+            Ok(None) => None,
+            Err(err) => {
+                error!("model {:?}: {:?}", name, err);
+                None
+            }
+        }
+    }
+
+    /// replace the predictor model @name with @f of the stored one, retried if another
+    /// session changed it meanwhile, returns the new model
+    pub fn update_model<F>(&mut self, name: &str, f: F) -> Option<Vec<u8>>
+        where F: Fn(Option<&[u8]>) -> Option<Vec<u8>> {
+        match self.models.update_and_fetch(name.as_bytes(), f) {
+            Ok(model) => model.map(|bytes| bytes.to_vec()),
+            Err(err) => {
+                error!("model {:?}: {:?}", name, err);
+                None
+            }
+        }
+    }
+
+    /// store the size in bytes of each block of query @key
+    pub fn set_block_sizes(&mut self, key: &str, sizes: &Vec<usize>) {
+        let bytes = bincode::serialize(sizes).unwrap();
+        let _ = self.sizes.set(key.as_bytes().to_vec(), bytes);
+    }
+
+    /// size in bytes of each block of query @key, None if it was never stored
+    pub fn get_block_sizes(&self, key: &str) -> Option<Vec<usize>> {
+        match self.sizes.get(key.as_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(&bytes).ok(),
+            Ok(None) => None,
+            Err(err) => {
+                error!("block sizes of {:?}: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    /// block sizes indexed the same as @blocks_per_query.
+    /// f: app specific function that returns the size of each block of a value,
+    ///    used for queries stored without their block sizes
+    pub fn collect_block_sizes(&self, blocks_per_query: &indexmap::IndexMap<String, usize>,
+                               f: fn(&Vec<u8>) -> Vec<usize>) -> scheduler::BlockSizes {
+        let sizes: Vec<Vec<usize>> = blocks_per_query.iter().map(|(key, _)| {
+            match self.get_block_sizes(key) {
+                Some(sizes) => sizes,
+                None => match self.get(key.as_bytes().to_vec()) {
+                    Some(v) => f(&v),
+                    None => Vec::new(),
+                }
+            }
+        }).collect();
+
+        scheduler::BlockSizes::PerBlock(sizes)
+    }
+
+    /// store the utility curve of query @key, one value per prefix of blocks
+    pub fn set_utility(&mut self, key: &str, curve: &Vec<f32>) {
+        let bytes = bincode::serialize(curve).unwrap();
+        let _ = self.utility.set(key.as_bytes().to_vec(), bytes);
+    }
+
+    /// measured utility curve of query @key, None if it was never measured
+    pub fn get_utility(&self, key: &str) -> Option<Vec<f32>> {
+        match self.utility.get(key.as_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(&bytes).ok(),
+            Ok(None) => None,
+            Err(err) => {
+                error!("utility of {:?}: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    /// decode every prefix of blocks of each query and store its quality against
+    /// the full query as the utility curve of the query.
+    /// f: app specific function that returns how blocks of a value are rendered
+    ///    and the content of each block
+    pub fn measure_utility_curves(&mut self, f: fn(&Vec<u8>) -> (progressive::Render, Vec<Vec<u8>>),
+                                  metric: quality::Metric) -> usize {
+        let mut curves: Vec<(String, Vec<f32>)> = Vec::new();
+        for result in self.get_iter() {
+            match result {
+                Ok((k, v)) => {
+                    let key = std::str::from_utf8(&k).unwrap().to_string();
+                    let (render, blocks) = f(&v.to_vec());
+                    let curve = quality::measure_curve(&blocks, render, metric);
+                    debug!("k: {:?} utility: {:?}", key, curve);
+                    curves.push((key, curve));
+                },
+                Err(err) => error!("{:?}", err),
+            }
+        }
+
+        for (key, curve) in curves.iter() {
+            self.set_utility(key, curve);
+        }
+
+        curves.len()
+    }
+
+    /// utility curves indexed the same as @blocks_per_query. queries without a measured
+    /// curve use @default, and queries with the same curve share a class
+    pub fn collect_utility_curves(&self, blocks_per_query: &indexmap::IndexMap<String, usize>,
+                                  default: Vec<f32>) -> scheduler::UtilityCurves {
+        let mut curves: Vec<Vec<f32>> = vec![default.clone()];
+        let mut class_of: Vec<usize> = Vec::with_capacity(blocks_per_query.len());
+        for (key, _) in blocks_per_query.iter() {
+            let class = match self.get_utility(key) {
+                Some(curve) => match curves.iter().position(|c| *c == curve) {
+                    Some(class) => class,
+                    None => {
+                        curves.push(curve);
+                        curves.len() - 1
+                    }
+                },
+                None => 0,
+            };
+            class_of.push(class);
+        }
+
+        match curves.len() {
+            1 => scheduler::UtilityCurves::Shared(default),
+            _ => scheduler::UtilityCurves::Classes{curves: curves, class_of: class_of},
+        }
     }
 
     pub fn collect_blocks_per_query(&self, f: fn(&Vec<u8>) -> usize) -> indexmap::IndexMap<String, usize> {
@@ -69,3 +296,25 @@ impl InMemBackend {
         blocks_per_query
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_request_concurrently() {
+        let path = std::env::temp_dir().join(format!("khameleon-inmem-{}", std::process::id()));
+        let backend = Arc::new(InMemBackend::new(path.to_string_lossy().into_owned()));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let backend = backend.clone();
+            std::thread::spawn(move || for _ in 0..100 { backend.add_request("R1"); })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(backend.get_popularity("R1"), 400);
+        assert_eq!(backend.get_popularity("R2"), 0);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -1 +1,4 @@
 pub mod inmem;
+pub mod progressive;
+pub mod quality;
+pub mod traces;
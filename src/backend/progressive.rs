@@ -0,0 +1,98 @@
+/*
+ * Progressive encoding of images into blocks.
+ *
+ * An image is encoded as a sequence of layers of increasing resolution and
+ * quality, each layer a self-contained JPEG. A prefix of k blocks is rendered
+ * by displaying the last layer scaled to the size of the full image, so every
+ * prefix is decodable and its quality grows with k.
+ */
+extern crate image;
+
+use image::{DynamicImage, GenericImageView, ImageResult};
+use serde_derive::{Deserialize, Serialize};
+
+/// JPEG quality of the first and of the last re-encoded layer
+const MIN_QUALITY: u8 = 50;
+const MAX_QUALITY: u8 = 90;
+
+/// how the client renders a prefix of blocks, sent in each block header
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Render {
+    /// blocks are byte ranges of one file, the prefix is their concatenation
+    Concat,
+    /// blocks are self-contained layers, the prefix is its last block
+    Replace,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub width: u32,
+    pub height: u32,
+    pub content: Vec<u8>,
+}
+
+/// Encode an image file into `nlayers` layers. Layer i is scaled by 1/2^(nlayers-1-i),
+/// and the last layer is the original file.
+pub fn encode_layers(file: &[u8], nlayers: usize) -> ImageResult<Vec<Layer>> {
+    let img = image::load_from_memory(file)?;
+    let (width, height) = img.dimensions();
+    let nlayers = std::cmp::max(nlayers, 1);
+
+    let mut layers: Vec<Layer> = Vec::with_capacity(nlayers);
+    for i in 0..nlayers - 1 {
+        let scale = 1u32 << (nlayers - 1 - i) as u32;
+        let (w, h) = (std::cmp::max(width / scale, 1), std::cmp::max(height / scale, 1));
+        let quality = MIN_QUALITY + ((MAX_QUALITY - MIN_QUALITY) as usize * i / nlayers) as u8;
+
+        let mut content: Vec<u8> = Vec::new();
+        img.resize_exact(w, h, image::FilterType::Triangle)
+           .write_to(&mut content, image::ImageOutputFormat::JPEG(quality))?;
+        debug!("layer {}: {}x{} q={} {} bytes", i, w, h, quality, content.len());
+        layers.push( Layer{width: w, height: h, content: content} );
+    }
+
+    layers.push( Layer{width: width, height: height, content: file.to_vec()} );
+
+    Ok(layers)
+}
+
+/// Decode a prefix of blocks the same way the client renders it
+pub fn decode_prefix(blocks: &[Vec<u8>], render: Render) -> ImageResult<DynamicImage> {
+    match render {
+        Render::Concat => {
+            let data: Vec<u8> = blocks.iter().flat_map(|b| b.iter().cloned()).collect();
+            image::load_from_memory(&data)
+        },
+        Render::Replace => match blocks.last() {
+            Some(block) => image::load_from_memory(block),
+            None => Err(image::ImageError::ImageEnd),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::quality;
+
+    #[test]
+    fn test_every_prefix_decodes() {
+        let mut file = std::fs::File::open("data/img_5_30_11.jpg").unwrap();
+        let mut img = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut img).unwrap();
+
+        let layers = encode_layers(&img, 4).unwrap();
+        assert_eq!(layers.len(), 4);
+        assert_eq!(layers[3].content, img);
+        assert!(layers.windows(2).all(|l| l[0].width < l[1].width));
+
+        let blocks: Vec<Vec<u8>> = layers.into_iter().map(|l| l.content).collect();
+        for k in 1..=blocks.len() {
+            assert!(decode_prefix(&blocks[..k], Render::Replace).is_ok(), "prefix {}", k);
+        }
+
+        let curve = quality::measure_curve(&blocks, Render::Replace, quality::Metric::SSIM);
+        debug!("curve: {:?}", curve);
+        assert!(curve[0] > 0.0);
+    }
+}
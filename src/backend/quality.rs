@@ -0,0 +1,177 @@
+/*
+ * Measure the quality of progressively decoded blocks at ingestion time.
+ *
+ * For each prefix of n blocks, decode it with the `image` crate and compare
+ * it against the image decoded from all the blocks. The resulting curve is
+ * used as the utility function of the query by the scheduler.
+ */
+extern crate image;
+
+use image::{DynamicImage, GrayImage, GenericImageView};
+use serde_derive::{Deserialize, Serialize};
+use super::progressive::{self, Render};
+
+/// PSNR above this value is considered as good as the full image
+const PSNR_MAX: f64 = 50.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    PSNR,
+    SSIM,
+}
+
+/// peak signal to noise ratio in dB between two images of the same size
+pub fn psnr(a: &GrayImage, b: &GrayImage) -> f64 {
+    let mut mse: f64 = 0.0;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let d = pa[0] as f64 - pb[0] as f64;
+        mse += d * d;
+    }
+    mse /= (a.width() * a.height()) as f64;
+
+    if mse == 0.0 {
+        return std::f64::INFINITY;
+    }
+
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// mean structural similarity over 8x8 windows of two images of the same size
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    let mut sum: f64 = 0.0;
+    let mut windows = 0;
+
+    let mut y = 0;
+    while y < height {
+        let mut x = 0;
+        while x < width {
+            let w = std::cmp::min(WINDOW, width - x);
+            let h = std::cmp::min(WINDOW, height - y);
+            let n = (w * h) as f64;
+
+            let (mut mu_a, mut mu_b) = (0.0, 0.0);
+            for j in y..y + h {
+                for i in x..x + w {
+                    mu_a += a.get_pixel(i, j)[0] as f64;
+                    mu_b += b.get_pixel(i, j)[0] as f64;
+                }
+            }
+            mu_a /= n;
+            mu_b /= n;
+
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for j in y..y + h {
+                for i in x..x + w {
+                    let da = a.get_pixel(i, j)[0] as f64 - mu_a;
+                    let db = b.get_pixel(i, j)[0] as f64 - mu_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    cov += da * db;
+                }
+            }
+            var_a /= n;
+            var_b /= n;
+            cov /= n;
+
+            sum += ((2.0 * mu_a * mu_b + C1) * (2.0 * cov + C2)) /
+                   ((mu_a * mu_a + mu_b * mu_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+            x += WINDOW;
+        }
+        y += WINDOW;
+    }
+
+    match windows {
+        0 => 1.0,
+        _ => sum / windows as f64,
+    }
+}
+
+/// quality of `img` compared to `full` normalized to [0, 1], 1 being identical.
+/// lower resolution decodes are upscaled to the size of the full image.
+pub fn quality(img: &DynamicImage, full: &GrayImage, metric: Metric) -> f32 {
+    let img = match img.dimensions() == full.dimensions() {
+        true => img.to_luma(),
+        false => img.resize_exact(full.width(), full.height(), image::FilterType::Triangle).to_luma(),
+    };
+
+    let q = match metric {
+        Metric::PSNR => psnr(&img, full) / PSNR_MAX,
+        Metric::SSIM => ssim(&img, full),
+    };
+
+    q.max(0.0).min(1.0) as f32
+}
+
+/// Utility curve of a query: quality of rendering its first n blocks, n = 1..=blocks.len().
+/// Prefixes that can't be decoded have no utility, and the curve never decreases
+/// since the client keeps the best version it rendered.
+pub fn measure_curve(blocks: &[Vec<u8>], render: Render, metric: Metric) -> Vec<f32> {
+    let full = match progressive::decode_prefix(blocks, render) {
+        Ok(img) => img.to_luma(),
+        Err(e) => {
+            error!("can't decode the full image, fall back to a linear curve: {:?}", e);
+            let n = blocks.len();
+            return (0..n).map(|i| (i as f32 + 1.0) / n as f32).collect();
+        }
+    };
+
+    let mut curve: Vec<f32> = Vec::with_capacity(blocks.len());
+    let mut best: f32 = 0.0;
+    for n in 1..=blocks.len() {
+        let q = match n == blocks.len() {
+            true => 1.0,
+            false => match progressive::decode_prefix(&blocks[..n], render) {
+                Ok(img) => quality(&img, &full, metric),
+                Err(_) => 0.0,
+            },
+        };
+
+        best = best.max(q);
+        curve.push(best);
+    }
+
+    curve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_metrics() {
+        let a = GrayImage::from_fn(16, 16, |x, y| image::Luma([(x * 16 + y) as u8]));
+        let mut b = a.clone();
+        assert_eq!(ssim(&a, &b), 1.0);
+        assert_eq!(psnr(&a, &b), std::f64::INFINITY);
+
+        b.put_pixel(0, 0, image::Luma([255]));
+        assert!(ssim(&a, &b) < 1.0);
+        assert!(psnr(&a, &b) < PSNR_MAX);
+    }
+
+    #[test]
+    // $ cargo test test_measure_curve -- --nocapture
+    fn test_measure_curve() {
+        let mut file = std::fs::File::open("data/img_5_30_11.jpg").unwrap();
+        let mut img = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut img).unwrap();
+
+        let blocks: Vec<Vec<u8>> = img.chunks(20*1024).map(|c| c.to_vec()).collect();
+        let curve = measure_curve(&blocks, Render::Concat, Metric::SSIM);
+        debug!("curve: {:?}", curve);
+
+        // the fixture is a baseline jpeg of 51 blocks: truncated files don't decode and
+        // have no utility, the full file is as good as itself
+        assert_eq!(blocks.len(), 51);
+        assert_eq!(&curve[..50], &[0.0; 50][..]);
+        let full = progressive::decode_prefix(&blocks, Render::Concat).unwrap();
+        assert_eq!(quality(&full, &full.to_luma(), Metric::SSIM), 1.0);
+        assert_eq!(curve[50], 1.0);
+    }
+}
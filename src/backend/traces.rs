@@ -0,0 +1,212 @@
+/*
+ * Store of the traces and experiment events uploaded by the client.
+ *
+ * Traces are kept as {root}/{experiment}/{session}/{kind}-{name}.json, with names
+ * reduced to [A-Za-z0-9._-] so they can't leave the root, and never overwritten:
+ * a name that is taken gets a -1, -2, ... suffix. Each file is a TraceRecord whose
+ * `data` is what the client posted, see sim::trace::load.
+ */
+use crate::error::Error;
+
+use serde_derive::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// longest experiment or trace name kept
+const MAX_NAME_LEN: usize = 64;
+/// suffixes tried before giving up on a taken name
+const MAX_SUFFIX: usize = 10000;
+pub const DEFAULT_EXPERIMENT: &str = "default";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceKind {
+    /// session trace posted to /log/trace
+    Trace,
+    /// experiment events posted to /log/write
+    Events,
+}
+
+impl TraceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TraceKind::Trace => "trace",
+            TraceKind::Events => "events",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub kind: TraceKind,
+    pub experiment: String,
+    pub session: usize,
+    pub name: String,
+    /// ms since the epoch
+    pub created: u64,
+    pub data: serde_json::Value,
+}
+
+/// stored trace, its file is downloaded from /traces/{experiment}/{session}/{file}
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TraceEntry {
+    pub experiment: String,
+    pub session: usize,
+    pub file: String,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceStore {
+    root: PathBuf,
+}
+
+/// name reduced to [A-Za-z0-9._-], without leading dots, `default` if nothing is left
+pub fn sanitize(name: &str, default: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '_' })
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim_start_matches('.');
+
+    match name.is_empty() {
+        true => default.to_owned(),
+        false => name.to_owned(),
+    }
+}
+
+impl TraceStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        TraceStore{root: root.into()}
+    }
+
+    /// config: {trace_dir?: "./traces"}
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        TraceStore::new(config["trace_dir"].as_str().unwrap_or("./traces"))
+    }
+
+    /// write `data` posted by the client as a new record, returns where it is stored
+    pub fn save(&self, kind: TraceKind, experiment: &str, session: usize, name: &str,
+                data: serde_json::Value) -> Result<TraceEntry, Error> {
+        let experiment = sanitize(experiment, DEFAULT_EXPERIMENT);
+        let name = sanitize(name, kind.as_str());
+        let dir = self.root.join(&experiment).join(session.to_string());
+        std::fs::create_dir_all(&dir)?;
+
+        let created = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                          .map(|d| d.as_millis() as u64).unwrap_or(0);
+        let record = TraceRecord{kind: kind, experiment: experiment.clone(), session: session,
+                                 name: name.clone(), created: created, data: data};
+        let bytes = serde_json::to_vec_pretty(&record)?;
+
+        for n in 0..MAX_SUFFIX {
+            let file = match n {
+                0 => format!("{}-{}.json", kind.as_str(), name),
+                n => format!("{}-{}-{}.json", kind.as_str(), name, n),
+            };
+
+            // create_new fails if the file exists, even if it was created meanwhile
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(dir.join(&file)) {
+                Ok(mut f) => {
+                    f.write_all(&bytes)?;
+                    info!("stored {} into {:?}", kind.as_str(), dir.join(&file));
+                    return Ok(TraceEntry{experiment: experiment, session: session, file: file,
+                                         bytes: bytes.len() as u64});
+                },
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+
+        Err(Error::Conflict(format!("too many traces named {:?}", name)))
+    }
+
+    /// stored traces, sorted by experiment, session and file
+    pub fn list(&self) -> Result<Vec<TraceEntry>, Error> {
+        let mut entries = Vec::new();
+        if !self.root.exists() {
+            return Ok(entries);
+        }
+
+        for experiment in std::fs::read_dir(&self.root)? {
+            let experiment = experiment?;
+            if !experiment.file_type()?.is_dir() {
+                continue;
+            }
+
+            for session in std::fs::read_dir(experiment.path())? {
+                let session = session?;
+                // e.g. files of older versions that stored traces at the root
+                let id = match session.file_name().to_str().and_then(|s| s.parse::<usize>().ok()) {
+                    Some(id) if session.file_type()?.is_dir() => id,
+                    _ => continue,
+                };
+
+                for file in std::fs::read_dir(session.path())? {
+                    let file = file?;
+                    if !file.file_type()?.is_file() {
+                        continue;
+                    }
+                    entries.push(TraceEntry{
+                        experiment: experiment.file_name().to_string_lossy().into_owned(),
+                        session: id,
+                        file: file.file_name().to_string_lossy().into_owned(),
+                        bytes: file.metadata()?.len(),
+                    });
+                }
+            }
+        }
+
+        entries.sort_by(|a, b| (&a.experiment, a.session, &a.file).cmp(&(&b.experiment, b.session, &b.file)));
+        Ok(entries)
+    }
+
+    /// path of a stored trace, only for names `sanitize` leaves unchanged
+    pub fn path(&self, experiment: &str, session: usize, file: &str) -> Result<PathBuf, Error> {
+        if experiment.is_empty() || file.is_empty()
+           || sanitize(experiment, "") != experiment || sanitize(file, "") != file {
+            return Err(Error::InvalidInput(format!("invalid trace {}/{}/{}", experiment, session, file)));
+        }
+
+        let path = self.root.join(experiment).join(session.to_string()).join(file);
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(Error::NotFound(format!("trace {}/{}/{}", experiment, session, file))),
+        }
+    }
+
+    pub fn read(&self, experiment: &str, session: usize, file: &str) -> Result<Vec<u8>, Error> {
+        Ok(std::fs::read(self.path(experiment, session, file)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_store() {
+        assert_eq!(sanitize("../../etc/passwd", "trace"), "_.._etc_passwd");
+        assert_eq!(sanitize("..", "trace"), "trace");
+        assert_eq!(sanitize("session-anon-1", "trace"), "session-anon-1");
+
+        let root = std::env::temp_dir().join(format!("khameleon-traces-{}", std::process::id()));
+        let store = TraceStore::new(&root);
+        let data = serde_json::json!([{"etype": "query", "e": {"query": "R1", "time": 3}}]);
+
+        // the same name twice is kept twice
+        let first = store.save(TraceKind::Trace, "exp 1", 2, "../session", data.clone()).unwrap();
+        let second = store.save(TraceKind::Trace, "exp 1", 2, "../session", data.clone()).unwrap();
+        assert_eq!((first.experiment.as_str(), first.file.as_str()), ("exp_1", "trace-_session.json"));
+        assert_eq!(second.file, "trace-_session-1.json");
+        assert_eq!(store.list().unwrap(), vec![second, first.clone()]);
+
+        let bytes = store.read("exp_1", 2, &first.file).unwrap();
+        let record: TraceRecord = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!((record.kind, record.session, record.data), (TraceKind::Trace, 2, data));
+        assert!(store.read("..", 2, &first.file).is_err());
+        assert!(store.read("exp_1", 3, &first.file).is_err());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,108 @@
+/// Offline trace-driven simulator: replay a recorded session without a browser
+///
+/// $ cargo run --release --bin khameleon-sim -- sim.json
+///
+/// sim.json:
+/// {
+///   "appstate": {"appname": "TestApp", "cachesize": 100, "state": {}}, // same as /initapp
+///   "config": {},                         // server config passed to the app
+///   "trace": "traces/default/1/trace-session-anon-1.json",   // uploaded to /log/trace
+///   "events": "traces/default/1/events-exp_details.json",    // optional, uploaded to /log/write
+///   "schedulers": ["Greedy"],
+///   "batch": 100,
+///   "bandwidth": 10.0,                    // megabits per second
+///   "latency": 100,                       // round trip in ms
+///   "prior_weight": 0.1,                  // weight of the app's prior, see AppTrait::get_prior
+///   "interpolation": {"mode": "Linear", "horizon": 500}, // see scheduler::Interpolation
+///   "output": "log/sim_report.json"       // optional, default stdout
+/// }
+use khameleon::{apps, ds, scheduler, sim};
+
+#[macro_use]
+extern crate log;
+
+use std::sync::{Arc, RwLock};
+
+fn main() -> std::io::Result<()> {
+    // keep stdout for the report
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!("[{}][{}] {}", record.target(), record.level(), message))
+        })
+        .level(log::LevelFilter::Warn)
+        .level_for("khameleon_sim", log::LevelFilter::Info)
+        .chain(std::io::stderr())
+        .apply().unwrap();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <sim config.json>", args[0]);
+        std::process::exit(1);
+    }
+
+    let file = std::fs::File::open(&args[1]).expect("file should open read only");
+    let config: serde_json::Value = serde_json::from_reader(file).expect("JSON was not well-formatted");
+
+    let appstate: ds::AppState = serde_json::from_value(config["appstate"].clone()).expect("invalid app state");
+    let trace = config["trace"].as_str().expect("trace file is missing");
+    let events = match sim::trace::load(trace, config["events"].as_str()) {
+        Ok(events) => events,
+        Err(err) => {
+            eprintln!("couldn't load trace: {}", err);
+            std::process::exit(1);
+        }
+    };
+    info!("loaded {} events from {:?}", events.len(), trace);
+
+    let schedulers: Vec<scheduler::SchedulerType> = match config["schedulers"].as_array() {
+        Some(_) => serde_json::from_value(config["schedulers"].clone()).expect("invalid scheduler type"),
+        None => vec![scheduler::SchedulerType::Greedy],
+    };
+
+    let batch: usize = match config["batch"].as_u64() {
+        Some(b) => b as usize,
+        None => 100,
+    };
+
+    let network = sim::Network{
+        bandwidth: config["bandwidth"].as_f64().unwrap_or(10.0),
+        latency: config["latency"].as_u64().unwrap_or(100) as usize,
+    };
+
+    let prior_weight = config["prior_weight"].as_f64().unwrap_or(0.1) as f32;
+    let interpolation: scheduler::Interpolation = match config.get("interpolation") {
+        Some(i) => serde_json::from_value(i.clone()).expect("invalid interpolation"),
+        None => scheduler::Interpolation::default(),
+    };
+
+    let mut reports = serde_json::Map::new();
+    for schedtype in schedulers.iter() {
+        // fresh app per run, decoding may update app state
+        let state_change_flag = Arc::new(RwLock::new(false));
+        let mut app = apps::new(&appstate, config["config"].clone(), state_change_flag);
+        let (queries_blcount, _) = app.get_scheduler_config();
+        let blocks_per_query: Vec<usize> = queries_blcount.iter().map(|(_k, &v)| v).collect();
+        let utility = app.get_utility_curves();
+        let block_sizes = app.get_block_sizes();
+        let cachebytes = appstate.cache_bytes(block_sizes.unit());
+
+        let tm = Arc::new(RwLock::new(ds::TimeManager::new(1, network.latency, network.bandwidth)));
+        let sched = scheduler::new(schedtype, batch, cachebytes, utility,
+                                   blocks_per_query, block_sizes, Some(tm.clone()));
+
+        let report = sim::run(app.as_mut(), sched, tm, cachebytes, &network, prior_weight, interpolation, &events);
+        info!("{:?}: {:?}", schedtype, report);
+        reports.insert(format!("{:?}", schedtype), serde_json::to_value(&report)?);
+    }
+
+    let reports = serde_json::Value::Object(reports);
+    match config["output"].as_str() {
+        Some(path) => {
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(file, &reports)?;
+        },
+        None => println!("{}", serde_json::to_string_pretty(&reports)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+/// Measure utility curves of the blocks stored in an app's backend
+///
+/// $ cargo run --release --bin khameleon-utility -- TestApp data/test_data SSIM
+///
+/// For each query, every prefix of its blocks is decoded and compared to the
+/// full query (PSNR or SSIM). The curves are stored in the backend next to the
+/// blocks and returned by the app to the scheduler instead of the linear ramp.
+use khameleon::{apps, backend};
+
+#[macro_use]
+extern crate log;
+
+fn main() {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!("[{}][{}] {}", record.target(), record.level(), message))
+        })
+        .level(log::LevelFilter::Warn)
+        .level_for("khameleon_utility", log::LevelFilter::Info)
+        .chain(std::io::stderr())
+        .apply().unwrap();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: {} <TestApp|Game> <db path> [SSIM|PSNR]", args[0]);
+        std::process::exit(1);
+    }
+
+    let appname: apps::AppType = serde_json::from_value(serde_json::json!(args[1])).expect("unknown app");
+    let metric: backend::quality::Metric = match args.get(3) {
+        Some(m) => serde_json::from_value(serde_json::json!(m)).expect("unknown metric"),
+        None => backend::quality::Metric::SSIM,
+    };
+
+    if !std::path::Path::new(&args[2]).exists() {
+        eprintln!("backend is not initialized {:?}", args[2]);
+        std::process::exit(1);
+    }
+
+    let mut backend = backend::inmem::InMemBackend::new(args[2].clone());
+    let count = apps::measure_utility(appname, &mut backend, metric);
+    info!("measured {:?} utility of {} queries in {:?}", metric, count, args[2]);
+}
@@ -2,10 +2,12 @@
  *
  * TimeManager: stores system information that are needed to translate between server time
  *              to client time to enable model querying.
+ * Block: binary framing of every block streamed to the client, see BlockHeader.
  */
 
 /// local imports
 use crate::apps;
+use crate::payload::Payload;
 
 /// public lib
 use serde_json::{Value};
@@ -18,47 +20,290 @@ use std::sync::{Arc};
 #[allow(dead_code)]
 #[derive(Debug, Message)]
 pub enum StreamBlock {
-    Binary(Vec<u8>),
+    /// framed and sent as a binary message, the websocket assigns its sequence number
+    Block(Block),
+    /// error reported to the client as a json text message {"error": ...}
+    Error(String),
+    /// the manager started session n, see /initapp
+    Session(usize),
     Stop
 }
 
+/// first bytes of every block
+pub const BLOCK_MAGIC: [u8; 4] = *b"KHBL";
+/// version of the block header, bumped on incompatible changes
+pub const BLOCK_VERSION: u8 = 1;
+/// the header carries the CRC-32 of the payload
+pub const FLAG_CHECKSUM: u8 = 0b0000_0001;
+
+/// Header of a block, encoded little endian:
+///
+/// | bytes | field                                                   |
+/// |-------|---------------------------------------------------------|
+/// | 4     | magic "KHBL"                                            |
+/// | 1     | version                                                 |
+/// | 1     | flags                                                   |
+/// | 2     | key length                                              |
+/// | 4     | seq: block sequence number in the session, acked by the client |
+/// | 4     | block id within the query                               |
+/// | 4     | total blocks of the query                               |
+/// | 4     | payload length                                          |
+/// | 4     | CRC-32 of the payload, only with FLAG_CHECKSUM          |
+/// | n     | query key, utf-8                                        |
+///
+/// followed by the app specific payload
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub seq: u32,
+    pub key: String,
+    pub block_id: u32,
+    pub nblocks: u32,
+    pub payload_len: u32,
+    pub checksum: Option<u32>,
+}
+
+impl BlockHeader {
+    /// size in bytes of the fixed fields
+    pub const FIXED_LEN: usize = 24;
+
+    /// size in bytes of the encoded header
+    pub fn len(&self) -> usize {
+        let checksum = if self.flags & FLAG_CHECKSUM != 0 { 4 } else { 0 };
+        BlockHeader::FIXED_LEN + checksum + self.key.len()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// fewer bytes than the header announces
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidKey,
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::BadMagic => write!(f, "not a block, bad magic"),
+            BlockError::UnsupportedVersion(v) => write!(f, "unsupported block version {}", v),
+            BlockError::Truncated{expected, actual} =>
+                write!(f, "truncated block, expected {} bytes got {}", expected, actual),
+            BlockError::InvalidKey => write!(f, "block key isn't utf-8"),
+            BlockError::ChecksumMismatch{expected, actual} =>
+                write!(f, "block checksum {:08x} doesn't match the payload {:08x}", expected, actual),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Block of a query streamed to the client. Apps fill in the key, block id, total
+/// blocks and payload; the websocket assigns the sequence number and encodes it once
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub payload: Vec<u8>,
+    /// sent for a /request rather than by the scheduler, not encoded
+    pub requested: bool,
+}
+
+impl Block {
+    pub fn new(key: &str, block_id: u32, nblocks: u32, payload: Vec<u8>) -> Self {
+        let header = BlockHeader{version: BLOCK_VERSION, flags: 0, seq: 0, key: key.to_owned(),
+                                 block_id: block_id, nblocks: nblocks,
+                                 payload_len: payload.len() as u32, checksum: None};
+        Block{header: header, payload: payload, requested: false}
+    }
+
+    /// let the client verify the payload
+    pub fn with_checksum(mut self) -> Self {
+        self.header.flags |= FLAG_CHECKSUM;
+        self
+    }
+
+    /// size in bytes on the wire
+    pub fn len(&self) -> usize {
+        self.header.len() + self.payload.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let h = &self.header;
+        let mut bytes: Vec<u8> = Vec::with_capacity(self.len());
+        bytes.extend_from_slice(&BLOCK_MAGIC);
+        bytes.push(BLOCK_VERSION);
+        bytes.push(h.flags);
+        bytes.extend_from_slice(&(h.key.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&h.seq.to_le_bytes());
+        bytes.extend_from_slice(&h.block_id.to_le_bytes());
+        bytes.extend_from_slice(&h.nblocks.to_le_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        if h.flags & FLAG_CHECKSUM != 0 {
+            bytes.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        }
+        bytes.extend_from_slice(h.key.as_bytes());
+        bytes.extend_from_slice(&self.payload);
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Block, BlockError> {
+        let truncated = |expected: usize| BlockError::Truncated{expected: expected, actual: bytes.len()};
+        if bytes.len() < BlockHeader::FIXED_LEN {
+            return Err(truncated(BlockHeader::FIXED_LEN));
+        }
+        if bytes[0..4] != BLOCK_MAGIC {
+            return Err(BlockError::BadMagic);
+        }
+        if bytes[4] != BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(bytes[4]));
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let flags = bytes[5];
+        let key_len = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        let payload_len = u32_at(20);
+        let mut offset = BlockHeader::FIXED_LEN;
+        let checksum = match flags & FLAG_CHECKSUM != 0 {
+            true if bytes.len() >= offset + 4 => {
+                offset += 4;
+                Some(u32_at(offset - 4))
+            },
+            true => return Err(truncated(offset + 4)),
+            false => None,
+        };
+
+        let expected = offset + key_len + payload_len as usize;
+        if bytes.len() < expected {
+            return Err(truncated(expected));
+        }
+        let key = std::str::from_utf8(&bytes[offset..offset + key_len]).map_err(|_| BlockError::InvalidKey)?;
+        let payload = bytes[offset + key_len..expected].to_vec();
+        if let Some(expected) = checksum {
+            let actual = crc32(&payload);
+            if actual != expected {
+                return Err(BlockError::ChecksumMismatch{expected: expected, actual: actual});
+            }
+        }
+
+        let header = BlockHeader{version: bytes[4], flags: flags, seq: u32_at(8), key: key.to_owned(),
+                                 block_id: u32_at(12), nblocks: u32_at(16), payload_len: payload_len,
+                                 checksum: checksum};
+        Ok(Block{header: header, payload: payload, requested: false})
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PredictorState {
     pub model: String,
-    pub data: serde_json::Value,
+    pub data: Payload,
 }
 
 impl PredictorState {
-    pub fn new(model: &str, data: serde_json::Value) -> Self {
-        PredictorState{model: model.to_owned(), data: data}
+    /// `data` is json or the fields of a binary payload
+    pub fn new<D: Into<Payload>>(model: &str, data: D) -> Self {
+        PredictorState{model: model.to_owned(), data: data.into()}
+    }
+}
+
+/// Encoding of PredictorState.data sent by the client
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    /// see payload
+    Binary,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Binary => "binary",
+        }
+    }
+
+    /// first encoding offered by the client that the server supports, json by default
+    pub fn negotiate(offered: &[String]) -> Encoding {
+        offered.iter()
+            .filter_map(|e| serde_json::from_value(Value::String(e.to_lowercase())).ok())
+            .next()
+            .unwrap_or(Encoding::Json)
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AppState {
     pub appname: apps::AppType,
+    /// client cache size in blocks
     pub cachesize: usize,
+    /// optional: client cache size in bytes, for apps with blocks of different sizes
+    #[serde(default)]
+    pub cachebytes: Option<usize>,
+    /// optional: predict on the server from raw interaction events, see predictor::Event
+    #[serde(default)]
+    pub predictor: Option<crate::predictor::PredictorType>,
+    /// optional: encodings of predictor states the client can send, most preferred first
+    #[serde(default)]
+    pub encodings: Vec<String>,
 
     // app specific initializations
     pub state: Value,
 }
 
+impl AppState {
+    /// client cache capacity in bytes, by default 'cachesize' blocks of 'block_unit' bytes
+    pub fn cache_bytes(&self, block_unit: usize) -> usize {
+        match self.cachebytes {
+            Some(bytes) => bytes,
+            None => self.cachesize * block_unit,
+        }
+    }
+}
+
 pub struct TimeManager {
     time_block_transfer_ms: usize,
     /// latency in ms
     latency: usize,
     bw: Arc<AtomicCell<f64>>,
+    /// bandwidth reported by the client, `bw` is capped by `bw_cap`
+    measured_bw: f64,
+    bw_cap: Option<f64>,
     blocksize_megabits: f64,
     time: Option<std::time::Instant>,
 }
 
 impl TimeManager {
     pub fn new(time_block_transfer_ms: usize, latency: usize, bw: f64) -> Self {
+        let measured_bw = bw;
         let bw = Arc::new(AtomicCell::new(bw));
         
 
         TimeManager{ time_block_transfer_ms: time_block_transfer_ms,
-                     latency: latency, bw: bw,
+                     latency: latency, bw: bw, measured_bw: measured_bw, bw_cap: None,
                      blocksize_megabits: 0.0, time: None }
     }
 
@@ -77,6 +322,19 @@ impl TimeManager {
        (self.latency / 2) + progress  + slot * self.time_block_transfer_ms
     }
 
+    /// time (ms) to put @bytes on the network
+    #[inline]
+    pub fn transfer_ms(&self, bytes: usize) -> usize {
+        let size_megabits = (bytes as f64 * 8.0) / (1024.0 * 1024.0);
+        ((size_megabits / self.bw.load()) * 1000.0).ceil() as usize
+    }
+
+    /// time (ms) from sending a block of @bytes until it is at the client
+    #[inline]
+    pub fn arrival_delay_ms(&self, bytes: usize) -> usize {
+        (self.latency / 2) + self.transfer_ms(bytes)
+    }
+
     pub fn update_blocksize_megabits(&mut self, bsize_megabits: f64) {
         self.blocksize_megabits = bsize_megabits;
         self.update_transfer_time(self.bw.load(), bsize_megabits);
@@ -87,10 +345,34 @@ impl TimeManager {
     }
 
     pub fn update_bandwidth(&mut self, bw: f64) {
+        self.measured_bw = bw;
+        let bw = match self.bw_cap {
+            Some(cap) => bw.min(cap),
+            None => bw,
+        };
         self.bw.store(bw);
         self.update_transfer_time(bw, self.blocksize_megabits);
     }
 
+    /// limit the bandwidth used to stream blocks, none to use what the client reports
+    pub fn set_bandwidth_cap(&mut self, cap: Option<f64>) {
+        self.bw_cap = cap;
+        self.update_bandwidth(self.measured_bw);
+    }
+
+    pub fn bandwidth_cap(&self) -> Option<f64> {
+        self.bw_cap
+    }
+
+    /// bandwidth (megabits per second) blocks are streamed at
+    pub fn bandwidth(&self) -> f64 {
+        self.bw.load()
+    }
+
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
     pub fn get_ref_bw(&self) -> Arc<AtomicCell<f64>> {
         self.bw.clone()
     }
@@ -100,3 +382,41 @@ impl TimeManager {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_roundtrip() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut block = Block::new("R1", 2, 4, vec![1, 2, 3]);
+        block.header.seq = 7;
+        let bytes = block.encode();
+        assert_eq!(bytes.len(), block.len());
+        assert_eq!(&bytes[..4], b"KHBL");
+        assert_eq!(Block::decode(&bytes), Ok(block));
+
+        let block = Block::new("0:4,4", 0, 1, vec![9; 100]).with_checksum();
+        let mut bytes = block.encode();
+        let decoded = Block::decode(&bytes).unwrap();
+        assert_eq!(decoded.header.checksum, Some(crc32(&block.payload)));
+        assert_eq!(decoded.header.key, "0:4,4");
+
+        let last = bytes.len() - 1;
+        bytes[last] = 0;
+        assert!(matches!(Block::decode(&bytes), Err(BlockError::ChecksumMismatch{..})));
+        assert!(matches!(Block::decode(&bytes[..30]), Err(BlockError::Truncated{..})));
+        bytes[4] = 2;
+        assert_eq!(Block::decode(&bytes), Err(BlockError::UnsupportedVersion(2)));
+        assert_eq!(Block::decode(&[0; 24]), Err(BlockError::BadMagic));
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let offered = |e: &[&str]| e.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(Encoding::negotiate(&offered(&["cbor", "binary", "json"])), Encoding::Binary);
+        assert_eq!(Encoding::negotiate(&offered(&["JSON", "binary"])), Encoding::Json);
+        assert_eq!(Encoding::negotiate(&[]), Encoding::Json);
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Crate wide error type.
+ *
+ * Handlers return it instead of panicking on malformed input; as an actix-web
+ * response error it becomes a 4xx/5xx response with a json body {"error": ...}.
+ */
+use crate::scheduler;
+
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[derive(Debug)]
+pub enum Error {
+    /// malformed request or message from the client
+    InvalidInput(String),
+    /// no app is initialized yet, see /initapp
+    NotInitialized(String),
+    /// the request conflicts with the running session
+    Conflict(String),
+    /// e.g. a session that ended
+    NotFound(String),
+    /// the caller isn't allowed to, e.g. /admin without the token
+    Forbidden(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Bincode(bincode::Error),
+    Decode(scheduler::DecodeError),
+    /// a worker thread or an actor failed
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidInput(_) | Error::Json(_) | Error::Decode(_) => StatusCode::BAD_REQUEST,
+            Error::NotInitialized(_) | Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Io(_) | Error::Bincode(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            Error::NotInitialized(msg) => write!(f, "not initialized: {}", msg),
+            Error::Conflict(msg) => write!(f, "conflict: {}", msg),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Json(e) => write!(f, "invalid json: {}", e),
+            Error::Bincode(e) => write!(f, "invalid encoding: {}", e),
+            Error::Decode(e) => write!(f, "{}", e),
+            Error::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Bincode(e)
+    }
+}
+
+impl From<scheduler::DecodeError> for Error {
+    fn from(e: scheduler::DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<actix::MailboxError> for Error {
+    fn from(e: actix::MailboxError) -> Self {
+        Error::Internal(format!("manager is unreachable: {}", e))
+    }
+}
+
+impl From<BlockingError<Error>> for Error {
+    fn from(e: BlockingError<Error>) -> Self {
+        match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => Error::Internal("blocking task was canceled".to_owned()),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(serde_json::json!({"error": self.to_string()}))
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+/// lock state shared with the workers. A worker that panics while holding the lock
+/// poisons it; the supervisor restarts the worker and everyone else keeps going with
+/// the data as it was left, instead of the panic cascading through every user
+pub fn lock<T: ?Sized>(lock: &Mutex<T>) -> MutexGuard<T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// see lock
+pub fn read<T: ?Sized>(lock: &RwLock<T>) -> RwLockReadGuard<T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// see lock
+pub fn write<T: ?Sized>(lock: &RwLock<T>) -> RwLockWriteGuard<T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// message of a panic caught with std::panic::catch_unwind
+pub fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => msg.clone(),
+            None => "unknown panic".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let err = Error::from(serde_json::from_str::<serde_json::Value>("{").unwrap_err());
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.error_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(Error::NotInitialized("app".to_owned()).status(), StatusCode::CONFLICT);
+
+        let payload = std::panic::catch_unwind(|| panic!("worker {}", 1)).unwrap_err();
+        assert_eq!(panic_message(&payload), "worker 1");
+
+        // a panic while holding the lock doesn't lock everyone else out
+        let shared = std::sync::Arc::new(RwLock::new(1));
+        let worker = shared.clone();
+        assert!(std::thread::spawn(move || {
+            let _guard = worker.write().unwrap();
+            panic!("worker");
+        }).join().is_err());
+        assert!(shared.is_poisoned());
+        *write(&shared) += 1;
+        assert_eq!(*read(&shared), 2);
+    }
+}
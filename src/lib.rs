@@ -0,0 +1,31 @@
+/// local imports
+pub mod ds;
+pub mod payload;
+pub mod scheduler;
+pub mod manager;
+pub mod webserver;
+pub mod backend;
+pub mod apps;
+pub mod sim;
+pub mod predictor;
+pub mod error;
+
+/// public libs
+extern crate lp_modeler;
+extern crate csv;
+extern crate crossbeam;
+extern crate crossbeam_utils;
+
+#[macro_use]
+extern crate indexmap;
+
+#[macro_use]
+extern crate actix_web;
+
+#[macro_use]
+extern crate log;
+
+extern crate rand;
+
+#[macro_use]
+extern crate ndarray;
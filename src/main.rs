@@ -1,36 +1,14 @@
 /// local imports
-mod ds;
-mod scheduler;
-mod manager;
-mod webserver;
-mod backend;
-mod apps;
-
-/// public libs
-extern crate lp_modeler;
-extern crate csv;
-extern crate crossbeam;
-extern crate crossbeam_utils;
-
-#[macro_use]
-extern crate indexmap;
-
-#[macro_use]
-extern crate actix_web;
+use khameleon::{backend, manager, webserver};
 
 #[macro_use]
 extern crate log;
 extern crate fern;
 
-extern crate rand;
-
 use fern::colors::{Color, ColoredLevelConfig};
 
 extern crate chrono;
 
-#[macro_use]
-extern crate ndarray;
-
 use serde_json::json;
 
 use actix_web::{App, HttpServer, middleware};
@@ -112,6 +90,8 @@ fn main() -> std::io::Result<()> {
     let sys = actix_rt::System::new("khameleon-actix");
 
     // 2) Start Manager Thread/Actor
+    let traces = backend::traces::TraceStore::from_config(&config);
+    let delivery_logs = webserver::delivery::DeliveryLogConfig::from_config(&config);
     let imanager = manager::Manager::new(config);
     let manager_addr = imanager.start();
 
@@ -120,6 +100,8 @@ fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .data(manager_addr.clone())
+            .data(traces.clone())
+            .data(delivery_logs.clone())
             .configure(webserver::appconfig::config_app)
             // enable logger
             .wrap(middleware::Logger::default())
@@ -0,0 +1,246 @@
+/*
+ * Inspection and steering of live sessions, served under /admin.
+ *
+ * The scheduling and sender threads publish what they are doing in PlanState
+ * and follow the settings of Control, both shared through SharedState.
+ *
+ * The api is off unless the config has "admin_api": true. Callers then need the
+ * bearer token of "admin_token", or connect from the loopback interface if no token
+ * is set, see authorize. The debug channel checks its callers the same way.
+ */
+use super::manager::SharedState;
+use super::supervisor::PipelineHealth;
+use crate::apps;
+use crate::error::{self, Error};
+use crate::scheduler;
+
+use serde_derive::{Deserialize, Serialize};
+use actix::prelude::*;
+
+/// queries listed in the summary of the current distribution
+pub const TOP_QUERIES: usize = 10;
+
+/// settings of a session changed at runtime
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Control {
+    /// scheduler of the next rounds
+    pub scheduler: scheduler::SchedulerType,
+    /// the sender holds the blocks of the plan
+    pub paused: bool,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control{scheduler: scheduler::SchedulerType::TopK, paused: false}
+    }
+}
+
+/// what the scheduling and sender threads are doing
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PlanState {
+    /// scheduling rounds so far
+    pub round: usize,
+    /// query of each block slot of the current plan
+    pub plan: Vec<usize>,
+    /// slots of the plan the sender went through
+    pub position: usize,
+    /// most likely queries of the distribution the plan was made for, when
+    /// its next block arrives
+    pub top_queries: Vec<(usize, f32)>,
+}
+
+/// content of the cache model, see CacheSimulator
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheSummary {
+    pub cachesize: usize,
+    pub head: usize,
+    /// (query, blocks in the cache) of the cached queries
+    pub blocks: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NetworkSummary {
+    /// megabits per second
+    pub bandwidth: f64,
+    pub bandwidth_cap: Option<f64>,
+    /// round trip in ms
+    pub latency: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub app: apps::AppType,
+    pub control: Control,
+    pub plan: PlanState,
+    pub cache: CacheSummary,
+    pub network: NetworkSummary,
+    pub pipeline: PipelineHealth,
+}
+
+impl SessionInfo {
+    pub fn new(id: usize, state: &SharedState) -> Self {
+        let cache = {
+            let cache = error::read(&state.cache_sim);
+            CacheSummary{
+                cachesize: cache.cachesize,
+                head: cache.head,
+                blocks: cache.cache_per_query.iter().enumerate()
+                             .filter(|(_, &n)| n > 0).map(|(q, &n)| (q, n)).collect(),
+            }
+        };
+
+        let network = {
+            let tm = error::read(&state.tm);
+            NetworkSummary{bandwidth: tm.bandwidth(), bandwidth_cap: tm.bandwidth_cap(), latency: tm.latency()}
+        };
+
+        SessionInfo{
+            id: id,
+            app: state.appstate.appname,
+            control: error::read(&state.control).clone(),
+            plan: error::read(&state.plan).clone(),
+            cache: cache,
+            network: network,
+            pipeline: error::read(&state.health).clone(),
+        }
+    }
+}
+
+#[derive(MessageResponse, Clone, Debug, Serialize)]
+pub struct Sessions {
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// changes to a session, fields left out are unchanged
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Steer {
+    pub scheduler: Option<scheduler::SchedulerType>,
+    /// megabits per second, 0 removes the cap
+    pub bandwidth_cap: Option<f64>,
+    pub paused: Option<bool>,
+}
+
+impl Steer {
+    pub fn apply(&self, state: &SharedState) {
+        {
+            let mut control = error::write(&state.control);
+            if let Some(schedtype) = self.scheduler {
+                info!("switch scheduler {:?} -> {:?}", control.scheduler, schedtype);
+                control.scheduler = schedtype;
+            }
+            if let Some(paused) = self.paused {
+                info!("streaming paused: {}", paused);
+                control.paused = paused;
+            }
+        }
+
+        if let Some(cap) = self.bandwidth_cap {
+            error::write(&state.tm).set_bandwidth_cap(if cap > 0.0 { Some(cap) } else { None });
+        }
+    }
+}
+
+/// caller of /admin
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Caller {
+    /// connected from the loopback interface and not through a proxy: a reverse proxy
+    /// on the same host connects from loopback on behalf of remote clients
+    pub local: bool,
+    /// bearer token of the Authorization header
+    pub token: Option<String>,
+}
+
+/// config: {admin_api?: false, admin_token?: "..."}
+pub fn authorize(config: &serde_json::Value, caller: &Caller) -> Result<(), Error> {
+    if !config["admin_api"].as_bool().unwrap_or(false) {
+        return Err(Error::NotFound("admin api is disabled, see \"admin_api\" in the config".to_owned()));
+    }
+    authorize_caller(config, caller)
+}
+
+/// the caller has the admin token, or is local if there is none
+pub fn authorize_caller(config: &serde_json::Value, caller: &Caller) -> Result<(), Error> {
+    match config["admin_token"].as_str() {
+        Some(token) if caller.token.as_ref().map_or(false, |t| token_eq(t, token)) => Ok(()),
+        Some(_) => Err(Error::Forbidden("missing or wrong admin token".to_owned())),
+        None if caller.local => Ok(()),
+        None => Err(Error::Forbidden("admin api is only served locally without \"admin_token\"".to_owned())),
+    }
+}
+
+/// compares tokens in a time that doesn't depend on where they differ
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    // two queries of one block
+    struct TwoQueries {
+        decoders: scheduler::DecoderRegistry,
+    }
+
+    impl apps::AppTrait for TwoQueries {
+        fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+            (indexmap::indexmap!{"a".to_owned() => 1, "b".to_owned() => 1}, vec![1.0])
+        }
+
+        fn get_decoders(&mut self) -> &mut scheduler::DecoderRegistry {
+            &mut self.decoders
+        }
+
+        fn get_block_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[test]
+    fn test_steer_session() {
+        let appstate: crate::ds::AppState = serde_json::from_value(serde_json::json!({
+            "appname": "TestApp", "cachesize": 10, "state": {}})).unwrap();
+        let app: Box<dyn apps::AppTrait> = Box::new(TwoQueries{decoders: scheduler::DecoderRegistry::new()});
+        let state = SharedState::new(appstate, Arc::new(Mutex::new(app)), Arc::new(RwLock::new(false)));
+        state.tm.write().unwrap().update_bandwidth(8.0);
+
+        let steer: Steer = serde_json::from_str(r#"{"scheduler": "Greedy", "bandwidth_cap": 2.5}"#).unwrap();
+        steer.apply(&state);
+        let info = SessionInfo::new(1, &state);
+        assert_eq!(info.control, Control{scheduler: scheduler::SchedulerType::Greedy, paused: false});
+        assert_eq!((info.network.bandwidth, info.network.bandwidth_cap), (2.5, Some(2.5)));
+
+        // the client reports less than the cap, then the cap is removed
+        state.tm.write().unwrap().update_bandwidth(1.0);
+        assert_eq!(SessionInfo::new(1, &state).network.bandwidth, 1.0);
+        Steer{bandwidth_cap: Some(0.0), paused: Some(true), ..Default::default()}.apply(&state);
+        let info = SessionInfo::new(1, &state);
+        assert_eq!((info.network.bandwidth, info.network.bandwidth_cap, info.control.paused), (1.0, None, true));
+    }
+
+    #[test]
+    fn test_authorize() {
+        let local = Caller{local: true, token: None};
+        let remote = Caller{local: false, token: Some("secret".to_owned())};
+        assert!(authorize(&serde_json::json!({}), &local).is_err());
+        // the debug channel checks the caller without the admin api
+        assert!(authorize_caller(&serde_json::json!({}), &local).is_ok());
+        assert!(authorize_caller(&serde_json::json!({}), &remote).is_err());
+
+        let config = serde_json::json!({"admin_api": true});
+        assert!(authorize(&config, &local).is_ok());
+        assert!(authorize(&config, &remote).is_err());
+
+        let config = serde_json::json!({"admin_api": true, "admin_token": "secret"});
+        assert!(authorize(&config, &local).is_err());
+        assert!(authorize(&config, &remote).is_ok());
+        let wrong = Caller{token: Some("guess".to_owned()), ..remote};
+        assert_eq!(authorize(&config, &wrong).unwrap_err().status(), actix_web::http::StatusCode::FORBIDDEN);
+        let prefix = Caller{token: Some("secre".to_owned()), ..wrong};
+        assert!(authorize(&config, &prefix).is_err());
+        assert!(token_eq("secret", "secret") && !token_eq("secret", "secreT") && !token_eq("secret", ""));
+    }
+}
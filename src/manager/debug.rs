@@ -0,0 +1,127 @@
+/*
+ * Opt-in debug channel: what the scheduler decided and what was streamed, as it
+ * happens, for the dashboard at client/static/debug.html.
+ *
+ * The scheduling thread and the websocket emit DebugEvents to the subscribers of
+ * the current session, see webserver::debug. Events are only built when someone
+ * is listening.
+ */
+use crate::error;
+use crate::scheduler;
+
+use serde_derive::Serialize;
+use std::sync::{Arc, Mutex};
+use actix::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum DebugEvent {
+    /// predictor state decoded by the scheduling thread
+    Distribution {
+        round: usize,
+        model: String,
+    },
+    /// most likely queries of the distribution, when the next block arrives
+    TopK {
+        round: usize,
+        queries: Vec<(usize, f32)>,
+    },
+    /// query of each block slot
+    Plan {
+        round: usize,
+        scheduler: scheduler::SchedulerType,
+        plan: Vec<usize>,
+        elapsed_ms: f64,
+    },
+    BlockSent {
+        seq: u32,
+        key: String,
+        block_id: u32,
+        nblocks: u32,
+        bytes: usize,
+    },
+    /// the client acked block `seq`
+    Ack {
+        seq: u32,
+        rtt_ms: u128,
+    },
+    /// the subscribers are dropped, e.g. after a new /initapp
+    SessionEnded,
+}
+
+/// event as sent to the dashboard: {"session": .., "time": .., "type": .., ...}
+#[derive(Message, Clone, Debug, Serialize)]
+#[rtype(result="()")]
+pub struct DebugMessage {
+    pub session: usize,
+    /// ms since the epoch
+    pub time: u128,
+    #[serde(flatten)]
+    pub event: DebugEvent,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    session: usize,
+    recipients: Vec<Recipient<DebugMessage>>,
+}
+
+/// subscribers to the debug events of the current session, shared by the manager,
+/// its threads and the websocket
+#[derive(MessageResponse, Clone, Default)]
+pub struct DebugChannel {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl DebugChannel {
+    /// someone is listening
+    pub fn active(&self) -> bool {
+        !error::lock(&self.subscribers).recipients.is_empty()
+    }
+
+    pub fn session(&self) -> usize {
+        error::lock(&self.subscribers).session
+    }
+
+    /// send the event built by `event` to the subscribers, dropping the closed ones
+    pub fn emit<F: FnOnce() -> DebugEvent>(&self, event: F) {
+        if !self.active() {
+            return;
+        }
+
+        let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                       .map(|d| d.as_millis()).unwrap_or(0);
+        let mut s = error::lock(&self.subscribers);
+        let msg = DebugMessage{session: s.session, time: time, event: event()};
+        s.recipients.retain(|r| r.do_send(msg.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self, recipient: Recipient<DebugMessage>) {
+        error::lock(&self.subscribers).recipients.push(recipient);
+    }
+
+    /// end the current session for its subscribers and follow `session`
+    pub fn start_session(&self, session: usize) {
+        self.emit(|| DebugEvent::SessionEnded);
+        let mut s = error::lock(&self.subscribers);
+        s.recipients.clear();
+        s.session = session;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_message_json() {
+        let msg = DebugMessage{session: 2, time: 10, event: DebugEvent::Ack{seq: 7, rtt_ms: 42}};
+        assert_eq!(serde_json::to_value(&msg).unwrap(),
+                   serde_json::json!({"session": 2, "time": 10, "type": "Ack", "seq": 7, "rtt_ms": 42}));
+
+        // nothing is built without subscribers
+        let channel = DebugChannel::default();
+        channel.emit(|| panic!("no subscriber"));
+        assert!(!channel.active());
+    }
+}
@@ -5,15 +5,20 @@
 /// local imports
 use crate::apps;
 use crate::ds;
+use crate::error::{self, Error};
+use crate::predictor;
 use crate::scheduler;
+use super::supervisor::PipelineHealth;
+use super::admin::{Caller, SessionInfo, Sessions, Steer};
+use super::debug::{DebugChannel, DebugMessage};
 
 /// public lib
 use serde_derive::{Deserialize, Serialize};
 use crossbeam_utils::atomic::AtomicCell;
-use std::io::prelude::*;
 
+use std::collections::HashMap;
 use std::sync::mpsc::{self, TrySendError};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
 use std::thread;
 use std::time::{Instant};
 // for the Actor primitive
@@ -26,9 +31,12 @@ use actix::prelude::*;
 /// kill_thread_flfag: singal to threads end of execution.
 /// dist_{tx/rx}: receives client update state and send it to scheduling thread.
 /// schedule_{tx/rx}: store the decision made by scheduler and send it to streaming thread.
+/// deadlines: time after which blocks of a query are stale, set by scheduler and used by streaming thread.
 /// appstate: application configuration received from client.
 /// app: instantiation of a new application based on received appstate.
 /// threads: handles for current running threads.
+/// health: status of the supervised threads.
+/// control, plan: settings changed and progress reported under /admin.
 pub struct SharedState {
     pub kill_thread_flag: Arc<AtomicCell<bool>>,
 
@@ -41,6 +49,7 @@ pub struct SharedState {
     // shared by scheduler and sender
     pub schedule_tx: Arc<Mutex<mpsc::SyncSender<Vec<usize>>>>,
     pub schedule_rx: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>,
+    pub deadlines: Arc<RwLock<HashMap<usize, Instant>>>,
 
     /// pass the sender to the application, which would be
     /// responsible for signaling to the scheduler if state
@@ -50,9 +59,16 @@ pub struct SharedState {
     pub appstate: ds::AppState,
     pub app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
     pub threads: Vec<Option<thread::JoinHandle<()>>>,
+    /// status of the threads, updated by their supervisor
+    pub health: Arc<RwLock<PipelineHealth>>,
+    pub control: Arc<RwLock<super::admin::Control>>,
+    pub plan: Arc<RwLock<super::admin::PlanState>>,
     pub tm: Arc<RwLock<ds::TimeManager>>,
 
 
+    /// server side predictor of the session, fed by raw interaction events
+    pub predictor: Option<Box<dyn predictor::Predictor>>,
+
     // prefetch manager
     pub request_count: usize,
     pub timestamp: std::time::Instant,
@@ -71,6 +87,8 @@ impl SharedState {
         let schedule_tx = Arc::new(Mutex::new(schedule_tx));
         let schedule_rx = Arc::new(Mutex::new(schedule_rx));
 
+        let deadlines = Arc::new(RwLock::new(HashMap::new()));
+
         let threads = Vec::with_capacity(2);
         
         let latency_init = 100;
@@ -81,26 +99,44 @@ impl SharedState {
         let tm = Arc::new(RwLock::new(tm));
         let timestamp = Instant::now();
         
-        let cachesize = appstate.cachesize;
-        let (queries_blcount, _)  = app.lock().unwrap().get_scheduler_config();
+        let cachebytes = appstate.cache_bytes(error::lock(&app).get_block_sizes().unit());
+        let (queries_blcount, _)  = error::lock(&app).get_scheduler_config();
         let total_queries = queries_blcount.len();
-        let cache_sim = Arc::new( RwLock::new( super::CacheSimulator::new(cachesize, total_queries) ));
+        let cache_sim = Arc::new( RwLock::new( super::CacheSimulator::new(cachebytes, total_queries) ));
+        let predictor = appstate.predictor.map(|ptype| predictor::new(ptype, &appstate.state));
 
         SharedState{
                     kill_thread_flag: kill_thread_flag,
                     appstate: appstate, app: app,
                     threads: threads,
+                    health: Arc::new(RwLock::new(PipelineHealth::default())),
+                    control: Arc::new(RwLock::new(super::admin::Control::default())),
+                    plan: Arc::new(RwLock::new(super::admin::PlanState::default())),
                     dist_tx: dist_tx,
                     dist_rx: dist_rx,
                     schedule_tx: schedule_tx,
                     schedule_rx: schedule_rx,
+                    deadlines: deadlines,
                     state_change_flag: state_change_flag,
                     tm: tm,
+                    predictor: predictor,
                     request_count: 0,
                     timestamp: timestamp,
                     cache_sim: cache_sim,
         }
     }
+
+    /// pass a predictor state to the scheduling thread, replacing the one it didn't pick up yet
+    pub fn push_dist(&self, userstate: ds::PredictorState) {
+        let v = error::lock(&self.dist_tx);
+        match v.try_send(userstate) {
+            Err(TrySendError::Full(data)) => {
+                let _ = error::lock(&self.dist_rx).try_recv();
+                let _ = v.try_send(data);
+            },
+            _ => {},
+        };
+    }
 }
 
 pub struct Manager {
@@ -112,6 +148,8 @@ pub struct Manager {
 
     pub config: serde_json::Value,
     pub congestion: Option<Arc<AtomicCell<u128>>>,
+    /// subscribers to the debug events of the session, see super::debug
+    pub debug: DebugChannel,
 }
 
 impl Actor for Manager {
@@ -120,22 +158,26 @@ impl Actor for Manager {
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Manager actor: started");
         self.manager_addr = Some(ctx.address());
-        
-        // create dir if it doesnt exist
-        let path = format!("manager_started.flag");
-
-        debug!("write file to signal manager initialized {:?}", path);
-        let path = std::path::Path::new( &path );
-        let display = path.display();
 
-        let mut file = match std::fs::File::create(path) {
-            Ok(file) => file,
-            Err(why) => panic!("couldnt create {}: {}", display, why.to_string()),
-        };
+        // optional file to signal the manager is up, /readyz tells the same
+        if let Some(path) = self.config["ready_file"].as_str() {
+            debug!("write file to signal manager initialized {:?}", path);
+            match std::fs::write(path, "ready") {
+                Ok(_) => debug!("successfully wrote to {}", path),
+                Err(why) => error!("couldn't write to {}: {}", path, why),
+            }
+        }
+    }
 
-        match file.write_all("done".as_bytes()) {
-            Err(why) => panic!("couldn't write to {}: {}", display, why.to_string()),
-            Ok(_) => debug!("successfully wrote to {}", display),
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some(state) = &self.state {
+            state.kill_thread_flag.store(true);
+            error::lock(&state.app).shutdown();
+        }
+        if let Some(path) = self.config["ready_file"].as_str() {
+            if let Err(why) = std::fs::remove_file(path) {
+                error!("couldn't remove {}: {}", path, why);
+            }
         }
     }
 }
@@ -143,16 +185,24 @@ impl Actor for Manager {
 /// Actor Model using acitx
 /// This message struct to pass websocket address from server to manager
 #[derive(Message)]
-#[rtype(bool)]
+#[rtype(Connected)]
 pub struct Connect {
     pub ws_addr: Recipient<ds::StreamBlock>,
     pub congestion: Arc<AtomicCell<u128>>,
 }
 
+/// what the websocket needs to report the blocks it sends and their acks
+#[derive(MessageResponse)]
+pub struct Connected {
+    pub debug: DebugChannel,
+    /// current session, the websocket gets a StreamBlock::Session for the next ones
+    pub session: usize,
+}
+
 /// implementation of actor model for `Connect` Message
 /// start communication threads to scheduler and stream data to client
 impl Handler<Connect> for Manager {
-    type Result = bool;
+    type Result = Connected;
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
         match &self.ws_addr {
@@ -168,7 +218,7 @@ impl Handler<Connect> for Manager {
         self.ws_addr = Some(msg.ws_addr);
         self.congestion = Some(msg.congestion);
 
-        true
+        Connected{debug: self.debug.clone(), session: self.instance}
     }
 }
 
@@ -188,13 +238,9 @@ impl Handler<SystemStat> for Manager {
 
         match &self.state {
             Some(state) => {
-                match state.tm.write() {
-                    Ok(mut tm) => {
-                        tm.update_bandwidth(stat.bw);
-                        tm.update_latency(stat.latency as usize);
-                    }
-                    Err(e)=> error!("couldn't update bandwidth, {:?}", e),
-                }
+                let mut tm = error::write(&state.tm);
+                tm.update_bandwidth(stat.bw);
+                tm.update_latency(stat.latency as usize);
             }
             None => (),
         }
@@ -212,34 +258,76 @@ pub struct Distributions {
 impl Handler<Distributions> for Manager {
     type Result = usize;
 
-    fn handle(&mut self, msg: Distributions, _: &mut Self::Context) -> Self::Result {
-        let userstate: ds::PredictorState = serde_json::from_str(&msg.data).unwrap();
+    fn handle(&mut self, msg: Distributions, ctx: &mut Self::Context) -> Self::Result {
+        match serde_json::from_str::<ds::PredictorState>(&msg.data) {
+            Ok(userstate) => <Self as Handler<DistUpdate>>::handle(self, DistUpdate{state: userstate}, ctx),
+            Err(err) => {
+                error!("invalid predictor state {:?}: {}", msg.data, err);
+                self.dist_counter
+            }
+        }
+    }
+}
 
+/// predictor state already parsed, e.g. received on the websocket
+#[derive(Message)]
+#[rtype(usize)]
+pub struct DistUpdate {
+    pub state: ds::PredictorState,
+}
+
+impl Handler<DistUpdate> for Manager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: DistUpdate, _: &mut Self::Context) -> Self::Result {
         if let Some(state) = &self.state {
             self.dist_counter += 1;
-            debug!("====> Manager Actor got new distribution {:?} -> {:?}", self.dist_counter, userstate);
-
-            match state.dist_tx.lock() {
-                Ok(v) => {
-                    match v.try_send(userstate) {
-                        Err(TrySendError::Full(data)) => {
-                            match state.dist_rx.lock() {
-                                Ok(rx) => {
-                                    rx.try_recv();
-                                }
-                                Err(err) => {
-                                    error!("Distributions: error dist_rx lock {:?}", err);
-                                }
-                            }
-                            let _ = v.try_send(data);
-                        },
-                        _ => {},
+            debug!("====> Manager Actor got new distribution {:?} -> {:?}", self.dist_counter, msg.state);
+            state.push_dist(msg.state);
+        }
+
+        self.dist_counter
+    }
+}
+
+/// raw interaction events for the server side predictor, see predictor::Event
+#[derive(Message)]
+#[rtype(usize)]
+pub struct Events {
+    pub data: String,
+}
+
+impl Handler<Events> for Manager {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Events, _: &mut Self::Context) -> Self::Result {
+        let events = match predictor::Event::parse(&msg.data) {
+            Ok(events) => events,
+            Err(err) => {
+                error!("invalid events {:?}: {}", msg.data, err);
+                return self.dist_counter;
+            }
+        };
+
+        if let Some(state) = &mut self.state {
+            let userstate = match &mut state.predictor {
+                Some(p) => {
+                    for event in events.iter() {
+                        p.observe(event);
                     }
-                }
-                Err(_) => {
-                    error!("couldn't get hold of channel dist_tx");
+                    p.predict()
+                },
+                None => {
+                    error!("received events but the session has no server side predictor");
+                    None
                 }
             };
+
+            if let Some(userstate) = userstate {
+                self.dist_counter += 1;
+                debug!("====> Manager Actor predicted distribution {:?} -> {:?}", self.dist_counter, userstate);
+                state.push_dist(userstate);
+            }
         }
 
         self.dist_counter
@@ -247,7 +335,7 @@ impl Handler<Distributions> for Manager {
 }
 
 #[derive(Message, Debug, Serialize, Deserialize)]
-#[rtype(bool)]
+#[rtype(result="Result<bool, Error>")]
 pub struct Request {
     /// currently, json encoded strings are only supported as queries
     pub query: serde_json::Value,
@@ -256,7 +344,7 @@ pub struct Request {
 }
 
 impl Handler<Request> for Manager {
-    type Result = bool;
+    type Result = Result<bool, Error>;
 
     fn handle(&mut self, msg: Request, _: &mut Self::Context) -> Self::Result {
         debug!("====> Manager Actor got new direct request {:?} {:?}", msg.query, msg.rtype);
@@ -265,7 +353,7 @@ impl Handler<Request> for Manager {
         // available bandwidth
         let ws_addr = match self.ws_addr.clone() {
             Some(addr) => addr,
-            None => panic!("websocket address wasn't initialized"),
+            None => return Err(Error::NotInitialized("websocket isn't connected".to_owned())),
         };
         
         
@@ -273,11 +361,13 @@ impl Handler<Request> for Manager {
             Some(state) => {
                 let mut queries = vec![];
                 if msg.rtype == true {
-                    let mut a: Vec<String> = serde_json::from_value(msg.query).unwrap();
+                    let mut a: Vec<String> = serde_json::from_value(msg.query)?;
                     queries.append(& mut a);
 
                 } else {
-                    let q: String = serde_json::from_value(msg.query).unwrap();
+                    let q: String = serde_json::from_value(msg.query)?;
+                    // requests, unlike prefetches, tell how popular a query is
+                    error::lock(&state.app).record_request(&q);
                     queries.push(q);
                 }
 
@@ -286,10 +376,17 @@ impl Handler<Request> for Manager {
                     // for each request
                     let count = 1;
                     let incache = 0;
-                    match state.app.lock().unwrap().get_nblocks_bykey(&q, count, incache) {
+                    match error::lock(&state.app).get_nblocks_bykey(&q, count, incache) {
                         Some(blocks) => {
                             for b in blocks {
                                 state.request_count += 1;
+                                let b = match b {
+                                    ds::StreamBlock::Block(mut block) => {
+                                        block.requested = true;
+                                        ds::StreamBlock::Block(block)
+                                    },
+                                    other => other,
+                                };
                                 let _ = ws_addr.do_send(b);
                             }
                             
@@ -299,12 +396,9 @@ impl Handler<Request> for Manager {
                     }
                 }
 
-                ret
-                
-
-
+                Ok(ret)
             },
-            None => panic!("state is not initialized"),
+            None => Err(Error::NotInitialized("no app is initialized".to_owned())),
         }
     }
 }
@@ -312,17 +406,19 @@ impl Handler<Request> for Manager {
 #[derive(MessageResponse, PartialEq)]
 pub struct InitAppData {
     pub instance: usize,
-    pub data: String
+    pub data: String,
+    /// encoding of predictor states negotiated with the client
+    pub encoding: ds::Encoding,
 }
 
 #[derive(Message)]
-#[rtype(InitAppData)]
+#[rtype(result="Result<InitAppData, Error>")]
 pub struct InitApp {
     pub state: String,
 }
 
 impl Handler<InitApp> for Manager {
-    type Result = InitAppData;
+    type Result = Result<InitAppData, Error>;
 
     fn handle(&mut self, msg: InitApp, _: &mut Self::Context) -> Self::Result {
         match serde_json::from_str(&msg.state) {
@@ -339,11 +435,16 @@ impl Handler<InitApp> for Manager {
                         // 2) join thread handles
                         for worker in &mut state.threads {
                             if let Some(thread) = worker.take() {
-                                thread.join().unwrap();
-                                debug!("joined thread");
+                                match thread.join() {
+                                    Ok(_) => debug!("joined thread"),
+                                    Err(err) => error!("worker thread panicked: {}", error::panic_message(&err)),
+                                }
                             }
                         }
 
+                        // 3) end the session of the app, it serves the next one
+                        error::lock(&state.app).shutdown();
+
                         let state_change_flag = Arc::new(RwLock::new(false));
                         let app = state.app.clone();
                         let shstate= SharedState::new(appstate, app, state_change_flag);
@@ -360,20 +461,18 @@ impl Handler<InitApp> for Manager {
                         let app = Arc::new(Mutex::new(apps::new(&appstate, self.config.clone(), state_change_flag.clone())));
                         let shstate = SharedState::new(appstate, app, state_change_flag);
 
-                        match shstate.tm.write() {
-                            Ok(mut tm) => {
-                                let latency: usize = match self.config["latency"].as_u64() {
-                                    Some(l) => l as usize,
-                                    None => 100
-                                };
-                                let bw  = match self.config["bandwidth"].as_f64() {
-                                    Some(l) => l,
-                                    None => 10.0
-                                };
-                                tm.update_bandwidth(bw);
-                                tm.update_latency(latency as usize);
-                            }
-                            Err(e)=> error!("couldn't update bandwidth, {:?}", e),
+                        {
+                            let mut tm = error::write(&shstate.tm);
+                            let latency: usize = match self.config["latency"].as_u64() {
+                                Some(l) => l as usize,
+                                None => 100
+                            };
+                            let bw  = match self.config["bandwidth"].as_f64() {
+                                Some(l) => l,
+                                None => 10.0
+                            };
+                            tm.update_bandwidth(bw);
+                            tm.update_latency(latency as usize);
                         }
 
 
@@ -382,30 +481,37 @@ impl Handler<InitApp> for Manager {
                     }
                 }
             }
-            Err(err) => panic!("invalid app state {:?}", err),
+            Err(err) => return Err(Error::InvalidInput(format!("invalid app state: {}", err))),
         };
 
         let state = match &mut self.state {
             Some(state) => state,
-            None => panic!("no state initialized"),
+            None => return Err(Error::NotInitialized("no app is initialized".to_owned())),
         };
         
-        let appinit = state.app.lock().unwrap().get_initstate();
+        error::lock(&state.app).get_utility_curves().validate()?;
+        let appinit = error::lock(&state.app).get_initstate();
+        let encoding = ds::Encoding::negotiate(&state.appstate.encodings);
 
 
         debug!("running {} threads", state.threads.len());
-        InitAppData{instance: self.instance, data: appinit}
+        self.debug.start_session(self.instance);
+        if let Some(addr) = &self.ws_addr {
+            let _ = addr.do_send(ds::StreamBlock::Session(self.instance));
+        }
+
+        Ok(InitAppData{instance: self.instance, data: appinit, encoding: encoding})
     }
 }
 
 #[derive(Message)]
-#[rtype(bool)]
+#[rtype(result="Result<bool, Error>")]
 pub struct StartThreads;
 
 impl Handler<StartThreads> for Manager {
-    type Result = bool;
+    type Result = Result<bool, Error>;
 
-    fn handle(&mut self, _msg: StartThreads, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, _msg: StartThreads, ctx: &mut Self::Context) -> Self::Result {
         let run_scheduler: bool = match self.config["runScheduler"].as_bool() {
             Some(flag) => flag,
             None => {
@@ -419,21 +525,229 @@ impl Handler<StartThreads> for Manager {
         if run_scheduler {
             let ws_addr = match self.ws_addr.clone() {
                 Some(addr) => addr,
-                None => panic!("websocket address wasn't initialized"),
+                None => return Err(Error::NotInitialized("websocket isn't connected".to_owned())),
             };
 
             let state = match &mut self.state {
                 Some(state) => state,
-                None => panic!("no state initialized"),
+                None => return Err(Error::NotInitialized("no app is initialized".to_owned())),
             };
             let congestion_flag = match self.congestion.clone() {
                 Some(v) => v,
-                None => panic!("congestion flag isn't set"),
+                None => return Err(Error::NotInitialized("websocket isn't connected".to_owned())),
             };
-            Manager::start_threads(state, ws_addr, congestion_flag, &self.config);
+            error::lock(&state.app).get_utility_curves().validate()?;
+            let failures = ctx.address().recipient();
+            Manager::start_threads(state, ws_addr, congestion_flag, failures, self.debug.clone(), &self.config);
+        }
+
+        Ok(run_scheduler)
+    }
+}
+
+/// health of the threads of the session, none before an app is initialized
+#[derive(Message)]
+#[rtype(result="Option<PipelineHealth>")]
+pub struct GetHealth;
+
+impl Handler<GetHealth> for Manager {
+    type Result = Option<PipelineHealth>;
+
+    fn handle(&mut self, _msg: GetHealth, _: &mut Self::Context) -> Self::Result {
+        let state = self.state.as_ref()?;
+        Some(error::read(&state.health).clone())
+    }
+}
+
+/// liveness and readiness of the manager, see /healthz and /readyz
+#[derive(MessageResponse, Clone, Debug, Serialize)]
+pub struct Status {
+    /// app of the session, none before /initapp
+    pub app: Option<apps::AppType>,
+    pub instance: usize,
+    pub websocket: bool,
+    /// why the app's backend can't serve blocks
+    pub backend_error: Option<String>,
+    pub pipeline: Option<PipelineHealth>,
+    /// the backend is available and no thread failed for good
+    pub ready: bool,
+}
+
+#[derive(Message)]
+#[rtype(Status)]
+pub struct GetStatus;
+
+impl Handler<GetStatus> for Manager {
+    type Result = Status;
+
+    fn handle(&mut self, _msg: GetStatus, ctx: &mut Self::Context) -> Self::Result {
+        let (app, backend_error) = match &self.state {
+            Some(state) => {
+                // don't wait for the workers, an app they are busy with is serving blocks
+                let backend_error = match state.app.try_lock() {
+                    Ok(app) => app.check_backend().err(),
+                    Err(TryLockError::Poisoned(app)) => app.into_inner().check_backend().err(),
+                    Err(TryLockError::WouldBlock) => None,
+                };
+                (Some(state.appstate.appname), backend_error)
+            },
+            None => (None, None),
+        };
+        let pipeline = <Self as Handler<GetHealth>>::handle(self, GetHealth, ctx);
+        let ready = backend_error.is_none() && pipeline.as_ref().map_or(true, |p| p.healthy());
+
+        Status{app: app, instance: self.instance, websocket: self.ws_addr.is_some(),
+               backend_error: backend_error, pipeline: pipeline, ready: ready}
+    }
+}
+
+/// liveness of the manager, answered without taking any lock; the app of the
+/// session, none before /initapp
+#[derive(Message)]
+#[rtype(result="Option<apps::AppType>")]
+pub struct Ping;
+
+impl Handler<Ping> for Manager {
+    type Result = Option<apps::AppType>;
+
+    fn handle(&mut self, _msg: Ping, _: &mut Self::Context) -> Self::Result {
+        self.state.as_ref().map(|state| state.appstate.appname)
+    }
+}
+
+/// id of the current session, 0 before /initapp
+#[derive(Message)]
+#[rtype(result="usize")]
+pub struct GetInstance;
+
+impl Handler<GetInstance> for Manager {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: GetInstance, _: &mut Self::Context) -> Self::Result {
+        self.instance
+    }
+}
+
+/// sessions of the manager, currently the one of the last /initapp
+#[derive(Message)]
+#[rtype(result="Result<Sessions, Error>")]
+pub struct ListSessions {
+    pub caller: Caller,
+}
+
+impl Handler<ListSessions> for Manager {
+    type Result = Result<Sessions, Error>;
+
+    fn handle(&mut self, msg: ListSessions, _: &mut Self::Context) -> Self::Result {
+        super::admin::authorize(&self.config, &msg.caller)?;
+        let sessions = match &self.state {
+            Some(state) => vec![SessionInfo::new(self.instance, state)],
+            None => Vec::new(),
+        };
+        Ok(Sessions{sessions: sessions})
+    }
+}
+
+/// change the scheduler, bandwidth cap or pause streaming of session `id`,
+/// an empty steer only inspects it
+#[derive(Message)]
+#[rtype(result="Result<SessionInfo, Error>")]
+pub struct SteerSession {
+    pub id: usize,
+    pub steer: Steer,
+    pub caller: Caller,
+}
+
+impl Handler<SteerSession> for Manager {
+    type Result = Result<SessionInfo, Error>;
+
+    fn handle(&mut self, msg: SteerSession, _: &mut Self::Context) -> Self::Result {
+        super::admin::authorize(&self.config, &msg.caller)?;
+        match &self.state {
+            Some(state) if msg.id == self.instance => {
+                msg.steer.apply(state);
+                Ok(SessionInfo::new(self.instance, state))
+            },
+            _ => Err(Error::NotFound(format!("session {}", msg.id))),
+        }
+    }
+}
+
+/// the caller may use the admin api, for handlers outside of the manager (/traces)
+#[derive(Message)]
+#[rtype(result="Result<(), Error>")]
+pub struct AuthorizeAdmin {
+    pub caller: Caller,
+}
+
+impl Handler<AuthorizeAdmin> for Manager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: AuthorizeAdmin, _: &mut Self::Context) -> Self::Result {
+        super::admin::authorize(&self.config, &msg.caller)
+    }
+}
+
+/// the caller may stream the debug events of session `id`, checked before the
+/// websocket starts
+#[derive(Message)]
+#[rtype(result="Result<(), Error>")]
+pub struct AuthorizeDebug {
+    pub id: usize,
+    pub caller: Caller,
+}
+
+impl Handler<AuthorizeDebug> for Manager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: AuthorizeDebug, _: &mut Self::Context) -> Self::Result {
+        self.debug_session(msg.id)?;
+        super::admin::authorize_caller(&self.config, &msg.caller)
+    }
+}
+
+/// stream the debug events of session `id` to `addr`, if "debug_channel" is enabled
+#[derive(Message)]
+#[rtype(result="Result<(), Error>")]
+pub struct SubscribeDebug {
+    pub id: usize,
+    pub addr: Recipient<DebugMessage>,
+}
+
+impl Handler<SubscribeDebug> for Manager {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SubscribeDebug, _: &mut Self::Context) -> Self::Result {
+        self.debug_session(msg.id)?;
+        info!("debug subscriber for session {}", msg.id);
+        self.debug.subscribe(msg.addr);
+        Ok(())
+    }
+}
+
+/// a worker thread panicked, sent by its supervisor
+#[derive(Message, Debug)]
+#[rtype(result="()")]
+pub struct WorkerFailed {
+    pub thread: String,
+    pub error: Error,
+    /// the supervisor runs the thread again, see supervisor::Supervisor
+    pub restarting: bool,
+}
+
+impl Handler<WorkerFailed> for Manager {
+    type Result = ();
+
+    fn handle(&mut self, msg: WorkerFailed, _: &mut Self::Context) -> Self::Result {
+        error!("{} thread failed: {} (restarting: {})", msg.thread, msg.error, msg.restarting);
+        if msg.restarting {
+            return;
         }
 
-        run_scheduler
+        // the client stops receiving prefetched blocks, let it know why
+        if let Some(addr) = &self.ws_addr {
+            let _ = addr.do_send(ds::StreamBlock::Error(format!("{} thread failed: {}", msg.thread, msg.error)));
+        }
     }
 }
 
@@ -449,12 +763,26 @@ impl Manager {
                 dist_counter: 0,
                 instance: 0,
                 congestion: None,
+                debug: DebugChannel::default(),
                 config: config,
                 }
     }
 
+    /// the debug channel is enabled and `id` is the current session
+    fn debug_session(&self, id: usize) -> Result<(), Error> {
+        if !self.config["debug_channel"].as_bool().unwrap_or(false) {
+            return Err(Error::NotFound("debug channel is disabled, see \"debug_channel\" in the config".to_owned()));
+        }
+
+        match &self.state {
+            Some(_) if id == self.instance => Ok(()),
+            _ => Err(Error::NotFound(format!("session {}", id))),
+        }
+    }
+
     pub fn start_threads(state: &mut SharedState, ws_addr: Recipient<ds::StreamBlock>,
-                         congestion_flag: Arc<AtomicCell<u128>>, config: &serde_json::Value) {
+                         congestion_flag: Arc<AtomicCell<u128>>, failures: Recipient<WorkerFailed>,
+                         debug: DebugChannel, config: &serde_json::Value) {
         info!("--> Start Scheduling/streaming Threads");
         let kill_thread_th1 = state.kill_thread_flag.clone();
         let kill_thread_th2 = state.kill_thread_flag.clone();
@@ -468,15 +796,22 @@ impl Manager {
         let app1 = Arc::clone(&state.app);
         let app2 = Arc::clone(&state.app);
 
-        let (queries_blcount, utility)  = app1.lock().unwrap().get_scheduler_config();
+        let (queries_blcount, _)  = error::lock(&app1).get_scheduler_config();
+        let utility = error::lock(&app1).get_utility_curves();
+        let block_sizes = error::lock(&app1).get_block_sizes();
         let total_queries = queries_blcount.len();
 
         let state_change_flag = state.state_change_flag.clone();
         
-        let cachesize = state.appstate.cachesize;
+        let cachebytes = state.appstate.cache_bytes(block_sizes.unit());
         let cache_sim_th1 = state.cache_sim.clone();
         let cache_sim_th2 = cache_sim_th1.clone();
 
+        let deadlines_th1 = state.deadlines.clone();
+        let deadlines_th2 = state.deadlines.clone();
+
+        let ws_addr_th1 = ws_addr.clone();
+
         let tm = state.tm.clone();
         let tm_th1 = tm.clone();
         let tm_th2 = tm.clone();
@@ -505,14 +840,45 @@ impl Manager {
             None => 0,
         };
 
-        match state.tm.write() {
-            Ok(mut tm) => {
-                tm.update_bandwidth(bw);
-                tm.update_latency(latency as usize);
+        let log_utility: bool = match config["log_utility"].as_bool() {
+            Some(flag) => flag,
+            None => false,
+        };
+
+        // weight of the app's prior blended with each predicted distribution
+        let prior_weight: f32 = match config["prior_weight"].as_f64() {
+            Some(w) => w as f32,
+            None => 0.1,
+        };
+
+        // how predicted distributions are interpolated between and after their deltas
+        let interpolation: scheduler::Interpolation = match config.get("interpolation") {
+            Some(i) => serde_json::from_value(i.clone()).unwrap_or_else(|e| {
+                error!("invalid interpolation {:?}: {}", i, e);
+                scheduler::Interpolation::default()
+            }),
+            None => scheduler::Interpolation::default(),
+        };
+
+        {
+            let mut tm = error::write(&state.tm);
+            tm.update_bandwidth(bw);
+            tm.update_latency(latency as usize);
+        }
+
+        // scheduler of the first rounds, can be switched under /admin
+        if let Some(schedtype) = config.get("scheduler") {
+            match serde_json::from_value::<scheduler::SchedulerType>(schedtype.clone()) {
+                Ok(schedtype) => error::write(&state.control).scheduler = schedtype,
+                Err(e) => error!("invalid scheduler {:?}: {}", schedtype, e),
             }
-            Err(e)=> error!("couldn't update bandwidth, {:?}", e),
         }
 
+        let control_th1 = state.control.clone();
+        let control_th2 = state.control.clone();
+        let plan_th1 = state.plan.clone();
+        let plan_th2 = state.plan.clone();
+
         info!("bw: {} rate: {} latency: {}",  bw, rate, latency);
 
         // 2) Start a Scheduler Threed, that checks queue
@@ -522,42 +888,71 @@ impl Manager {
         //
         // receive updated distributions and schedule new blocks
         // send new decision to thread2
-        let worker1 = thread::spawn(move || {
-            let blocks_per_query :Vec<usize> = queries_blcount.iter().map(|(_k, &v)| v ).collect();
+        let supervisor = super::supervisor::Supervisor::new(config, state.health.clone(),
+                                                            state.kill_thread_flag.clone(), Some(failures));
+        let blocks_per_query :Vec<usize> = queries_blcount.iter().map(|(_k, &v)| v ).collect();
+        let worker1 = supervisor.spawn("scheduling", move || {
             let continues = false;
-            let schedtype = scheduler::SchedulerType::TopK;
             let time_to_converge = 300;
             let batch = 100;
-            let sched = scheduler::new(&schedtype,
-                                       batch,
-                                       cachesize,
-                                       utility,
-                                       blocks_per_query, Some(tm.clone()));
+            let new_sched = |schedtype: &scheduler::SchedulerType| {
+                scheduler::new(schedtype,
+                               batch,
+                               cachebytes,
+                               utility.clone(),
+                               blocks_per_query.clone(), block_sizes.clone(), Some(tm.clone()))
+            };
         
             super::scheduling::start( // objects
-                                     app1, cache_sim_th1, sched, tm_th1,
+                                     app1.clone(), cache_sim_th1.clone(), new_sched, tm_th1.clone(),
                                       // config
                                       continues, time_to_converge, total_queries,
+                                      log_utility, prior_weight, interpolation, utility.clone(), blocks_per_query.clone(),
                                       // flags
-                                      kill_thread_th1, state_change_flag,
+                                      kill_thread_th1.clone(), state_change_flag.clone(),
+                                      control_th1.clone(), plan_th1.clone(), debug.clone(),
                                       // channels
-                                      dist_rx, schedule_tx, schedule_rx_th1,
+                                      dist_rx.clone(), schedule_tx.clone(), schedule_rx_th1.clone(), deadlines_th1.clone(),
+                                      ws_addr_th1.clone(),
                                   );
         });
         state.threads.push(Some(worker1));
         // receive scheduler's decisions and stream them to end user
-        let worker2 = thread::spawn(move || {
+        let worker2 = supervisor.spawn("sender", move || {
             super::sender::start( // object
-                                  app2, cache_sim_th2, ws_addr, tm_th2,
-                                  congestion_flag,
+                                  app2.clone(), cache_sim_th2.clone(), ws_addr.clone(), tm_th2.clone(),
+                                  congestion_flag.clone(),
                                   // flags
-                                  kill_thread_th2,
+                                  kill_thread_th2.clone(),
+                                  control_th2.clone(), plan_th2.clone(),
 
                                   min_wait,
                                   // channels
-                                  schedule_rx_th2,
+                                  schedule_rx_th2.clone(), deadlines_th2.clone(),
                                 );
         });
         state.threads.push(Some(worker2));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_and_ready_file() {
+        let path = std::env::temp_dir().join(format!("khameleon-ready-{}", std::process::id()));
+        let config = serde_json::json!({"ready_file": path.to_str().unwrap()});
+
+        let mut sys = System::new("test_status");
+        let addr = Manager::new(config).start();
+        let status = sys.block_on(addr.send(GetStatus)).unwrap();
+        assert!(status.ready);
+        assert_eq!((status.app, status.websocket, status.pipeline), (None, false, None));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ready");
+        assert_eq!(sys.block_on(addr.send(Ping)).unwrap(), None);
+        assert_eq!(sys.block_on(addr.send(GetInstance)).unwrap(), 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,25 +1,34 @@
 pub mod sender;
 pub mod scheduling;
 pub mod manager;
+pub mod supervisor;
+pub mod admin;
+pub mod debug;
 
 // export
-pub use manager::{Manager, SystemStat, Request, Connect, Distributions, InitApp};
+pub use manager::{Manager, SystemStat, Request, Connect, Connected, Distributions, DistUpdate, Events, InitApp, WorkerFailed, GetHealth, GetStatus, Status, Ping, GetInstance, ListSessions, SteerSession, AuthorizeAdmin, AuthorizeDebug, SubscribeDebug};
+pub use debug::{DebugChannel, DebugEvent, DebugMessage};
+pub use supervisor::{Supervisor, PipelineHealth, WorkerHealth, WorkerStatus};
 
 extern crate ndarray;
 use ndarray::{Array1};
 
+/// Model of the client cache: a ring buffer that holds 'cachesize' bytes
 #[derive(Clone, Debug)]
 pub struct CacheSimulator {
     pub cache_per_query: Array1<usize>,
-    pub cache: Vec<i32>,
+    /// query of each block in the cache, in the order they were added
+    pub cache: Vec<usize>,
+    /// capacity in bytes
     pub cachesize: usize,
+    /// bytes in the cache
     pub head: usize,
 }
 
 impl CacheSimulator {
     pub fn new(cachesize: usize, total_queries: usize) -> Self {
         // simulate ring buffer
-        let cache: Vec<i32> = vec![-1; cachesize];
+        let cache: Vec<usize> = Vec::new();
         // cache state for scheduler
         let cache_per_query: Array1<usize> = Array1::zeros(total_queries);
         let head = 0;
@@ -36,35 +45,41 @@ impl CacheSimulator {
         (self.head, self.cache_per_query.clone())
     }
 
-    fn get(&self, qid: usize) -> usize {
+    pub fn get(&self, qid: usize) -> usize {
         match self.cache_per_query.get(qid) {
             Some(count) => *count,
             None => 0,
         }
     }
 
-    fn reset(&mut self) {
+    /// the blocks of @qid belong to content the query no longer names, they keep
+    /// their space in the ring
+    pub fn forget(&mut self, qid: usize) {
+        if let Some(count) = self.cache_per_query.get_mut(qid) {
+            *count = 0;
+        }
+    }
+
+    pub fn reset(&mut self) {
         debug!("reset ------ {:?} {:?}", self.cache, self.head);
         self.head = 0;
-        self.cache = vec![-1; self.cachesize];
+        self.cache.clear();
         self.cache_per_query.fill(0);
     }
 
-    fn add(&mut self, qid: usize) {
-        // add new block
-        let cur_qid = self.cache[self.head];
-        if cur_qid >= 0 {
-            self.cache_per_query[cur_qid as usize] -= 1;
+    /// add a block of @size bytes for query @qid
+    pub fn add(&mut self, qid: usize, size: usize) {
+        // the ring wraps around when the block doesn't fit
+        if self.head > 0 && self.head + size > self.cachesize {
+            self.reset()
         }
 
-        self.cache[self.head] = qid as i32;
+        self.cache.push(qid);
         self.cache_per_query[qid] += 1;
-        
-        if self.head + 1 >= self.cachesize {
+        self.head += size;
+
+        if self.head >= self.cachesize {
             self.reset()
-        } else {
-            self.head += 1;
         }
     }
 }
-
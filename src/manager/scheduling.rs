@@ -1,4 +1,5 @@
 use crate::ds;
+use crate::error;
 use crate::apps;
 use crate::scheduler;
 
@@ -7,40 +8,65 @@ use std::sync::mpsc::{self, TryRecvError, TrySendError};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Instant};
 use crossbeam_utils::atomic::AtomicCell;
+// for the Actor primitive
+use actix::prelude::*;
 
-pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
+pub fn start<F>(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             cache_sim: Arc<RwLock<super::CacheSimulator>>,
-            mut sched: Box<dyn scheduler::SchedulerTrait>,
+            // scheduler of a type, rebuilt when the type is switched in `control`
+            new_sched: F,
             tm: Arc<RwLock<ds::TimeManager>>,
 
             // config
             continues: bool,
             time_to_converge: u128,
             total_queries: usize,
+            log_utility: bool,
+            prior_weight: f32,
+            interpolation: scheduler::Interpolation,
+            utility: scheduler::UtilityCurves,
+            blocks_per_query: Vec<usize>,
 
             // flags
             kill_thread: Arc<AtomicCell<bool>>,
             state_change_flag: Arc<RwLock<bool>>,
+            control: Arc<RwLock<super::admin::Control>>,
+            plan: Arc<RwLock<super::admin::PlanState>>,
+            debug: super::DebugChannel,
             
             // channels
             dist_rx: Arc<Mutex<mpsc::Receiver<ds::PredictorState>>>,
             schedule_tx: Arc<Mutex<mpsc::SyncSender<Vec<usize>>>>,
             schedule_rx_th1: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>,
+            deadlines: Arc<RwLock<HashMap<usize, Instant>>>,
+            // report states that can't be decoded to the client
+            ws_addr: Recipient<ds::StreamBlock>,
             )
+    where F: Fn(&scheduler::SchedulerType) -> Box<dyn scheduler::SchedulerTrait>
     {
 
     // stats
     let mut round: usize = 1;
     // variables memory holder
     let mut decoded_dist_copy : scheduler::Prob = scheduler::Prob::new(total_queries);
-    let block_size = app.lock().unwrap().get_block_size(); // bytes
-    let size_megabits = (block_size as f64* 8.0) / (1024.0 * 1024.0);
-
-    // To estimate how long it takes to transfer a block
-    match tm.write() {
-        Ok(mut tm_w) => tm_w.update_blocksize_megabits(size_megabits),
-        Err(e) => panic!("couldn't update time manager with blocksize {:?}", e),
+    decoded_dist_copy.set_interpolation(interpolation);
+    let block_sizes = error::lock(&app).get_block_sizes(); // bytes
+    // prefetch with the prior until the first predictor state arrives
+    let prior = error::lock(&app).get_prior();
+    let mut use_prior = prior.is_some();
+    if let Some(prior) = &prior {
+        decoded_dist_copy.blend_prior(prior, 1.0);
     }
+    let size_megabits = (block_sizes.unit() as f64* 8.0) / (1024.0 * 1024.0);
+
+    // To estimate how long it takes to transfer a block of average size
+    error::write(&tm).update_blocksize_megabits(size_megabits);
+
+    // to log the expected utility of each plan
+    let utility = utility.utility_matrix(&blocks_per_query);
+
+    let mut schedtype = error::read(&control).scheduler;
+    let mut sched = new_sched(&schedtype);
 
     let mut last_new_dist = Instant::now();
     let debug_cache = false;
@@ -55,14 +81,43 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         let start = Instant::now();
         // debug!("try_recv");
         let decoded_dist = {
-            match dist_rx.lock().unwrap().try_recv() {
+            match error::lock(&dist_rx).try_recv() {
                 Ok(dist) => {
                     // new distribution
                     debug!("calling decode_dist");
-                    let dist = app.lock().unwrap().decode_dist(dist);
+                    let model = dist.model.clone();
+                    let (dist, recycled) = {
+                        let mut app = error::lock(&app);
+                        (app.decode_dist(dist), app.take_recycled_queries())
+                    };
+                    if !recycled.is_empty() {
+                        let mut cache_sim = error::write(&cache_sim);
+                        for &qid in recycled.iter() {
+                            cache_sim.forget(qid);
+                        }
+                    }
+                    let dist = match dist {
+                        Ok(mut dist) => {
+                            dist.set_interpolation(interpolation);
+                            if let Some(prior) = &prior {
+                                dist.blend_prior(prior, prior_weight);
+                            }
+                            dist
+                        },
+                        Err(e) => {
+                            error!("couldn't decode predictor state: {}", e);
+                            if let Err(err) = ws_addr.do_send(ds::StreamBlock::Error(e.to_string())) {
+                                error!("couldn't report decoding error to the client {:?}", err);
+                            }
+                            continue;
+                        }
+                    };
+                    debug.emit(|| super::DebugEvent::Distribution{round: round, model: model});
                     decoded_dist_copy = dist.clone();
                     last_new_dist = Instant::now();
-                    tm.write().unwrap().update_time(dist.time.clone());
+                    error::write(&tm).update_time(dist.time.clone());
+                    // let the sender drop blocks that would arrive too late
+                    *error::write(&deadlines) = dist.get_deadlines();
                     
                     dist
                 }
@@ -72,7 +127,11 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
                 },
                 Err(TryRecvError::Empty) => {
                     // check if we sent the whole schedule
-                   if continues && (last_new_dist.elapsed().as_millis() > time_to_converge) {
+                   if use_prior {
+                       info!("use prior distribution");
+                       use_prior = false;
+                       decoded_dist_copy.clone()
+                   } else if continues && (last_new_dist.elapsed().as_millis() > time_to_converge) {
                        info!("use old distribution {:?}", last_new_dist.elapsed());
 
                        last_new_dist = Instant::now();
@@ -93,16 +152,16 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         
         // 2) get the current state from the sender:
         {
-            let state_changed = state_change_flag.read().unwrap();
+            let state_changed = error::read(&state_change_flag);
             if *state_changed {
-               cache_sim.write().unwrap().reset();
+               error::write(&cache_sim).reset();
             }
         }
         {
-            let mut flag = state_change_flag.write().unwrap();
+            let mut flag = error::write(&state_change_flag);
             *flag = false;
         }
-        let (cache_head, cache_state) = cache_sim.read().unwrap().get_state();
+        let (cache_head, cache_state) = error::read(&cache_sim).get_state();
 
         debug!("schedule for {:?}", cache_head);
         if debug_cache {
@@ -119,25 +178,60 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             debug!("schedule content nblocks:index {:?}", cache_content);
         }
         
+        // 3) switch the scheduler if it was changed under /admin
+        let control_schedtype = error::read(&control).scheduler;
+        if control_schedtype != schedtype {
+            info!("switch scheduler {:?} -> {:?}", schedtype, control_schedtype);
+            schedtype = control_schedtype;
+            sched = new_sched(&schedtype);
+        }
+
         // 4) start scheduling
+        let top_queries = decoded_dist.top_queries(super::admin::TOP_QUERIES,
+                                                   error::read(&tm).slot_to_client_delta(0));
+        let eval_state = match log_utility {
+            true => Some((decoded_dist.clone(), cache_state.clone())),
+            false => None,
+        };
         let start = Instant::now();
         let decision = sched.run_scheduler(decoded_dist, cache_state, cache_head);
         let duration = start.elapsed();
+
+        debug.emit(|| super::DebugEvent::TopK{round: round, queries: top_queries.clone()});
+        debug.emit(|| super::DebugEvent::Plan{round: round, scheduler: schedtype, plan: decision.clone(),
+                                              elapsed_ms: duration.as_secs_f64() * 1000.0});
         
         info!("decisions elapsed time {:?}", duration);
 
+        if let Some((probs, cache_state)) = eval_state {
+            let eval = scheduler::evaluate(&decision, &probs, &utility, &blocks_per_query, &block_sizes,
+                                           cache_state, &error::read(&tm));
+            info!("round ({}) expected utility: {:?} gain: {:?} mean: {:?} top: {:?}",
+                  round, eval.total, eval.gain(), eval.mean(), eval.top_queries(5));
+        }
+
         round += 1;
         if decision.len() == 0 {
             error!("Empty decision results");
             continue;
         }
 
+        {
+            let mut plan = error::write(&plan);
+            plan.round = round - 1;
+            plan.plan = decision.clone();
+            plan.position = 0;
+            plan.top_queries = top_queries;
+        }
+
         // write result to sender thread
-        let local_schedule_tx = schedule_tx.lock().unwrap();
+        let local_schedule_tx = error::lock(&schedule_tx);
         match local_schedule_tx.try_send(decision) {
             Err(TrySendError::Full(data)) => {
-                let _ = schedule_rx_th1.lock().unwrap().try_recv();
-                local_schedule_tx.try_send(data).unwrap();
+                let _ = error::lock(&schedule_rx_th1).try_recv();
+                if let Err(e) = local_schedule_tx.try_send(data) {
+                    error!("couldn't pass the schedule to the sender: {:?}", e);
+                }
             },
             _ => {},
         }
@@ -1,11 +1,13 @@
 use crate::ds;
+use crate::error;
 use crate::apps;
 
 use actix::prelude::*;
 extern crate ndarray;
 use std::sync::mpsc::{self};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Instant};
+use std::time::{Duration, Instant};
 use crossbeam_utils::atomic::AtomicCell;
 
 /*
@@ -19,6 +21,7 @@ use crossbeam_utils::atomic::AtomicCell;
  *   should check for new schedule?
  *     update/check schedule
  *   
+ *   drop block if it would arrive after the query's deadline
  *   select which block for request based on cache simulator
  *   cachesimulator.update
  *   ws.send(block)
@@ -29,14 +32,20 @@ use crossbeam_utils::atomic::AtomicCell;
  *
  **/
 
+/// how often a paused sender checks whether it was resumed
+const PAUSE_POLL_MS: u64 = 10;
+
 pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
              cache_sim: Arc<RwLock<super::CacheSimulator>>,
              ws_addr: Recipient<ds::StreamBlock>,
              tm: Arc<RwLock<ds::TimeManager>>,
              _congestion: Arc<AtomicCell<u128>>,
              kill_thread: Arc<AtomicCell<bool>>,
+             control: Arc<RwLock<super::admin::Control>>,
+             plan: Arc<RwLock<super::admin::PlanState>>,
              min_wait: usize,
-             schedule_rx: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>) {
+             schedule_rx: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>,
+             deadlines: Arc<RwLock<HashMap<usize, Instant>>>) {
     // stats
     let mut round: usize = 1;
     let mut total_blocks: usize = 1;
@@ -46,11 +55,10 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
     let mut schedule_pt: Vec<usize> = Vec::new();
     let mut schedule_iter = schedule_pt.iter();
     
-    // for bw control
-    let block_size = app.lock().unwrap().get_block_size(); // bytes
-    let size_megabits = (block_size as f64* 8.0) / (1024.0 * 1024.0);
-    let bandwidth = tm.read().unwrap().get_ref_bw();
-    info!("block_size: {:?} size_megabits: {:?}", block_size, size_megabits);
+    // for bw control, blocks may differ in size
+    let block_sizes = error::lock(&app).get_block_sizes(); // bytes
+    let bandwidth = error::read(&tm).get_ref_bw();
+    info!("block_size: {:?}", block_sizes.unit());
 
     let mut start = Instant::now();
     loop {
@@ -60,15 +68,23 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             break;
         }
 
+        // paused under /admin, the plan resumes where it stopped
+        if error::read(&control).paused {
+            std::thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+            start = Instant::now();
+            continue;
+        }
+
         
 
-        schedule_iter = match schedule_rx.lock().unwrap().try_recv() {
+        schedule_iter = match error::lock(&schedule_rx).try_recv() {
             Ok(schedule) => {
                 debug!("scheduler: {:?}", schedule);
                 schedule_pt = schedule;
 
                 // submit this to app
-                app.lock().unwrap().prepare_schedule(&schedule_pt);
+                error::lock(&app).prepare_schedule(&schedule_pt);
+                error::write(&plan).position = 0;
 
                 schedule_pt.iter()
             },
@@ -76,32 +92,50 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         };
 
 
+        // bytes put on the network this round
+        let mut sent_bytes: usize = 0;
         match schedule_iter.next() {
             Some(&qid) => {
+                error::write(&plan).position += 1;
+
                 // get how many blocks in cache, and update cache
-                let incache = cache_sim.read().unwrap().get(qid);
+                let incache = error::read(&cache_sim).get(qid);
+
+                // stale blocks, e.g. frames of a tick that passed, are not worth the bandwidth
+                if let Some(&deadline) = error::read(&deadlines).get(&qid) {
+                    let delay = error::read(&tm).arrival_delay_ms(block_sizes.get(qid, incache));
+                    if Instant::now() + Duration::from_millis(delay as u64) > deadline {
+                        debug!("drop stale block for {:?}", qid);
+                        continue
+                    }
+                }
+
                 // let cache_start = Instant::now();
-                // cache_sim.write().unwrap().add(qid);
+                // error::write(&cache_sim).add(qid);
                 // let cache_update_time = cache_start.elapsed().as_millis() as u64;
                 let retrieval_start = Instant::now();
                 let count = 1;
-                match app.lock().unwrap().get_nblocks_byindex(qid, count, incache) {
+                match error::lock(&app).get_nblocks_byindex(qid, count, incache) {
                     Some(blocks) => {
                         if blocks.len() == 0 {
-                            // todo: give scheduler max blocks per query
-                            error!("get_nblocks no blocks: {:?} {:?} {:?} <- happens when we have var # of blocks", qid, count, incache);
+                            error!("get_nblocks no blocks left: {:?} {:?} {:?}", qid, count, incache);
                             continue
                         }
 
                         for b in blocks {
 
                             let retrieval_time = retrieval_start.elapsed().as_millis();
+                            let size = match &b {
+                                ds::StreamBlock::Block(block) => block.len(),
+                                _ => 0,
+                            };
                             let sending_start = Instant::now();
                             let req= ws_addr.send(b);
                             let w = req.wait();
                             match w {
                                 Ok(_) => {
                                     total_blocks += 1;
+                                    sent_bytes += size;
                                     // debug!("sending took: {:?} retrieval: {:?} cache_update: {:?}", sending_start.elapsed(), retrieval_time, cache_update_time);
                                     debug!("sending took: {:?} retrieval: {:?}", sending_start.elapsed(), retrieval_time);
                                     if sending_start.elapsed().as_millis() > 1 {
@@ -133,12 +167,13 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         let elapsed = start.elapsed();
         let elapsed_ns = elapsed.as_nanos();
 
+        let size_megabits = (sent_bytes as f64 * 8.0) / (1024.0 * 1024.0);
         let sending_time_ms: f64 =   ((size_megabits / bw as f64) * 1000.0).ceil() ;
         let sending_time_ns: u128 =   (sending_time_ms * 1000000.0).ceil() as u128;
 
         
-        info!("({}) -> elapsed for {:?} total_blocks {:?}, bw: {:?} sending_time: {:?}",
-             round, elapsed, total_blocks, bw, sending_time_ms);
+        info!("({}) -> elapsed for {:?} total_blocks {:?}, bytes: {:?} bw: {:?} sending_time: {:?}",
+             round, elapsed, total_blocks, sent_bytes, bw, sending_time_ms);
         
         let wait = sending_time_ns  as i64 - elapsed_ns as i64;
         info!("wait {:?}", wait as f64 / 1000000.0);
@@ -0,0 +1,192 @@
+/*
+ * Supervision of the scheduling and sender threads of a session.
+ *
+ * Each worker runs in a thread that catches its panics, logs the payload and
+ * runs the worker again on the same shared state until it exhausted its restart
+ * budget. The status of the workers is kept in PipelineHealth.
+ */
+use super::manager::WorkerFailed;
+use crate::error::{self, Error};
+
+use serde_derive::{Deserialize, Serialize};
+use crossbeam_utils::atomic::AtomicCell;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use actix::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WorkerStatus {
+    Running,
+    /// panicked, waiting to run again
+    Restarting,
+    /// exhausted its restart budget
+    Failed,
+    /// returned after the session ended
+    Stopped,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkerHealth {
+    pub name: String,
+    pub status: WorkerStatus,
+    pub restarts: usize,
+    /// payload of the last panic
+    pub last_error: Option<String>,
+}
+
+/// health of the workers of a session
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PipelineHealth {
+    pub workers: Vec<WorkerHealth>,
+}
+
+impl PipelineHealth {
+    /// no worker failed for good
+    pub fn healthy(&self) -> bool {
+        self.workers.iter().all(|w| w.status != WorkerStatus::Failed)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&WorkerHealth> {
+        self.workers.iter().find(|w| w.name == name)
+    }
+
+    fn update<F: FnOnce(&mut WorkerHealth)>(&mut self, name: &str, f: F) {
+        let idx = match self.workers.iter().position(|w| w.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.workers.push(WorkerHealth{name: name.to_owned(), status: WorkerStatus::Running,
+                                               restarts: 0, last_error: None});
+                self.workers.len() - 1
+            }
+        };
+        f(&mut self.workers[idx]);
+    }
+}
+
+pub struct Supervisor {
+    /// restarts of a worker before it is marked failed
+    pub max_restarts: usize,
+    /// wait before the n-th restart is n times this
+    pub backoff: Duration,
+    pub health: Arc<RwLock<PipelineHealth>>,
+    pub kill_thread: Arc<AtomicCell<bool>>,
+    /// the manager, told about every failure
+    pub failures: Option<Recipient<WorkerFailed>>,
+}
+
+impl Supervisor {
+    /// config: {max_restarts?: 3, restart_backoff?: 100 (ms)}
+    pub fn new(config: &serde_json::Value, health: Arc<RwLock<PipelineHealth>>,
+               kill_thread: Arc<AtomicCell<bool>>, failures: Option<Recipient<WorkerFailed>>) -> Self {
+        let max_restarts: usize = match config["max_restarts"].as_u64() {
+            Some(n) => n as usize,
+            None => 3,
+        };
+
+        let backoff: u64 = match config["restart_backoff"].as_u64() {
+            Some(ms) => ms,
+            None => 100,
+        };
+
+        Supervisor{max_restarts: max_restarts, backoff: Duration::from_millis(backoff),
+                   health: health, kill_thread: kill_thread, failures: failures}
+    }
+
+    /// run `body` in a new thread and run it again each time it panics
+    pub fn spawn<F>(&self, name: &str, body: F) -> thread::JoinHandle<()>
+    where F: FnMut() + Send + 'static {
+        let name = name.to_owned();
+        let max_restarts = self.max_restarts;
+        let backoff = self.backoff;
+        let health = self.health.clone();
+        let kill_thread = self.kill_thread.clone();
+        let failures = self.failures.clone();
+
+        Supervisor::set(&health, &name, |w| w.status = WorkerStatus::Running);
+        thread::spawn(move || {
+            let mut body = body;
+            loop {
+                let err = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| body())) {
+                    Ok(_) => {
+                        debug!("{} thread stopped", name);
+                        Supervisor::set(&health, &name, |w| w.status = WorkerStatus::Stopped);
+                        break;
+                    },
+                    Err(err) => error::panic_message(&err),
+                };
+
+                error!("{} thread panicked: {}", name, err);
+                let mut restarts = 0;
+                Supervisor::set(&health, &name, |w| {
+                    w.last_error = Some(err.clone());
+                    restarts = w.restarts;
+                });
+
+                // the session ended while the worker was failing
+                if kill_thread.load() {
+                    Supervisor::set(&health, &name, |w| w.status = WorkerStatus::Stopped);
+                    break;
+                }
+
+                let restarting = restarts < max_restarts;
+                if let Some(failures) = &failures {
+                    let msg = WorkerFailed{thread: name.clone(), error: Error::Internal(err), restarting: restarting};
+                    if let Err(e) = failures.do_send(msg) {
+                        error!("couldn't report the failure of the {} thread: {:?}", name, e);
+                    }
+                }
+
+                if !restarting {
+                    error!("{} thread failed {} times, giving up", name, restarts + 1);
+                    Supervisor::set(&health, &name, |w| w.status = WorkerStatus::Failed);
+                    break;
+                }
+
+                Supervisor::set(&health, &name, |w| {
+                    w.status = WorkerStatus::Restarting;
+                    w.restarts += 1;
+                });
+                thread::sleep(backoff * (restarts as u32 + 1));
+                info!("restarting {} thread ({}/{})", name, restarts + 1, max_restarts);
+                Supervisor::set(&health, &name, |w| w.status = WorkerStatus::Running);
+            }
+        })
+    }
+
+    fn set<F: FnOnce(&mut WorkerHealth)>(health: &RwLock<PipelineHealth>, name: &str, f: F) {
+        error::write(health).update(name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supervisor_restarts() {
+        let config = serde_json::json!({"max_restarts": 2, "restart_backoff": 1});
+        let health = Arc::new(RwLock::new(PipelineHealth::default()));
+        let kill_thread = Arc::new(AtomicCell::new(false));
+        let supervisor = Supervisor::new(&config, health.clone(), kill_thread.clone(), None);
+
+        // panics once then returns
+        let mut runs = 0;
+        supervisor.spawn("flaky", move || {
+            runs += 1;
+            if runs == 1 {
+                panic!("run {}", runs);
+            }
+        }).join().unwrap();
+
+        // always panics
+        supervisor.spawn("broken", || panic!("broken")).join().unwrap();
+
+        let health = health.read().unwrap().clone();
+        assert_eq!(health.get("flaky"), Some(&WorkerHealth{name: "flaky".to_owned(), status: WorkerStatus::Stopped,
+                                                            restarts: 1, last_error: Some("run 1".to_owned())}));
+        let broken = health.get("broken").unwrap();
+        assert_eq!((broken.status, broken.restarts), (WorkerStatus::Failed, 2));
+        assert!(!health.healthy());
+    }
+}
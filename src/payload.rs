@@ -0,0 +1,449 @@
+/*
+ * Compact binary encoding of PredictorState.data.
+ *
+ * Instead of json, clients that negotiated the binary encoding at /initapp send the
+ * data of a predictor state as named fields of typed arrays, little endian:
+ *
+ *   u16 number of fields, then per field
+ *   u8 name length, name (utf-8), u8 tag, value:
+ *
+ *   tag | value                                            | json equivalent
+ *   0   | i64                                              | integer
+ *   1   | f64                                              | number
+ *   2   | u32 n, n u32                                     | [integer]
+ *   3   | u32 n, n f32                                     | [number]
+ *   4   | u32 rows, u32 cols, rows * cols f32, row major   | [[number]]
+ *   5   | u32 n, n u32 index, n f32 prob                   | {"index": prob}
+ *   6   | u32 n, n u32 index, n u16 prob quantized to 1/65535 | {"index": prob}
+ *   7   | u32 n, n bytes of json                           | any
+ *
+ * Decoders read PredictorState.data through Payload, which holds either the json or
+ * the fields, so every decoder accepts either form without turning the fields back
+ * into json, e.g. the markov model {action, tick, history, dist} is sent as two Int
+ * fields, an Indices field and a Dense transition table.
+ */
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Int(i64),
+    Float(f64),
+    Indices(Vec<u32>),
+    Vector(Vec<f32>),
+    Dense { rows: u32, cols: u32, values: Vec<f32> },
+    Sparse { indices: Vec<u32>, probs: Vec<f32> },
+    /// probabilities in [0, 1] quantized to 1/65535
+    Quantized { indices: Vec<u32>, probs: Vec<u16> },
+    Json(Value),
+}
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_INDICES: u8 = 2;
+const TAG_VECTOR: u8 = 3;
+const TAG_DENSE: u8 = 4;
+const TAG_SPARSE: u8 = 5;
+const TAG_QUANTIZED: u8 = 6;
+const TAG_JSON: u8 = 7;
+
+/// step of a quantized probability
+const QUANTUM: f32 = 1.0 / 65535.0;
+
+impl Field {
+    /// quantize sparse probabilities, clamped to [0, 1]
+    pub fn quantize(indices: Vec<u32>, probs: &[f32]) -> Field {
+        let probs = probs.iter().map(|p| (p.max(0.0).min(1.0) / QUANTUM).round() as u16).collect();
+        Field::Quantized{indices: indices, probs: probs}
+    }
+
+    pub fn to_json(&self) -> Value {
+        let sparse = |indices: &[u32], probs: Vec<f32>| -> Value {
+            let map: Map<String, Value> = indices.iter().zip(probs.into_iter())
+                .map(|(i, p)| (i.to_string(), Value::from(p as f64)))
+                .collect();
+            Value::Object(map)
+        };
+
+        match self {
+            Field::Int(v) => Value::from(*v),
+            Field::Float(v) => Value::from(*v),
+            Field::Indices(v) => Value::from(v.clone()),
+            Field::Vector(v) => Value::from(v.iter().map(|&x| x as f64).collect::<Vec<f64>>()),
+            Field::Dense{cols, values, ..} => {
+                let rows: Vec<Value> = values.chunks(std::cmp::max(*cols as usize, 1))
+                    .map(|row| Value::from(row.iter().map(|&x| x as f64).collect::<Vec<f64>>()))
+                    .collect();
+                Value::Array(rows)
+            },
+            Field::Sparse{indices, probs} => sparse(indices, probs.clone()),
+            Field::Quantized{indices, probs} => sparse(indices, probs.iter().map(|&p| p as f32 * QUANTUM).collect()),
+            Field::Json(v) => v.clone(),
+        }
+    }
+}
+
+/// PredictorState.data as sent by the client: json, or the fields of a binary payload
+#[derive(Clone, Debug)]
+pub enum Payload {
+    Json(Value),
+    Fields(Vec<(String, Field)>),
+}
+
+impl Payload {
+    /// typed field `name` of a binary payload
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        match self {
+            Payload::Json(_) => None,
+            Payload::Fields(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, field)| field),
+        }
+    }
+
+    /// `name` as json, only converted for typed fields
+    pub fn get(&self, name: &str) -> Option<Cow<Value>> {
+        match self {
+            Payload::Json(data) => data.get(name).map(Cow::Borrowed),
+            Payload::Fields(_) => match self.field(name)? {
+                Field::Json(v) => Some(Cow::Borrowed(v)),
+                field => Some(Cow::Owned(field.to_json())),
+            },
+        }
+    }
+
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        match self {
+            Payload::Json(data) => data[name].as_u64(),
+            Payload::Fields(_) => match self.field(name)? {
+                Field::Int(v) if *v >= 0 => Some(*v as u64),
+                Field::Json(v) => v.as_u64(),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn get_f64(&self, name: &str) -> Option<f64> {
+        match self {
+            Payload::Json(data) => data[name].as_f64(),
+            Payload::Fields(_) => match self.field(name)? {
+                Field::Int(v) => Some(*v as f64),
+                Field::Float(v) => Some(*v),
+                Field::Json(v) => v.as_f64(),
+                _ => None,
+            },
+        }
+    }
+
+    /// list of indices, e.g. the last actions
+    pub fn get_indices(&self, name: &str) -> Option<Result<Vec<usize>, String>> {
+        match self.field(name) {
+            Some(Field::Indices(v)) => Some(Ok(v.iter().map(|&i| i as usize).collect())),
+            _ => self.get(name).map(|v| serde::Deserialize::deserialize(&*v)
+                                        .map_err(|e| format!("invalid {} {:?}: {}", name, v, e))),
+        }
+    }
+
+    /// matrix as rows, e.g. a transition table
+    pub fn get_matrix(&self, name: &str) -> Option<Result<Vec<Vec<f64>>, String>> {
+        match self.field(name) {
+            Some(Field::Dense{rows, cols, values}) => Some(match values.len() == (*rows as usize) * (*cols as usize) {
+                true => Ok(values.chunks(std::cmp::max(*cols as usize, 1))
+                                 .map(|row| row.iter().map(|&x| x as f64).collect()).collect()),
+                false => Err(format!("{}x{} matrix {} with {} values", rows, cols, name, values.len())),
+            }),
+            _ => self.get(name).map(|v| serde::Deserialize::deserialize(&*v)
+                                        .map_err(|e| format!("invalid {} {:?}: {}", name, v, e))),
+        }
+    }
+
+    /// (index, probability) pairs, from a Sparse or Quantized field or {"index": prob}
+    pub fn get_sparse(&self, name: &str) -> Option<Result<Vec<(usize, f32)>, String>> {
+        match self.field(name) {
+            Some(Field::Sparse{indices, probs}) =>
+                Some(Ok(indices.iter().zip(probs.iter()).map(|(&i, &p)| (i as usize, p)).collect())),
+            Some(Field::Quantized{indices, probs}) =>
+                Some(Ok(indices.iter().zip(probs.iter()).map(|(&i, &p)| (i as usize, p as f32 * QUANTUM)).collect())),
+            _ => self.get(name).map(|v| match v.as_object() {
+                Some(obj) => obj.iter().map(|(i, p)| match (i.parse::<usize>(), p.as_f64()) {
+                    (Ok(i), Some(p)) => Ok((i, p as f32)),
+                    _ => Err(format!("invalid probability {:?} of {:?} in {}", p, i, name)),
+                }).collect(),
+                None => Err(format!("expected {{index: prob}} for {}, got {:?}", name, v)),
+            }),
+        }
+    }
+
+    /// replace or add the matrix `name`, in the form of the payload
+    pub fn set_matrix(&mut self, name: &str, rows: &[Vec<f64>]) {
+        match self {
+            Payload::Json(data) => {
+                if let Some(obj) = data.as_object_mut() {
+                    obj.insert(name.to_owned(), serde_json::json!(rows));
+                }
+            },
+            Payload::Fields(fields) => {
+                let cols = rows.first().map(|row| row.len()).unwrap_or(0);
+                let field = Field::Dense{rows: rows.len() as u32, cols: cols as u32,
+                                         values: rows.iter().flat_map(|row| row.iter().map(|&x| x as f32)).collect()};
+                match fields.iter_mut().find(|(n, _)| n == name) {
+                    Some((_, f)) => *f = field,
+                    None => fields.push((name.to_owned(), field)),
+                }
+            },
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Payload::Json(data) => data.clone(),
+            Payload::Fields(fields) =>
+                Value::Object(fields.iter().map(|(name, field)| (name.clone(), field.to_json())).collect()),
+        }
+    }
+}
+
+/// the same data, whatever its form
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        match (self, other) {
+            (Payload::Json(a), Payload::Json(b)) => a == b,
+            (Payload::Fields(a), Payload::Fields(b)) => a == b,
+            _ => self.to_json() == other.to_json(),
+        }
+    }
+}
+
+impl From<Value> for Payload {
+    fn from(data: Value) -> Self {
+        Payload::Json(data)
+    }
+}
+
+impl serde::Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Payload::Json(data) => data.serialize(serializer),
+            payload => payload.to_json().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Payload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(Payload::Json)
+    }
+}
+
+/// reads little endian values from a byte slice
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < self.offset + n {
+            return Err(format!("truncated payload, {} bytes at offset {} of {}", n, self.offset, self.bytes.len()));
+        }
+        self.offset += n;
+        Ok(&self.bytes[self.offset - n..self.offset])
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// u32 length then `n` items
+    fn array<T>(&mut self, n: usize, item: impl Fn(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        // every item takes at least one byte
+        if n > self.bytes.len() - self.offset {
+            return Err(format!("{} items don't fit in the payload", n));
+        }
+        (0..n).map(|_| item(self)).collect()
+    }
+}
+
+/// fields of a binary payload, in order
+pub fn decode_fields(bytes: &[u8]) -> Result<Vec<(String, Field)>, String> {
+    let mut r = Reader{bytes: bytes, offset: 0};
+    let nfields = r.u16()?;
+    let mut fields = Vec::with_capacity(nfields as usize);
+    for _ in 0..nfields {
+        let len = r.u8()? as usize;
+        let name = std::str::from_utf8(r.take(len)?).map_err(|e| format!("invalid field name: {}", e))?.to_owned();
+        let field = match r.u8()? {
+            TAG_INT => Field::Int(r.u64()? as i64),
+            TAG_FLOAT => Field::Float(f64::from_bits(r.u64()?)),
+            TAG_INDICES => {
+                let n = r.u32()? as usize;
+                Field::Indices(r.array(n, Reader::u32)?)
+            },
+            TAG_VECTOR => {
+                let n = r.u32()? as usize;
+                Field::Vector(r.array(n, Reader::f32)?)
+            },
+            TAG_DENSE => {
+                let (rows, cols) = (r.u32()?, r.u32()?);
+                let n = (rows as usize).checked_mul(cols as usize).ok_or("dense matrix is too large")?;
+                Field::Dense{rows: rows, cols: cols, values: r.array(n, Reader::f32)?}
+            },
+            TAG_SPARSE => {
+                let n = r.u32()? as usize;
+                let indices = r.array(n, Reader::u32)?;
+                Field::Sparse{indices: indices, probs: r.array(n, Reader::f32)?}
+            },
+            TAG_QUANTIZED => {
+                let n = r.u32()? as usize;
+                let indices = r.array(n, Reader::u32)?;
+                Field::Quantized{indices: indices, probs: r.array(n, Reader::u16)?}
+            },
+            TAG_JSON => {
+                let n = r.u32()? as usize;
+                Field::Json(serde_json::from_slice(r.take(n)?).map_err(|e| format!("invalid json field {:?}: {}", name, e))?)
+            },
+            tag => return Err(format!("unknown tag {} of field {:?}", tag, name)),
+        };
+        fields.push((name, field));
+    }
+
+    Ok(fields)
+}
+
+/// PredictorState.data of a binary payload
+pub fn decode_payload(bytes: &[u8]) -> Result<Payload, String> {
+    Ok(Payload::Fields(decode_fields(bytes)?))
+}
+
+pub fn encode_payload(fields: &[(&str, Field)]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+    for (name, field) in fields {
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+        match field {
+            Field::Int(v) => {
+                bytes.push(TAG_INT);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Field::Float(v) => {
+                bytes.push(TAG_FLOAT);
+                bytes.extend_from_slice(&v.to_bits().to_le_bytes());
+            },
+            Field::Indices(v) => {
+                bytes.push(TAG_INDICES);
+                bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                v.iter().for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+            },
+            Field::Vector(v) => {
+                bytes.push(TAG_VECTOR);
+                bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                v.iter().for_each(|x| bytes.extend_from_slice(&x.to_bits().to_le_bytes()));
+            },
+            Field::Dense{rows, cols, values} => {
+                bytes.push(TAG_DENSE);
+                bytes.extend_from_slice(&rows.to_le_bytes());
+                bytes.extend_from_slice(&cols.to_le_bytes());
+                values.iter().for_each(|x| bytes.extend_from_slice(&x.to_bits().to_le_bytes()));
+            },
+            Field::Sparse{indices, probs} => {
+                bytes.push(TAG_SPARSE);
+                bytes.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+                indices.iter().for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+                probs.iter().for_each(|x| bytes.extend_from_slice(&x.to_bits().to_le_bytes()));
+            },
+            Field::Quantized{indices, probs} => {
+                bytes.push(TAG_QUANTIZED);
+                bytes.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+                indices.iter().for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+                probs.iter().for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+            },
+            Field::Json(v) => {
+                let json = v.to_string().into_bytes();
+                bytes.push(TAG_JSON);
+                bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&json);
+            },
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let fields = vec![("tick", Field::Int(7)),
+                          ("w", Field::Float(0.5)),
+                          ("history", Field::Indices(vec![1, 0])),
+                          ("dist", Field::Dense{rows: 2, cols: 2, values: vec![0.25, 0.75, 1.0, 0.0]}),
+                          ("p", Field::Sparse{indices: vec![3], probs: vec![0.5]}),
+                          ("q", Field::quantize(vec![4, 9], &[1.0, 0.3])),
+                          ("layout", Field::Json(serde_json::json!({"a": {"x": 1}})))];
+        let bytes = encode_payload(&fields);
+        let decoded = decode_fields(&bytes).unwrap();
+        assert_eq!(decoded[..5].iter().map(|(n, f)| (n.as_str(), f.clone())).collect::<Vec<_>>(), fields[..5].to_vec());
+
+        let data = decode_payload(&bytes).unwrap();
+        assert_eq!(data.get_u64("tick"), Some(7));
+        assert_eq!(data.get_indices("history"), Some(Ok(vec![1, 0])));
+        assert_eq!(data.get_matrix("dist"), Some(Ok(vec![vec![0.25, 0.75], vec![1.0, 0.0]])));
+        assert_eq!(data.get_sparse("p"), Some(Ok(vec![(3, 0.5)])));
+        assert!((data.get_sparse("q").unwrap().unwrap()[1].1 - 0.3).abs() < 1e-4);
+        assert_eq!(data.get("layout").unwrap()["a"]["x"], 1);
+        assert!(data.get("missing").is_none());
+
+        // the json form reads the same
+        let json = Payload::Json(data.to_json());
+        assert_eq!(json, data);
+        assert_eq!(json.get_matrix("dist"), data.get_matrix("dist"));
+        assert_eq!(json.get_sparse("p"), data.get_sparse("p"));
+        assert_eq!(json.get_indices("history"), data.get_indices("history"));
+
+        assert!(decode_payload(&bytes[..bytes.len() - 1]).is_err());
+        // a huge length doesn't allocate
+        assert!(decode_payload(&[1, 0, 1, b'h', TAG_VECTOR, 255, 255, 255, 255]).is_err());
+    }
+
+    #[test]
+    fn test_markov_decoder_reads_binary() {
+        let table = vec![0.1, 0.9, 0.8, 0.2];
+        let bytes = encode_payload(&[("action", Field::Int(1)), ("tick", Field::Int(3)),
+                                     ("history", Field::Indices(vec![1])),
+                                     ("dist", Field::Dense{rows: 2, cols: 2, values: table})]);
+        let json = serde_json::json!({"action": 1, "tick": 3, "history": [1], "dist": [[0.1, 0.9], [0.8, 0.2]]});
+
+        let decode = |data: &Payload| {
+            let index = Arc::new(RwLock::new(scheduler::SequenceIndex::new(8)));
+            let mut decoder = scheduler::MarkovDecoder::new(2, 2, 4, index);
+            scheduler::PredictorDecoder::decode(&mut decoder, data).unwrap()
+        };
+        let (binary, json) = (decode(&decode_payload(&bytes).unwrap()), decode(&Payload::Json(json)));
+        for qid in 0..8 {
+            assert!((binary.get(qid, 0) - json.get(qid, 0)).abs() < 1e-6);
+        }
+    }
+}
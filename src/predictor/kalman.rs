@@ -0,0 +1,182 @@
+/*
+ * Kalman filter over the cursor position.
+ *
+ * Constant velocity model with state [x, y, vx, vy] in layout coordinates and ms,
+ * updated with each observed position. Predictions at each horizon are bivariate
+ * gaussians sent as the gaussian model, see scheduler::Gaussian2D.
+ */
+use super::{Event, Predictor};
+use crate::ds;
+use crate::scheduler;
+
+use ndarray::{Array1, Array2};
+
+/// predicted client deltas in ms, same as the client side kalman filter
+const DEFAULT_HORIZONS: [usize; 3] = [100, 200, 500];
+/// variance of a measured position (px^2)
+const MEASURE_VAR: f64 = 4.0;
+/// variance added per ms to the position (px^2) and velocity ((px/ms)^2)
+const POSITION_NOISE: f64 = 0.1;
+const VELOCITY_NOISE: f64 = 0.001;
+/// predictions are never more certain than this standard deviation (px)
+const MIN_SIGMA: f64 = 1.0;
+
+pub struct KalmanPredictor {
+    horizons: Vec<usize>,
+    /// [x, y, vx, vy] and its covariance, None until the first position
+    state: Option<(Array1<f64>, Array2<f64>)>,
+    last_time: u64,
+    layout: Option<serde_json::Value>,
+    /// observed events since the last prediction
+    changed: bool,
+}
+
+/// transition over `dt` ms and the noise it adds
+fn transition(dt: f64) -> (Array2<f64>, Array2<f64>) {
+    let f = array![[1.0, 0.0, dt, 0.0],
+                   [0.0, 1.0, 0.0, dt],
+                   [0.0, 0.0, 1.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]];
+    let mut q: Array2<f64> = Array2::zeros((4, 4));
+    q[[0, 0]] = POSITION_NOISE * dt;
+    q[[1, 1]] = POSITION_NOISE * dt;
+    q[[2, 2]] = VELOCITY_NOISE * dt;
+    q[[3, 3]] = VELOCITY_NOISE * dt;
+
+    (f, q)
+}
+
+/// state and covariance `dt` ms after (x, p)
+fn propagate(x: &Array1<f64>, p: &Array2<f64>, dt: f64) -> (Array1<f64>, Array2<f64>) {
+    let (f, q) = transition(dt);
+    (f.dot(x), f.dot(p).dot(&f.t()) + q)
+}
+
+impl KalmanPredictor {
+    /// config: {horizons?: [ms]}
+    pub fn new(config: &serde_json::Value) -> Self {
+        let horizons: Vec<usize> = match config.get("horizons") {
+            Some(h) => serde_json::from_value(h.clone()).unwrap_or_else(|e| {
+                error!("invalid horizons {:?}: {}", h, e);
+                DEFAULT_HORIZONS.to_vec()
+            }),
+            None => DEFAULT_HORIZONS.to_vec(),
+        };
+
+        KalmanPredictor{horizons: horizons, state: None, last_time: 0, layout: None, changed: false}
+    }
+
+    fn update(&mut self, mx: f64, my: f64, time: u64) {
+        let (x, p) = match self.state.take() {
+            None => {
+                let x = array![mx, my, 0.0, 0.0];
+                let p = array![[MEASURE_VAR, 0.0, 0.0, 0.0],
+                               [0.0, MEASURE_VAR, 0.0, 0.0],
+                               [0.0, 0.0, 1.0, 0.0],
+                               [0.0, 0.0, 0.0, 1.0]];
+                self.state = Some((x, p));
+                self.last_time = time;
+                return;
+            },
+            Some(state) => state,
+        };
+
+        // events may arrive out of order, they count as simultaneous
+        let dt = time.saturating_sub(self.last_time) as f64;
+        self.last_time = std::cmp::max(time, self.last_time);
+        let (x, p) = propagate(&x, &p, dt);
+
+        // measure the position only: S = P[:2, :2] + R, K = P[:, :2] S^-1
+        let (s00, s01, s11) = (p[[0, 0]] + MEASURE_VAR, p[[0, 1]], p[[1, 1]] + MEASURE_VAR);
+        let det = s00 * s11 - s01 * s01;
+        let s_inv = array![[s11 / det, -s01 / det], [-s01 / det, s00 / det]];
+        let k = p.slice(s![.., 0..2]).dot(&s_inv);
+        let innovation = array![mx - x[0], my - x[1]];
+
+        let x = x + k.dot(&innovation);
+        let mut kh: Array2<f64> = Array2::zeros((4, 4));
+        kh.slice_mut(s![.., 0..2]).assign(&k);
+        let p = (Array2::eye(4) - kh).dot(&p);
+
+        self.state = Some((x, p));
+    }
+
+    /// bivariate gaussian of the position `dt` ms after the last observation
+    fn position_at(&self, dt: f64) -> Option<scheduler::Gaussian2D> {
+        let (x, p) = match &self.state {
+            Some((x, p)) => propagate(x, p, dt),
+            None => return None,
+        };
+
+        let xsigma = p[[0, 0]].sqrt().max(MIN_SIGMA);
+        let ysigma = p[[1, 1]].sqrt().max(MIN_SIGMA);
+        let rho = (p[[0, 1]] / (xsigma * ysigma)).max(-0.99).min(0.99);
+
+        Some(scheduler::Gaussian2D{w: 1.0, xmu: x[0], ymu: x[1], xsigma: xsigma, ysigma: ysigma, rho: rho})
+    }
+}
+
+impl Predictor for KalmanPredictor {
+    fn observe(&mut self, event: &Event) {
+        match event {
+            Event::Mouse{x, y, time} => self.update(*x, *y, *time),
+            Event::Layout{layout} => self.layout = Some(layout.clone()),
+            Event::Action{..} => return,
+        }
+
+        self.changed = true;
+    }
+
+    fn predict(&mut self) -> Option<ds::PredictorState> {
+        if !self.changed || self.state.is_none() {
+            return None;
+        }
+        self.changed = false;
+
+        let mut dist = serde_json::Map::new();
+        for &delta in self.horizons.iter() {
+            if let Some(g) = self.position_at(delta as f64) {
+                dist.insert(delta.to_string(), serde_json::to_value(&g).unwrap());
+            }
+        }
+
+        let mut data = serde_json::json!({"dist": dist});
+        // the decoder keeps the last layout it received
+        if let Some(layout) = self.layout.take() {
+            data["layout"] = layout;
+        }
+
+        Some(ds::PredictorState::new(scheduler::GAUSSIAN_MODEL, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kalman_follows_cursor() {
+        let mut predictor = KalmanPredictor::new(&serde_json::json!({}));
+        assert_eq!(predictor.predict(), None);
+
+        predictor.observe(&Event::Layout{layout: serde_json::json!({"a": {"x": 0, "y": 0, "w": 10, "h": 10}})});
+        // moving right at 1px/ms
+        for t in 0..20 {
+            predictor.observe(&Event::Mouse{x: (t * 10) as f64, y: 50.0, time: t * 10});
+        }
+
+        let state = predictor.predict().unwrap();
+        assert_eq!(state.model, scheduler::GAUSSIAN_MODEL);
+        assert!(state.data.get("layout").is_some());
+        let g: Vec<scheduler::Gaussian2D> = scheduler::Gaussian2D::parse_mixture(&state.data.get("dist").unwrap()["100"]).unwrap();
+        assert!((g[0].xmu - 290.0).abs() < 20.0, "{:?}", g);
+        assert!((g[0].ymu - 50.0).abs() < 1.0, "{:?}", g);
+
+        // uncertainty grows with the horizon
+        let far = scheduler::Gaussian2D::parse_mixture(&state.data.get("dist").unwrap()["500"]).unwrap();
+        assert!(far[0].xsigma > g[0].xsigma);
+
+        // nothing new to send
+        assert_eq!(predictor.predict(), None);
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Markov chain over the actions of the player.
+ *
+ * Counts the transitions between observed actions in a table of order k, the
+ * context of a row being the last k actions, and sends it row normalised as the
+ * markov model, see scheduler::MarkovDecoder.
+ */
+use super::{Event, Predictor};
+use crate::ds;
+use crate::scheduler;
+use serde_derive::{Deserialize, Serialize};
+
+/// pseudo count of every transition before any action is observed
+const PRIOR_COUNT: f64 = 1.0;
+
+/// Transition counts of order k: row c counts the actions observed after the
+/// context c of the last k actions, see scheduler::markov_context
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransitionCounts {
+    pub num_actions: usize,
+    pub order: usize,
+    /// num_actions^order rows of num_actions
+    pub counts: Vec<Vec<f64>>,
+}
+
+impl TransitionCounts {
+    pub fn new(num_actions: usize, order: usize) -> Self {
+        let order = std::cmp::max(order, 1);
+        let counts = vec![vec![0.0; num_actions]; num_actions.pow(order as u32)];
+        TransitionCounts{num_actions: num_actions, order: order, counts: counts}
+    }
+
+    /// count `action` after the last `order` actions of `history`
+    pub fn add(&mut self, history: &[usize], action: usize) {
+        if history.len() < self.order || action >= self.num_actions
+           || history.iter().any(|&a| a >= self.num_actions) {
+            return;
+        }
+
+        let context = scheduler::markov_context(&history[history.len() - self.order..], self.num_actions);
+        self.counts[context][action] += 1.0;
+    }
+
+    /// add the counts of `other`, ignored if it counts another action space
+    pub fn merge(&mut self, other: &TransitionCounts) {
+        if other.num_actions != self.num_actions || other.order != self.order {
+            return;
+        }
+
+        for (row, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            for (n, m) in row.iter_mut().zip(other.iter()) {
+                *n += m;
+            }
+        }
+    }
+
+    /// number of observed transitions
+    pub fn total(&self) -> f64 {
+        self.counts.iter().map(|row| row.iter().sum::<f64>()).sum()
+    }
+
+    /// transition table with `pseudo` added to every count, rows normalised to sum to 1
+    pub fn table(&self, pseudo: f64) -> Vec<Vec<f64>> {
+        self.posterior(None, pseudo)
+    }
+
+    /// transition table combining the counts with a model of the same shape worth
+    /// `weight` observations per row, e.g. the model sent by the client. Rows without
+    /// any observation are uniform
+    pub fn posterior(&self, model: Option<&[Vec<f64>]>, weight: f64) -> Vec<Vec<f64>> {
+        self.counts.iter().enumerate().map(|(c, row)| {
+            let prior: Vec<f64> = match model.and_then(|m| m.get(c)) {
+                Some(p) if p.len() == self.num_actions => {
+                    let sum: f64 = p.iter().sum();
+                    p.iter().map(|x| if sum > 0.0 { weight * x / sum } else { 0.0 }).collect()
+                },
+                _ => vec![weight; self.num_actions],
+            };
+
+            let row: Vec<f64> = row.iter().zip(prior.iter()).map(|(n, p)| n + p).collect();
+            let sum: f64 = row.iter().sum();
+            match sum > 0.0 {
+                true => row.iter().map(|x| x / sum).collect(),
+                false => vec![1.0 / self.num_actions as f64; self.num_actions],
+            }
+        }).collect()
+    }
+}
+
+pub struct MarkovPredictor {
+    num_actions: usize,
+    order: usize,
+    counts: TransitionCounts,
+    /// last `order` actions, most recent last
+    history: Vec<usize>,
+    tick: u64,
+    /// observed actions since the last prediction
+    changed: bool,
+}
+
+impl MarkovPredictor {
+    /// config: {nactions?: 5, order?: 1}
+    pub fn new(config: &serde_json::Value) -> Self {
+        let num_actions = config["nactions"].as_u64().unwrap_or(5) as usize;
+        let order = std::cmp::max(config["order"].as_u64().unwrap_or(1) as usize, 1);
+        let counts = TransitionCounts::new(num_actions, order);
+
+        MarkovPredictor{num_actions: num_actions, order: order, counts: counts,
+                        history: Vec::with_capacity(order), tick: 0, changed: false}
+    }
+
+    /// transition table with rows normalised to sum to 1
+    pub fn table(&self) -> Vec<Vec<f64>> {
+        self.counts.table(PRIOR_COUNT)
+    }
+
+    fn update(&mut self, action: usize, tick: u64) {
+        if action >= self.num_actions {
+            error!("action {} is out of range {}", action, self.num_actions);
+            return;
+        }
+
+        self.counts.add(&self.history, action);
+        if self.history.len() == self.order {
+            self.history.remove(0);
+        }
+
+        self.history.push(action);
+        self.tick = tick;
+        self.changed = true;
+    }
+}
+
+impl Predictor for MarkovPredictor {
+    fn observe(&mut self, event: &Event) {
+        if let Event::Action{action, tick} = event {
+            self.update(*action, *tick);
+        }
+    }
+
+    fn predict(&mut self) -> Option<ds::PredictorState> {
+        // a context needs `order` actions
+        if !self.changed || self.history.len() < self.order {
+            return None;
+        }
+        self.changed = false;
+
+        let data = serde_json::json!({
+            "action": self.history[self.history.len() - 1],
+            "tick": self.tick,
+            "order": self.order,
+            "history": self.history,
+            "dist": self.table(),
+        });
+
+        Some(ds::PredictorState::new(scheduler::MARKOV_MODEL, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_transition_counts_posterior() {
+        let mut counts = TransitionCounts::new(2, 1);
+        for _ in 0..30 {
+            counts.add(&[0], 1);
+        }
+        assert_eq!(counts.total(), 30.0);
+
+        // no observation after 1
+        let table = counts.table(1.0);
+        assert_eq!(table[1], vec![0.5, 0.5]);
+        assert!((table[0][1] - 31.0 / 32.0).abs() < 1e-9);
+
+        // the client model is a prior worth 10 observations
+        let client = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let table = counts.posterior(Some(&client), 10.0);
+        assert!((table[0][1] - 0.75).abs() < 1e-9, "{:?}", table);
+        assert_eq!(table[1], vec![0.0, 1.0]);
+
+        let bytes = bincode::serialize(&counts).unwrap();
+        assert_eq!(bincode::deserialize::<TransitionCounts>(&bytes).unwrap(), counts);
+
+        let mut other = TransitionCounts::new(2, 1);
+        other.add(&[1], 0);
+        counts.merge(&other);
+        assert_eq!(counts.counts, vec![vec![0.0, 30.0], vec![1.0, 0.0]]);
+        counts.merge(&TransitionCounts::new(3, 1));
+        assert_eq!(counts.total(), 31.0);
+    }
+
+    #[test]
+    fn test_markov_learns_transitions() {
+        let mut predictor = MarkovPredictor::new(&serde_json::json!({"nactions": 3, "order": 2}));
+        predictor.observe(&Event::Action{action: 0, tick: 1});
+        assert_eq!(predictor.predict(), None);
+
+        // 0, 1, 2, 0, 1, 2, ...
+        for tick in 2..90 {
+            predictor.observe(&Event::Action{action: (tick - 1) as usize % 3, tick: tick});
+        }
+
+        let table = predictor.table();
+        assert!(table[scheduler::markov_context(&[0, 1], 3)][2] > 0.9, "{:?}", table);
+
+        // decoded by the app decoder of the same model
+        let state = predictor.predict().unwrap();
+        let index = Arc::new(RwLock::new(scheduler::SequenceIndex::new(8)));
+        let mut decoder = scheduler::MarkovDecoder::new(3, 3, 4, index.clone());
+        let prob = scheduler::PredictorDecoder::decode(&mut decoder, &state.data).unwrap();
+        let (_, actions) = index.read().unwrap().get(0).cloned().unwrap();
+        assert_eq!(actions, vec![2, 0, 1]);
+        assert!(prob.get(0, 0) > 0.7);
+    }
+}
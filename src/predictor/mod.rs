@@ -0,0 +1,78 @@
+/*
+ * Server side predictors.
+ *
+ * Instead of sending the state of a predictor running in the browser, thin clients
+ * send raw interaction events (cursor positions, actions). A predictor selected per
+ * session with 'AppState.predictor' consumes them and emits the same PredictorState a
+ * client side predictor of its model would send, which the app decodes into a Prob
+ * with its registered decoders.
+ */
+pub mod kalman;
+pub mod markov;
+
+use crate::ds;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PredictorType {
+    /// kalman filter over the cursor position, emits the gaussian model "GM"
+    Kalman,
+    /// markov chain over actions, emits the markov model "MM"
+    Markov,
+}
+
+/// raw interaction event sent by the client
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// cursor position in layout coordinates at client time `time` in ms
+    Mouse { x: f64, y: f64, time: u64 },
+    /// action taken at game tick `tick`
+    Action { action: usize, tick: u64 },
+    /// query rectangles {key: {x, y, w, h}}
+    Layout { layout: serde_json::Value },
+}
+
+impl Event {
+    /// events of a message, either one event or a list of events
+    pub fn parse(msg: &str) -> Result<Vec<Event>, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(msg)?;
+        match value {
+            serde_json::Value::Array(_) => serde_json::from_value(value),
+            _ => serde_json::from_value(value).map(|e| vec![e]),
+        }
+    }
+}
+
+pub trait Predictor: Send + Sync {
+    /// update the predictor with an interaction event
+    fn observe(&mut self, event: &Event);
+
+    /// state of the predicted model if it changed since the last prediction
+    fn predict(&mut self) -> Option<ds::PredictorState>;
+}
+
+/// predictors::new: create the predictor of a session
+///                  config: app state sent by the client, see each predictor for its options
+pub fn new(ptype: PredictorType, config: &serde_json::Value) -> Box<dyn Predictor> {
+    match ptype {
+        PredictorType::Kalman => Box::new(kalman::KalmanPredictor::new(config)) as Box<dyn Predictor>,
+        PredictorType::Markov => Box::new(markov::MarkovPredictor::new(config)) as Box<dyn Predictor>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        let events = Event::parse(r#"[{"type": "Mouse", "x": 1.0, "y": 2.0, "time": 10},
+                                      {"type": "Action", "action": 3, "tick": 7}]"#).unwrap();
+        assert_eq!(events, vec![Event::Mouse{x: 1.0, y: 2.0, time: 10}, Event::Action{action: 3, tick: 7}]);
+
+        let events = Event::parse(r#"{"type": "Layout", "layout": {}}"#).unwrap();
+        assert_eq!(events, vec![Event::Layout{layout: serde_json::json!({})}]);
+        assert!(Event::parse(r#"{"type": "Scroll"}"#).is_err());
+    }
+}
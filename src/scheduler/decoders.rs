@@ -1,8 +1,14 @@
-use super::prob::{Prob};
-use ndarray::{Array1, Array2, ArrayView1};
+use super::prob::{Prob, Component};
+use crate::payload::Payload;
+use crate::error;
+use super::registry::PredictorDecoder;
+use ndarray::{Array1, Array2};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
 extern crate statrs;
 
+/// dist of the linear gaussian point model, see LinearPointDecoder
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LinearPointGaussian {
     pub p: serde_json::Value,
@@ -15,8 +21,12 @@ pub struct LinearPointGaussian {
 ///
 /// # Example
 /// ```
-/// let dist = json!({"x": 0.4, "y": 0.6});
-/// let decoded_dist = decode_dist(dist);
+/// use khameleon::scheduler::decode_dist;
+///
+/// let queries_blcount = indexmap::indexmap!{"x".to_owned() => 4, "y".to_owned() => 4};
+/// let dist = serde_json::json!({"x": 0.4, "y": 0.6});
+/// let decoded_dist = decode_dist(dist, &queries_blcount);
+/// assert_eq!(decoded_dist, indexmap::indexmap!{0 => 0.4, 1 => 0.6});
 /// ```
 pub fn decode_dist(dist: serde_json::Value,
                    queries_blcount: &indexmap::IndexMap<String, usize>)
@@ -36,49 +46,274 @@ pub fn decode_dist(dist: serde_json::Value,
     map
 }
 
-fn norm_cdf(x: f64, mu: f64, sigma: f64) -> f64 {
-    let z: f64 = (x - mu) / sigma;
-    const SQRT_2: f64 = 1.4142135623730951;
-    let y: f64 = z / SQRT_2;
-    let cdf: f64 = {
-        if x >= 3.0 {
-            0.5 * statrs::function::erf::erfc( -1.0 * y)
+/// standard normal cdf
+fn phi(z: f64) -> f64 {
+    0.5 * statrs::function::erf::erfc(-z / std::f64::consts::SQRT_2)
+}
+
+/// Gauss-Legendre points and weights on [-1, 1] (half of each symmetric set)
+/// for 6, 12 and 20 points
+const GL_X: [&[f64]; 3] = [
+    &[-0.9324695142031522, -0.6612093864662647, -0.2386191860831970],
+    &[-0.9815606342467191, -0.9041172563704750, -0.7699026741943050,
+      -0.5873179542866171, -0.3678314989981802, -0.1252334085114692],
+    &[-0.9931285991850949, -0.9639719272779138, -0.9122344282513259,
+      -0.8391169718222188, -0.7463319064601508, -0.6360536807265150,
+      -0.5108670019508271, -0.3737060887154196, -0.2277858511416451,
+      -0.07652652113349733],
+];
+const GL_W: [&[f64]; 3] = [
+    &[0.1713244923791705, 0.3607615730481384, 0.4679139345726904],
+    &[0.04717533638651177, 0.1069393259953183, 0.1600783285433464,
+      0.2031674267230659, 0.2334925365383547, 0.2491470458134029],
+    &[0.01761400713915212, 0.04060142980038694, 0.06267204833410906,
+      0.08327674157670475, 0.1019301198172404, 0.1181945319615184,
+      0.1316886384491766, 0.1420961093183821, 0.1491729864726037,
+      0.1527533871307259],
+];
+
+/// P(X > h, Y > k) for a standard bivariate normal with correlation r,
+/// Genz (2004) "Numerical computation of rectangular bivariate and trivariate normal
+/// and t probabilities", accurate to about 1e-15
+fn bvn_upper(h: f64, k: f64, r: f64) -> f64 {
+    const TWOPI: f64 = 2.0 * std::f64::consts::PI;
+    let ng = if r.abs() < 0.3 { 0 } else if r.abs() < 0.75 { 1 } else { 2 };
+    let (x, w) = (GL_X[ng], GL_W[ng]);
+
+    let mut k = k;
+    let mut hk = h * k;
+    let mut bvn: f64 = 0.0;
+    if r.abs() < 0.925 {
+        let hs = (h * h + k * k) / 2.0;
+        let asr = r.asin();
+        for i in 0..x.len() {
+            let sn = (asr * (x[i] + 1.0) / 2.0).sin();
+            bvn += w[i] * ((sn * hk - hs) / (1.0 - sn * sn)).exp();
+            let sn = (asr * (-x[i] + 1.0) / 2.0).sin();
+            bvn += w[i] * ((sn * hk - hs) / (1.0 - sn * sn)).exp();
+        }
+        bvn = bvn * asr / (2.0 * TWOPI) + phi(-h) * phi(-k);
+    } else {
+        if r < 0.0 {
+            k = -k;
+            hk = -hk;
+        }
+
+        if r.abs() < 1.0 {
+            let a_s = (1.0 - r) * (1.0 + r);
+            let mut a = a_s.sqrt();
+            let bs = (h - k) * (h - k);
+            let c = (4.0 - hk) / 8.0;
+            let d = (12.0 - hk) / 16.0;
+            bvn = a * (-(bs / a_s + hk) / 2.0).exp()
+                  * (1.0 - c * (bs - a_s) * (1.0 - d * bs / 5.0) / 3.0 + c * d * a_s * a_s / 5.0);
+            if hk > -160.0 {
+                let b = bs.sqrt();
+                bvn -= (-hk / 2.0).exp() * TWOPI.sqrt() * phi(-b / a) * b
+                       * (1.0 - c * bs * (1.0 - d * bs / 5.0) / 3.0);
+            }
+
+            a /= 2.0;
+            for i in 0..x.len() {
+                let xs = (a * (x[i] + 1.0)).powi(2);
+                let rs = (1.0 - xs).sqrt();
+                bvn += a * w[i] * ((-bs / (2.0 * xs) - hk / (1.0 + rs)).exp() / rs
+                                   - (-(bs / xs + hk) / 2.0).exp() * (1.0 + c * xs * (1.0 + d * xs)));
+                let xs = a_s * (-x[i] + 1.0).powi(2) / 4.0;
+                let rs = (1.0 - xs).sqrt();
+                bvn += a * w[i] * (-(bs / xs + hk) / 2.0).exp()
+                       * ((-hk * (1.0 - rs) / (2.0 * (1.0 + rs))).exp() / rs - (1.0 + c * xs * (1.0 + d * xs)));
+            }
+            bvn = -bvn / TWOPI;
+        }
+
+        if r > 0.0 {
+            bvn += phi(-h.max(k));
         } else {
-            0.5 + 0.5 * statrs::function::erf::erf( y )
+            bvn = -bvn + (phi(-h) - phi(-k)).max(0.0);
         }
-    };
-    
-    cdf
+    }
+
+    bvn.max(0.0).min(1.0)
+}
+
+/// P(X < h, Y < k) for a standard bivariate normal with correlation rho
+pub fn bivariate_norm_cdf(h: f64, k: f64, rho: f64) -> f64 {
+    bvn_upper(-h, -k, rho)
+}
+
+/// Bivariate normal over the mouse position, a component of a gaussian mixture
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gaussian2D {
+    /// weight in the mixture
+    #[serde(default = "Gaussian2D::default_weight")]
+    pub w: f64,
+    pub xmu: f64,
+    pub ymu: f64,
+    pub xsigma: f64,
+    pub ysigma: f64,
+    /// correlation between x and y, in (-1, 1)
+    #[serde(default)]
+    pub rho: f64,
 }
 
-/// TODO: think of an optimization that compute queries that fall within
-///       percentile range and exclude the others
-fn cdf_array(arr: ArrayView1<f32>, mu: f64, sigma: f64) -> Array1<f32> {
-    let mut cdf_out = Array1::zeros(arr.len());
-    
-    for (i, value) in cdf_out.indexed_iter_mut() {
-        *value = norm_cdf(arr[i] as f64, mu, sigma) as f32;
+impl Gaussian2D {
+    fn default_weight() -> f64 {
+        1.0
     }
 
-    cdf_out
+    fn cdf(&self, x: f64, y: f64) -> f64 {
+        // degenerate sigmas put all the mass on the mean
+        let h = (x - self.xmu) / self.xsigma.max(1e-6);
+        let k = (y - self.ymu) / self.ysigma.max(1e-6);
+        let rho = self.rho.max(-0.999999).min(0.999999);
+        bivariate_norm_cdf(h, k, rho)
+    }
+
+    /// probability of the rectangle [x1, x2] x [y1, y2]
+    pub fn rect_prob(&self, x1: f64, x2: f64, y1: f64, y2: f64) -> f64 {
+        let p = self.cdf(x2, y2) - self.cdf(x1, y2) - self.cdf(x2, y1) + self.cdf(x1, y1);
+        p.max(0.0)
+    }
+
+    /// components of the model at one time step: a single gaussian
+    /// {xmu, ymu, xsigma, ysigma, rho?} or a mixture {"mixture": [{w, xmu, ...}, ...]}
+    pub fn parse_mixture(model: &serde_json::Value) -> Result<Vec<Gaussian2D>, String> {
+        let components = match model.get("mixture") {
+            Some(mixture) => serde_json::from_value(mixture.clone()),
+            None => serde_json::from_value(model.clone()).map(|g| vec![g]),
+        };
+
+        let components: Vec<Gaussian2D> = components.map_err(|e| format!("invalid gaussian model {:?}: {}", model, e))?;
+        let total: f64 = components.iter().map(|g| g.w).sum();
+        if components.is_empty() || total <= 0.0 {
+            return Err(format!("gaussian mixture without weight {:?}", model));
+        }
+
+        Ok(components.into_iter().map(|mut g| { g.w /= total; g }).collect())
+    }
+}
+
+/// probability of each query rectangle (row of layout matrix) under a gaussian mixture
+fn mixture_rect_probs(mixture: &[Gaussian2D], layout_matrix: &Array2<f32>) -> Array1<f32> {
+    let mut probs: Array1<f32> = Array1::zeros(layout_matrix.rows());
+    for (i, row) in layout_matrix.genrows().into_iter().enumerate() {
+        let (xpw, xmw, yph, ymh) = (row[0] as f64, row[1] as f64, row[2] as f64, row[3] as f64);
+        probs[i] = mixture.iter().map(|g| g.w * g.rect_prob(xmw, xpw, ymh, yph)).sum::<f64>() as f32;
+    }
+
+    probs
 }
 
-pub fn decode_point_model(point: &serde_json::Value) -> (f64, f64, f64) {
-    match point.as_object() {
-        Some(obj) => {
-            let alpha = obj["a"].as_f64().unwrap_or(1.0);
-            let x = obj["X"].as_f64().unwrap_or(0.0);
-            let y = obj["Y"].as_f64().unwrap_or(0.0);
-            
-            // map x and y to query
-            (alpha, x, y)
-        }, None => (1.0, 0.0, 0.0)
-    } 
+/// Layout of the queries sent by the client {key: {x, y, w, h}} as a matrix
+/// with a row per query and columns [x+w, x, y+h, y]. Queries without a layout
+/// have an empty rectangle.
+pub fn layout_matrix(layout: &serde_json::Value,
+                     queries_blcount: &indexmap::IndexMap<String, usize>) -> Array2<f32> {
+    let mut matrix: Array2<f32> = Array2::zeros((queries_blcount.len(), 4));
+    if let Some(obj) = layout.as_object() {
+        for (k, bounds) in obj.iter() {
+            match queries_blcount.get_full(k) {
+                Some((index, _, _)) => {
+                    let x = bounds["x"].as_f64().unwrap_or(0.0) as f32;
+                    let y = bounds["y"].as_f64().unwrap_or(0.0) as f32;
+                    let w = bounds["w"].as_f64().unwrap_or(0.0) as f32;
+                    let h = bounds["h"].as_f64().unwrap_or(0.0) as f32;
+                    matrix.row_mut(index).assign(&Array1::from_vec(vec![x + w, x, y + h, y]));
+                }, None => error!("layout key isn't in queries_blcount {:?}", k),
+            }
+        }
+    }
+
+    matrix
+}
+
+/// name of the gaussian mouse model in PredictorState.model, its data is
+/// {dist: {time: model}, layout?: {key: {x, y, w, h}}}, see `Gaussian2D::parse_mixture`
+pub const GAUSSIAN_MODEL: &str = "GM";
+
+/// Decodes the gaussian mouse model over the layout of the queries, the layout
+/// is kept from the last state that carried one
+pub struct GaussianDecoder {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    layout: Array2<f32>,
+}
+
+impl GaussianDecoder {
+    pub fn new(blocks_per_query: indexmap::IndexMap<String, usize>) -> Self {
+        let layout = Array2::zeros((blocks_per_query.len(), 4));
+        GaussianDecoder{blocks_per_query: blocks_per_query, layout: layout}
+    }
+}
+
+impl PredictorDecoder for GaussianDecoder {
+    fn decode(&mut self, data: &Payload) -> Result<Prob, String> {
+        if let Some(layout) = data.get("layout") {
+            self.layout = layout_matrix(&layout, &self.blocks_per_query);
+        }
+
+        match data.get("dist") {
+            Some(dist) if dist.is_object() => Ok(decode_model(&dist, &self.layout)),
+            _ => Err(format!("expected {{dist: {{time: model}}}} got {:?}", data.to_json())),
+        }
+    }
+}
+
+/// name of the linear gaussian point model in PredictorState.model, its data is
+/// {dist: {g: {time: model}, p: {a, X, Y}}, layout?: {key: {x, y, w, h}}}
+pub const LINEAR_POINT_MODEL: &str = "LGP";
+
+/// Decodes the linear gaussian point model: the cursor stays on the query under the
+/// point (X, Y) with probability `a`, otherwise it follows the gaussian model
+pub struct LinearPointDecoder {
+    gaussian: GaussianDecoder,
+}
+
+impl LinearPointDecoder {
+    pub fn new(blocks_per_query: indexmap::IndexMap<String, usize>) -> Self {
+        LinearPointDecoder{gaussian: GaussianDecoder::new(blocks_per_query)}
+    }
+
+    /// (alpha, x, y) of the point model
+    fn parse_point(point: &serde_json::Value) -> Result<(f32, f64, f64), String> {
+        match (point["a"].as_f64(), point["X"].as_f64(), point["Y"].as_f64()) {
+            (Some(a), Some(x), Some(y)) if a >= 0.0 && a <= 1.0 => Ok((a as f32, x, y)),
+            _ => Err(format!("expected {{a: [0, 1], X, Y}} got {:?}", point)),
+        }
+    }
+
+    /// query whose layout contains (x, y)
+    fn query_at(&self, x: f64, y: f64) -> Option<usize> {
+        let (x, y) = (x as f32, y as f32);
+        self.gaussian.layout.outer_iter().position(|b| x >= b[1] && x <= b[0] && y >= b[3] && y <= b[2])
+    }
+}
+
+impl PredictorDecoder for LinearPointDecoder {
+    fn decode(&mut self, data: &Payload) -> Result<Prob, String> {
+        if let Some(layout) = data.get("layout") {
+            self.gaussian.layout = layout_matrix(&layout, &self.gaussian.blocks_per_query);
+        }
+
+        let dist: LinearPointGaussian = match data.get("dist") {
+            Some(dist) => serde::Deserialize::deserialize(&*dist).map_err(|e| format!("invalid dist {:?}: {}", dist, e))?,
+            None => return Err(format!("expected {{dist: {{g, p}}}} got {:?}", data.to_json())),
+        };
+        if !dist.g.is_object() {
+            return Err(format!("expected {{time: model}} got {:?}", dist.g));
+        }
+
+        let mut prob = decode_model(&dist.g, &self.gaussian.layout);
+        let (alpha, x, y) = LinearPointDecoder::parse_point(&dist.p)?;
+        if let Some(qid) = self.query_at(x, y) {
+            prob.add_component(alpha, Component::Point(qid)).map_err(|e| e.to_string())?;
+        }
+        Ok(prob)
+    }
 }
 
 /// this is used if we stream model instead of explicit probs
 /// get list of queries and their layout -> for each query, compute prob given the layout
-#[allow(dead_code)]
 pub fn decode_model(dist: &serde_json::Value, layout_matrix: &Array2<f32>) -> Prob {
         let nqueries = layout_matrix.rows();
         let mut probs = Prob::new(nqueries);
@@ -89,25 +324,24 @@ pub fn decode_model(dist: &serde_json::Value, layout_matrix: &Array2<f32>) -> Pr
         if let Some(obj) = dist.as_object() {
             debug!("Model Parameters {:?}", obj);
             for (time, model) in obj {
-                let time = time.parse::<i32>().unwrap();
-                
-                let xmu = model["xmu"].as_f64().unwrap();
-                let ymu = model["ymu"].as_f64().unwrap();
-                // does not take into account correlation
-                let xsigma = model["xsigma"].as_f64().unwrap();
-                let ysigma = model["ysigma"].as_f64().unwrap();
-
-                // for each xpw, xmw, yph, ymh (col in layout matrix) compute cdf into cdf_array
-                let col_xpw = layout_matrix.column(0);
-                let col_xmw = layout_matrix.column(1);
-                let col_yph = layout_matrix.column(2);
-                let col_ymh = layout_matrix.column(3);
-
-                let out_col_xpw = cdf_array(col_xpw, xmu, xsigma);
-                let out_col_xmw = cdf_array(col_xmw, xmu, xsigma);
-                let out_col_yph = cdf_array(col_yph, ymu, ysigma);
-                let out_col_ymh = cdf_array(col_ymh, ymu, ysigma);
-                let probs_t = &out_col_xpw * &out_col_yph - &out_col_xpw * &out_col_ymh - &out_col_xmw * &out_col_yph + &out_col_xmw * &out_col_ymh;
+                let time = match time.parse::<usize>() {
+                    Ok(time) => time,
+                    Err(_) => {
+                        error!("invalid time {:?}", time);
+                        continue;
+                    }
+                };
+
+                let mixture = match Gaussian2D::parse_mixture(model) {
+                    Ok(mixture) => mixture,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+
+                // for each query rectangle [xmw, xpw] x [ymh, yph] in the layout matrix
+                let probs_t = mixture_rect_probs(&mixture, layout_matrix);
                 
                 let mut sub_queries_idx: Vec<usize> = Vec::new();
                 let mut sub_qprobs: Vec<f32> = Vec::new();
@@ -136,52 +370,332 @@ pub fn decode_model(dist: &serde_json::Value, layout_matrix: &Array2<f32>) -> Pr
                 // 5. construct the map
                 for (i, qidx) in sub_queries_idx.iter().enumerate() {
                     let mut p = sub_qprobs[i];
-                    if max_index == i as i32 { p += diff; }
+                    if max_index == *qidx as i32 { p += diff; }
                     map.insert(*qidx, p);
                 }
 
-                probs.set_probs_at(map, time as usize);
+                probs.set_probs_at(map, time);
             }
         } 
 
         probs
 }
 
-pub fn decode_markov(dist: &serde_json::Value, future: u32, actions_n: usize, queries_n: usize, lastaction_id: usize, tick: u64, prob: &mut Prob) {
-    let mut tmatrix: Vec<Vec<serde_json::Value>> = Vec::new();
-    dist.as_array().unwrap().clone()
-        .into_iter()
-        .for_each(|v| tmatrix.push(v.as_array().unwrap().clone()));
-    let mut map: indexmap::IndexMap<usize, f32> = indexmap::IndexMap::new();
-    for qid in 0..queries_n {
-        let mut i = qid;
-        // translate qid to sequence of future actions
-        let mut actions: Vec<usize> = Vec::new();
-        for d in (0..future).rev() {
-            actions.push(i / actions_n.pow(d));
-            i = i % actions_n.pow(d);
-        }
-        actions.sort();
-        let mut sorted_qid = 0;
-        for d in 0..future {
-            sorted_qid += actions_n.pow(future - 1 - d) * actions[d as usize];
-        }
-        // calculate probability of each sequence of actions
-        let mut p = 1.0;
-        let mut prevaction_id = lastaction_id;
-        for a in actions.into_iter() {
-            p *= tmatrix[prevaction_id][a].as_f64().unwrap();
-            prevaction_id = a;
-        }
-        // safe cast?
-        let ticked_qid = tick as usize * 10usize.pow(future) + sorted_qid;
-        let new_p = match map.get(&ticked_qid) {
-            Some(prev_p) => {
-                prev_p + p as f32
-            }, _ => {p as f32}
+/// name of the markov model in PredictorState.model, its data is
+/// {action, tick, dist: transition table, order?: k, history?: [actions]}, see `MarkovDecoder`
+pub const MARKOV_MODEL: &str = "MM";
+
+/// branches less likely than this are pruned while expanding action sequences
+const MIN_SEQUENCE_PROB: f64 = 1e-4;
+
+/// row of the context `actions` in a transition table, the last action is the least
+/// significant digit in base `num_actions`
+pub fn markov_context(actions: &[usize], num_actions: usize) -> usize {
+    actions.iter().rev().enumerate().map(|(i, &a)| a * num_actions.pow(i as u32)).sum()
+}
+
+/// Most likely sequences of `future` actions following `history`, in the order the
+/// actions are taken, with their probability.
+///
+/// `table` is a transition table of order k = `order`: row c holds the probability of
+/// each next action given the context c of the last k actions, encoded in base
+/// `num_actions` with the most recent action as the least significant digit.
+/// At each step only the `beam` most likely prefixes with probability at least
+/// `min_prob` are expanded, so at most `beam` sequences are returned, most likely first.
+pub fn markov_sequences(table: &[Vec<f64>], order: usize, num_actions: usize, history: &[usize],
+                        future: u32, beam: usize, min_prob: f64) -> Vec<(Vec<usize>, f64)> {
+    let context = |prefix: &[usize]| -> usize {
+        let mut last: Vec<usize> = history.iter().chain(prefix.iter()).rev().take(order).cloned().collect();
+        last.reverse();
+        markov_context(&last, num_actions)
+    };
+
+    let mut sequences: Vec<(Vec<usize>, f64)> = vec![(Vec::new(), 1.0)];
+    for _ in 0..future {
+        let mut expanded: Vec<(Vec<usize>, f64)> = Vec::with_capacity(sequences.len() * num_actions);
+        for (prefix, p) in sequences.iter() {
+            let row = match table.get(context(prefix)) {
+                Some(row) => row,
+                None => continue,
+            };
+
+            for (a, &pa) in row.iter().enumerate().take(num_actions) {
+                let p = p * pa;
+                if p < min_prob {
+                    continue;
+                }
+
+                let mut seq = prefix.clone();
+                seq.push(a);
+                expanded.push((seq, p));
+            }
+        }
+
+        expanded.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(core::cmp::Ordering::Equal));
+        expanded.truncate(beam);
+        sequences = expanded;
+    }
+
+    sequences
+}
+
+/// Query ids of predicted frames, a frame is the tick it's displayed at and the
+/// sequence of actions that leads to it. Ids are assigned the first time a frame is
+/// predicted and recycled oldest first once `capacity` frames are known, so the
+/// number of queries doesn't grow with the length of the sequences. The capacity is
+/// the number of queries of the scheduler, and the blocks cached under a recycled id
+/// belong to its previous frame, see take_recycled.
+pub struct SequenceIndex {
+    capacity: usize,
+    ids: HashMap<(u64, Vec<usize>), usize>,
+    frames: Vec<(u64, Vec<usize>)>,
+    /// next id to assign or recycle
+    next: usize,
+    /// ids given to a new frame since the last take_recycled
+    recycled: BTreeSet<usize>,
+}
+
+impl SequenceIndex {
+    pub fn new(capacity: usize) -> Self {
+        SequenceIndex{capacity: std::cmp::max(capacity, 1), ids: HashMap::new(),
+                      frames: Vec::new(), next: 0, recycled: BTreeSet::new()}
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// id of the frame at `tick` after `actions`, assigned if it's a new frame
+    pub fn id(&mut self, tick: u64, actions: &[usize]) -> usize {
+        let frame = (tick, actions.to_vec());
+        if let Some(&id) = self.ids.get(&frame) {
+            return id;
+        }
+
+        let id = self.next;
+        if id < self.frames.len() {
+            let old = std::mem::replace(&mut self.frames[id], frame.clone());
+            self.ids.remove(&old);
+            self.recycled.insert(id);
+        } else {
+            self.frames.push(frame.clone());
+        }
+        self.ids.insert(frame, id);
+        self.next = (id + 1) % self.capacity;
+
+        id
+    }
+
+    /// ids that now name another frame, to forget in the cache models
+    pub fn take_recycled(&mut self) -> Vec<usize> {
+        std::mem::replace(&mut self.recycled, BTreeSet::new()).into_iter().collect()
+    }
+
+    /// (tick, actions) of the frame with id `qid`
+    pub fn get(&self, qid: usize) -> Option<&(u64, Vec<usize>)> {
+        self.frames.get(qid)
+    }
+
+    /// key of a frame shared with the client: "tick:action,action,..."
+    pub fn key(tick: u64, actions: &[usize]) -> String {
+        let actions: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+        format!("{}:{}", tick, actions.join(","))
+    }
+}
+
+/// Decodes the markov model into the most likely frames `future` ticks ahead, the
+/// ids of the frames are assigned by the shared `SequenceIndex`
+pub struct MarkovDecoder {
+    future: u32,
+    num_actions: usize,
+    /// most likely sequences kept at each step
+    beam: usize,
+    index: Arc<RwLock<SequenceIndex>>,
+}
+
+impl MarkovDecoder {
+    pub fn new(future: u32, num_actions: usize, beam: usize, index: Arc<RwLock<SequenceIndex>>) -> Self {
+        MarkovDecoder{future: future, num_actions: num_actions, beam: beam, index: index}
+    }
+
+    /// transition table with `num_actions`^`order` rows, each row normalised to sum to 1
+    fn parse_table(&self, data: &Payload, order: usize) -> Result<Vec<Vec<f64>>, String> {
+        let nrows = self.num_actions.pow(order as u32);
+        let rows = data.get_matrix("dist").unwrap_or_else(|| Err("missing transition table".to_owned()))?;
+        if rows.len() != nrows || rows.iter().any(|row| row.len() != self.num_actions) {
+            return Err(format!("expected a {}x{} transition table of order {} got {:?}",
+                               nrows, self.num_actions, order, rows));
+        }
+
+        Ok(rows.into_iter().map(|row| {
+            let sum: f64 = row.iter().sum();
+            match sum > 0.0 {
+                true => row.into_iter().map(|p| p / sum).collect(),
+                false => row,
+            }
+        }).collect())
+    }
+}
+
+impl PredictorDecoder for MarkovDecoder {
+    fn decode(&mut self, data: &Payload) -> Result<Prob, String> {
+        let action = data.get_u64("action").ok_or_else(|| format!("missing last action in {:?}", data.to_json()))? as usize;
+        let cur_tick = data.get_u64("tick").ok_or_else(|| format!("missing tick in {:?}", data.to_json()))?;
+        let order = data.get_u64("order").unwrap_or(1) as usize;
+        if order == 0 {
+            return Err("order of the markov model should be at least 1".to_owned());
+        }
+
+        // last actions taken, most recent last
+        let history: Vec<usize> = match data.get_indices("history") {
+            Some(history) => history?,
+            None => vec![action],
         };
-        map.insert(ticked_qid, new_p);
+        if history.len() < order {
+            return Err(format!("{} actions of history for a model of order {}", history.len(), order));
+        }
+        if let Some(a) = history.iter().find(|&&a| a >= self.num_actions) {
+            return Err(format!("action {} is out of range {}", a, self.num_actions));
+        }
+
+        let table = self.parse_table(data, order)?;
+        let sequences = markov_sequences(&table, order, self.num_actions, &history,
+                                         self.future, self.beam, MIN_SEQUENCE_PROB);
+
+        let mut index = error::write(&self.index);
+        let tick = cur_tick + self.future as u64;
+        let mut map: indexmap::IndexMap<usize, f32> = indexmap::IndexMap::new();
+        for (seq, p) in sequences.iter() {
+            map.insert(index.id(tick, seq), *p as f32);
+        }
+        debug!("decoded {} sequences of {} actions", map.len(), self.future);
+
+        let mut prob = Prob::new(index.capacity());
+        prob.set_probs_at(map, 0);
+
+        Ok(prob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bivariate_norm_cdf() {
+        // closed form at the origin: 1/4 + asin(rho) / 2pi
+        for &rho in [-0.99, -0.95, -0.5, 0.0, 0.2, 0.5, 0.9, 0.95, 0.99].iter() {
+            let expected = 0.25 + (rho as f64).asin() / (2.0 * std::f64::consts::PI);
+            let cdf = bivariate_norm_cdf(0.0, 0.0, rho);
+            assert!((cdf - expected).abs() < 1e-9, "rho {}: {} != {}", rho, cdf, expected);
+        }
+
+        // independent components
+        let cdf = bivariate_norm_cdf(1.0, -0.5, 0.0);
+        assert!((cdf - phi(1.0) * phi(-0.5)).abs() < 1e-9);
+        // perfectly correlated in the limit
+        assert!((bivariate_norm_cdf(1.0, 2.0, 0.999) - phi(1.0)).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_decode_model_correlation() {
+        // two queries on the diagonal and two off the diagonal
+        let layout = indexmap!{"a".to_owned() => 1, "b".to_owned() => 1, "c".to_owned() => 1, "d".to_owned() => 1};
+        let matrix = layout_matrix(&serde_json::json!({
+            "a": {"x": 0.0, "y": 0.0, "w": 10.0, "h": 10.0},
+            "b": {"x": -10.0, "y": -10.0, "w": 10.0, "h": 10.0},
+            "c": {"x": -10.0, "y": 0.0, "w": 10.0, "h": 10.0},
+            "d": {"x": 0.0, "y": -10.0, "w": 10.0, "h": 10.0},
+        }), &layout);
+
+        let model = serde_json::json!({"xmu": 0.0, "ymu": 0.0, "xsigma": 3.0, "ysigma": 3.0, "rho": 0.8});
+        let probs = mixture_rect_probs(&Gaussian2D::parse_mixture(&model).unwrap(), &matrix);
+        assert!(probs[0] > probs[2] && probs[1] > probs[3], "{:?}", probs);
+        assert!((probs.sum() - 1.0).abs() < 1e-2, "{:?}", probs);
+
+        // mixture weights are normalised
+        let model = serde_json::json!({"mixture": [
+            {"w": 3.0, "xmu": 5.0, "ymu": 5.0, "xsigma": 1.0, "ysigma": 1.0},
+            {"w": 1.0, "xmu": -5.0, "ymu": 5.0, "xsigma": 1.0, "ysigma": 1.0},
+        ]});
+        let probs = mixture_rect_probs(&Gaussian2D::parse_mixture(&model).unwrap(), &matrix);
+        assert!((probs[0] - 0.75).abs() < 1e-3 && (probs[2] - 0.25).abs() < 1e-3, "{:?}", probs);
+
+        let dist = serde_json::json!({"0": model});
+        let decoded = decode_model(&dist, &matrix);
+        assert!(decoded.get_k().contains(&0));
+    }
+
+    #[test]
+    fn test_linear_point_decoder() {
+        let mut decoder = LinearPointDecoder::new(indexmap!{"a".to_owned() => 1, "b".to_owned() => 1});
+        let layout = serde_json::json!({"a": {"x": 0.0, "y": 0.0, "w": 10.0, "h": 10.0},
+                                        "b": {"x": 100.0, "y": 0.0, "w": 10.0, "h": 10.0}});
+        let g = serde_json::json!({"0": {"xmu": 5.0, "ymu": 5.0, "xsigma": 2.0, "ysigma": 2.0}});
+        let data = Payload::Json(serde_json::json!({"layout": layout,
+                                                    "dist": {"g": g, "p": {"a": 0.7, "X": 105.0, "Y": 5.0}}}));
+        let prob = decoder.decode(&data).unwrap();
+        assert_eq!(prob.get_mixture(), &[(0.7, Component::Point(1))][..]);
+        assert!(prob.get(1, 0) > 0.69 && prob.get(0, 0) > 0.29, "{} {}", prob.get(0, 0), prob.get(1, 0));
+
+        // the layout is kept, a point outside of it leaves the gaussian model
+        let data = Payload::Json(serde_json::json!({"dist": {"g": g, "p": {"a": 0.7, "X": 50.0, "Y": 5.0}}}));
+        assert!(decoder.decode(&data).unwrap().get_mixture().is_empty());
+        let data = Payload::Json(serde_json::json!({"dist": {"g": g, "p": {"a": 2.0, "X": 5.0, "Y": 5.0}}}));
+        assert!(decoder.decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_markov_sequences_keep_order() {
+        // 2 actions, after 0 always 1, after 1 always 0
+        let table = vec![vec![0.0, 1.0], vec![1.0, 0.0]];
+        let sequences = markov_sequences(&table, 1, 2, &[0], 3, 10, 0.0);
+        assert_eq!(sequences.len(), 8);
+        assert_eq!(sequences[0], (vec![1, 0, 1], 1.0));
+        assert!(sequences[1..].iter().all(|(_, p)| *p == 0.0));
+
+        // second order: repeat the action before the last one
+        let table = vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0], vec![0.0, 1.0]];
+        let sequences = markov_sequences(&table, 2, 2, &[1, 0], 2, 1, 0.0);
+        assert_eq!(sequences, vec![(vec![1, 0], 1.0)]);
+    }
+
+    #[test]
+    fn test_markov_decoder_beam() {
+        let future = 6;
+        let index = Arc::new(RwLock::new(SequenceIndex::new(32)));
+        let mut decoder = MarkovDecoder::new(future, 5, 8, index.clone());
+        let tmatrix: Vec<Vec<f64>> = (0..5).map(|i| (0..5).map(|j| if i == j { 0.6 } else { 0.1 }).collect()).collect();
+        let data = Payload::Json(serde_json::json!({"action": 2, "tick": 10, "dist": tmatrix}));
+
+        let prob = decoder.decode(&data).unwrap();
+        // bounded by the beam, not 5^6 sequences
+        let qids = prob.get_k();
+        assert!(qids.len() <= 8, "{:?}", qids);
+
+        let index = index.read().unwrap();
+        let (tick, actions) = index.get(0).unwrap();
+        assert_eq!(*tick, 16);
+        assert_eq!(*actions, vec![2; 6]);
+        assert_eq!(SequenceIndex::key(*tick, actions), "16:2,2,2,2,2,2");
+        assert!(prob.get(0, 0) > prob.get(1, 0));
+
+        // wrong shape and unknown actions are reported
+        assert!(decoder.decode(&Payload::Json(serde_json::json!({"action": 7, "tick": 10, "dist": tmatrix}))).is_err());
+        assert!(decoder.decode(&Payload::Json(serde_json::json!({"action": 2, "tick": 10, "order": 2, "dist": tmatrix}))).is_err());
     }
-    // debug!("decoded dist: {:?}", map);
-    prob.set_probs_at(map, 0);
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sequence_index_recycles_ids() {
+        let mut index = SequenceIndex::new(2);
+        assert_eq!(index.id(1, &[0, 1]), 0);
+        assert_eq!(index.id(1, &[1, 0]), 1);
+        assert_eq!(index.id(1, &[0, 1]), 0);
+        // the oldest frame gives its id away
+        assert_eq!(index.id(2, &[0, 0]), 0);
+        assert_eq!(index.get(0), Some(&(2, vec![0, 0])));
+        assert_eq!(index.id(1, &[0, 1]), 1);
+        assert_eq!(index.take_recycled(), vec![0, 1]);
+        assert!(index.take_recycled().is_empty());
+    }
+}
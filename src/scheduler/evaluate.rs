@@ -0,0 +1,252 @@
+/*
+ * Expected-utility evaluator for schedules.
+ *
+ * Scores a plan (sequence of query ids, one per block slot) against a
+ * probability distribution using the same model the greedy scheduler optimizes:
+ * a block for query q sent at slot t adds its marginal utility for every ms,
+ * from its arrival at the client until the end of the horizon, weighted by the
+ * probability that q is requested during that time. Slots are discretised
+ * in blocks of average size, so a block starts at the slot of the bytes sent before it.
+ */
+use super::prob::{Prob};
+use super::BlockSizes;
+use crate::ds;
+
+use ndarray::{Array1, Array2};
+
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    /// expected utility of the plan together with the blocks already in cache
+    pub total: f32,
+    /// expected utility of the blocks already in cache only
+    pub cached: f32,
+    /// expected utility per query, indexed by query id
+    pub per_query: Array1<f32>,
+    /// client side time range (ms) the plan was evaluated over
+    pub delta_0: usize,
+    pub delta_m: usize,
+}
+
+impl Evaluation {
+    /// expected utility gained by the plan on top of the cache content
+    pub fn gain(&self) -> f32 {
+        self.total - self.cached
+    }
+
+    /// average expected utility per ms over the horizon, in [0, 1]
+    pub fn mean(&self) -> f32 {
+        if self.delta_m <= self.delta_0 {
+            return 0.0;
+        }
+
+        self.total / (self.delta_m - self.delta_0) as f32
+    }
+
+    /// the `k` queries contributing the most utility, sorted descending
+    pub fn top_queries(&self, k: usize) -> Vec<(usize, f32)> {
+        let mut queries: Vec<(usize, f32)> = self.per_query.iter().enumerate()
+                                                 .filter(|(_, &u)| u > 0.0)
+                                                 .map(|(q, &u)| (q, u)).collect();
+        queries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(core::cmp::Ordering::Equal));
+        queries.truncate(k);
+        queries
+    }
+}
+
+/// Compute the expected utility of `plan` over its horizon.
+///
+/// # Arguments
+///
+/// * `plan` - query id for each block slot, as returned by `SchedulerTrait::run_scheduler`.
+/// * `probs` - distribution over future requests.
+/// * `utility` - marginal utility of each block of each query, as returned by `UtilityCurves::utility_matrix`.
+/// * `blocks_per_query` - total blocks available for each query.
+/// * `block_sizes` - size in bytes of each block.
+/// * `cache_state` - blocks per query already at the client.
+/// * `tm` - translates block slots to client time.
+///
+/// # Example
+/// ```
+/// use khameleon::{ds, scheduler};
+///
+/// // two queries of two blocks, uniformly likely, and a slot per client ms
+/// let blocks_per_query = vec![2, 2];
+/// let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+/// let block_sizes = scheduler::BlockSizes::Fixed(1);
+/// let probs = scheduler::Prob::new(blocks_per_query.len());
+/// let tm = ds::TimeManager::new(1, 0, 1.0);
+///
+/// // send both blocks of query 0 to an empty cache
+/// let plan = [0, 0];
+/// let cache_state = ndarray::Array1::zeros(2);
+/// let eval = scheduler::evaluate(&plan, &probs, &utility, &blocks_per_query, &block_sizes, cache_state, &tm);
+/// assert!((eval.total - 0.75).abs() < 1e-6);
+/// ```
+pub fn evaluate(plan: &[usize], probs: &Prob, utility: &Array2<f32>,
+                blocks_per_query: &[usize], block_sizes: &BlockSizes,
+                cache_state: Array1<usize>, tm: &ds::TimeManager) -> Evaluation {
+    let total_queries = blocks_per_query.len();
+    let mut per_query: Array1<f32> = Array1::zeros(total_queries);
+    let mut state = cache_state;
+
+    let unit = block_sizes.unit();
+    // the horizon spans the bytes of every block in the plan
+    let mut plan_bytes: usize = 0;
+    let mut nblocks = state.clone();
+    for &qid in plan.iter().filter(|&&qid| qid < total_queries) {
+        plan_bytes += block_sizes.get(qid, nblocks[qid]);
+        nblocks[qid] += 1;
+    }
+    let horizon = (plan_bytes + unit - 1) / unit;
+    let delta_0 = tm.slot_to_client_delta(0);
+    let delta_m = tm.slot_to_client_delta(horizon);
+
+    // blocks already in cache are available from the start of the horizon
+    let mut cached: f32 = 0.0;
+    for (qid, &nblocks) in state.iter().enumerate() {
+        if nblocks == 0 || qid >= total_queries {
+            continue;
+        }
+
+        let nblocks = std::cmp::min(nblocks, std::cmp::min(blocks_per_query[qid], utility.cols()));
+        let u: f32 = utility.row(qid).iter().take(nblocks).sum();
+        let p = probs.integrate_over_range(qid, delta_0, delta_m);
+        per_query[qid] += u * p;
+        cached += u * p;
+    }
+
+    // each scheduled block adds its marginal utility from the time it reaches the client
+    let mut sent: usize = 0;
+    for &qid in plan.iter() {
+        if qid >= total_queries {
+            error!("evaluate: query {} is out of range {}", qid, total_queries);
+            continue;
+        }
+
+        let nblocks = state[qid];
+        let size = block_sizes.get(qid, nblocks);
+        if nblocks >= blocks_per_query[qid] || nblocks >= utility.cols() {
+            // the sender skips it too
+            continue;
+        }
+
+        let delta = tm.slot_to_client_delta(sent / unit);
+        per_query[qid] += utility[[qid, nblocks]] * probs.integrate_over_range(qid, delta, delta_m);
+        state[qid] += 1;
+        sent += size;
+    }
+
+    let total = per_query.sum();
+
+    Evaluation{total: total, cached: cached, per_query: per_query,
+               delta_0: delta_0, delta_m: delta_m}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler;
+
+    // slot t maps to client delta t ms
+    fn time_manager() -> ds::TimeManager {
+        ds::TimeManager::new(1, 0, 1.0)
+    }
+
+    #[test]
+    fn test_evaluate_uniform() {
+        let tm = time_manager();
+        let sizes = BlockSizes::Fixed(1);
+        let probs = Prob::new(2);
+        let blocks_per_query = vec![2, 2];
+        let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+
+        // slot 0 covers [0, 2) and slot 1 covers [1, 2) with p=0.5
+        let eval = evaluate(&[0, 0], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        assert!((eval.total - 0.75).abs() < 1e-6, "{:?}", eval);
+        assert!((eval.per_query[0] - 0.75).abs() < 1e-6);
+        assert_eq!(eval.per_query[1], 0.0);
+        assert_eq!(eval.cached, 0.0);
+    }
+
+    #[test]
+    fn test_evaluate_prefers_likely_query() {
+        let tm = time_manager();
+        let sizes = BlockSizes::Fixed(1);
+        let mut probs = Prob::new(2);
+        let mut dist: indexmap::IndexMap<usize, f32> = indexmap::IndexMap::new();
+        dist.insert(0, 0.9);
+        dist.insert(1, 0.1);
+        probs.set_probs_at(dist, 0);
+
+        let blocks_per_query = vec![2, 2];
+        let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+
+        let likely = evaluate(&[0, 0], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        let unlikely = evaluate(&[1, 1], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        assert!(likely.total > unlikely.total, "{:?} {:?}", likely, unlikely);
+        assert_eq!(likely.top_queries(1)[0].0, 0);
+    }
+
+    #[test]
+    fn test_evaluate_deadline() {
+        let tm = time_manager();
+        let sizes = BlockSizes::Fixed(1);
+        let mut probs = Prob::new(2);
+        probs.set_deadline(0, 1);
+        let blocks_per_query = vec![2, 2];
+        let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+
+        // only the first block arrives before the deadline, and is used until then
+        let eval = evaluate(&[0, 0], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        assert!((eval.total - 0.25).abs() < 1e-6, "{:?}", eval);
+    }
+
+    #[test]
+    fn test_evaluate_per_query_utility() {
+        let tm = time_manager();
+        let sizes = BlockSizes::Fixed(1);
+        let probs = Prob::new(2);
+        let blocks_per_query = vec![2, 2];
+        // the first block of query 1 is worth little
+        let curves = scheduler::UtilityCurves::Classes{curves: vec![vec![0.5, 1.0], vec![0.1, 1.0]],
+                                                       class_of: vec![0, 1]};
+        let utility = curves.utility_matrix(&blocks_per_query);
+
+        let eval_0 = evaluate(&[0], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        let eval_1 = evaluate(&[1], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        assert!((eval_0.total - 0.25).abs() < 1e-6, "{:?}", eval_0);
+        assert!((eval_1.total - 0.05).abs() < 1e-6, "{:?}", eval_1);
+    }
+
+    #[test]
+    fn test_evaluate_skips_exhausted_queries() {
+        let tm = time_manager();
+        let sizes = BlockSizes::Fixed(1);
+        let probs = Prob::new(2);
+        let blocks_per_query = vec![2, 2];
+        let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+
+        let mut cache_state: Array1<usize> = Array1::zeros(2);
+        cache_state[0] = 2;
+        let eval = evaluate(&[0, 0], &probs, &utility, &blocks_per_query, &sizes, cache_state, &tm);
+        assert!(eval.cached > 0.0);
+        assert_eq!(eval.gain(), 0.0);
+    }
+
+    #[test]
+    fn test_evaluate_block_sizes() {
+        let tm = time_manager();
+        let probs = Prob::new(2);
+        let blocks_per_query = vec![2, 2];
+        // query 0 blocks take 3 slots of average size
+        let sizes = BlockSizes::PerBlock(vec![vec![3, 3], vec![1, 1]]);
+        assert_eq!(sizes.unit(), 2);
+        let utility = scheduler::UtilityCurves::Shared(vec![0.5, 1.0]).utility_matrix(&blocks_per_query);
+
+        // query 1 sent after query 0 starts at slot 1 of a 2 slots horizon
+        let eval = evaluate(&[0, 1], &probs, &utility, &blocks_per_query, &sizes, Array1::zeros(2), &tm);
+        assert_eq!(eval.delta_m, 2);
+        assert!((eval.per_query[0] - 0.5).abs() < 1e-6, "{:?}", eval);
+        assert!((eval.per_query[1] - 0.25).abs() < 1e-6, "{:?}", eval);
+    }
+}
@@ -1,5 +1,6 @@
 #[warn(dead_code)]
 use crate::ds;
+use crate::error;
 
 /// public lib
 extern crate rand;
@@ -14,42 +15,31 @@ use ndarray::{Array1, Array2, ArrayView2, ArrayViewMut2};
 
 #[derive(Clone)]
 pub struct GreedyScheduler {
-    /// longest future, client cache size in blocks
-    pub cachesize: usize, 
-    /// use indexmap instead?
-    pub utility: Array1<f32>,
+    /// longest future, client cache size in bytes
+    pub cachebytes: usize,
     pub blocks_per_query: Array1<usize>,
+    pub block_sizes: super::BlockSizes,
+    /// marginal utility of each block (col) of each query (row)
     pub utility_matrix: Array2<f32>,
     pub total_queries: usize,
     pub tm: Arc<RwLock<ds::TimeManager>>,
+    /// blocks of average size planned per round
     pub batch: usize,
 }
 
-pub fn new(batch: usize, cachesize: usize, utility: Array1<f32>,
-           blocks_per_query: Vec<usize>,
+/// utility_matrix: see `UtilityCurves::utility_matrix`
+pub fn new(batch: usize, cachebytes: usize, utility_matrix: Array2<f32>,
+           blocks_per_query: Vec<usize>, block_sizes: super::BlockSizes,
            tm: Arc<RwLock<ds::TimeManager>>) -> GreedyScheduler {
     let total_queries = blocks_per_query.len();
-    let max_blocks_count = utility.len();
-    let mut utility_matrix: Array2<f32> = Array2::zeros((total_queries, max_blocks_count));
-
-    ndarray::Zip::from(utility_matrix.genrows_mut()).and(&blocks_per_query)
-                 .apply(|mut a_row, b_elt| {
-                     for (i, v) in a_row.indexed_iter_mut() {
-                         if i < *b_elt {
-                             *v = utility[i];
-                         } else {
-                             *v = 0.0;
-                         }
-
-                     }
-                 });
+    assert_eq!(utility_matrix.rows(), total_queries);
 
     let blocks_per_query: Array1<usize> = blocks_per_query.iter().map(|v| *v).collect();
 
-    GreedyScheduler {cachesize: cachesize, utility: utility, batch: batch,
+    GreedyScheduler {cachebytes: cachebytes, batch: batch,
                      total_queries: total_queries, utility_matrix: utility_matrix,
                      tm: tm,
-                     blocks_per_query: blocks_per_query}
+                     blocks_per_query: blocks_per_query, block_sizes: block_sizes}
 }
 
 
@@ -60,20 +50,19 @@ impl GreedyScheduler {
         let mut matrix: Array2<f32> = Array2::zeros((total_queries, horizon));
         let mut index = 0;
 
-        let tm = self.tm.read().unwrap();
+        let tm = error::read(&self.tm);
         let mut deltas: Vec<usize> = Vec::new();
-        let mut lows: Vec<usize> = Vec::new();
         
         for t in 0..horizon {
             deltas.push(tm.slot_to_client_delta(t));
-            lows.push(probs.get_lower_bound(t));
         }
         let horizon_delta = tm.slot_to_client_delta(horizon);
 
 
         for mut row in matrix.genrows_mut() {
+                let row_probs = probs.integrate_row(index, &deltas, horizon_delta);
                 for (t, v) in row.indexed_iter_mut() {
-                    *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
+                    *v = row_probs[t];
                 }
             index += 1;
         }
@@ -81,7 +70,7 @@ impl GreedyScheduler {
         matrix
     }
 
-    /// Analytically compute area under linear curve from t to m
+    /// Analytically compute area under the interpolated curve from t to m
     ///
     /// For each query, integrate (e.g., sum) over probabilities
     /// precompute u_i,t array
@@ -94,13 +83,11 @@ impl GreedyScheduler {
         let mut matrix: Array2<f32> = Array2::zeros((total_queries, horizon));
         let mut index = 0;
 
-        let tm = self.tm.read().unwrap();
+        let tm = error::read(&self.tm);
         let mut deltas: Vec<usize> = Vec::new();
-        let mut lows: Vec<usize> = Vec::new();
         
         for t in 0..horizon {
             deltas.push(tm.slot_to_client_delta(t));
-            lows.push(probs.get_lower_bound(t));
         }
         let horizon_delta = tm.slot_to_client_delta(horizon);
 
@@ -112,8 +99,9 @@ impl GreedyScheduler {
         for mut row in matrix.genrows_mut() {
             if q_in_p.contains(&index) {
                 // compute the probability of the query over future timestamps
+                let row_probs = probs.integrate_row(index, &deltas, horizon_delta);
                 for (t, v) in row.indexed_iter_mut() {
-                    *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
+                    *v = row_probs[t];
                 }
             } else {
                     match &rest {
@@ -121,8 +109,9 @@ impl GreedyScheduler {
                             row.assign( &r.clone() );
                         }, None => {
                             let mut rest_prob: Array1<f32> = Array1::zeros(horizon);
+                            let row_probs = probs.integrate_row(index, &deltas, horizon_delta);
                             for (t, v) in rest_prob.indexed_iter_mut() {
-                                *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
+                                *v = row_probs[t];
                             }
                             row.assign( &rest_prob.clone() );
                             rest = Some( rest_prob );
@@ -139,13 +128,11 @@ impl GreedyScheduler {
         -> (Array2<f32>, Array1<usize>) {
         let mut rest_index = 0;
 
-        let tm = self.tm.read().unwrap();
+        let tm = error::read(&self.tm);
         let mut deltas: Vec<usize> = Vec::new();
-        let mut lows: Vec<usize> = Vec::new();
         
         for t in 0..horizon {
             deltas.push(tm.slot_to_client_delta(t));
-            lows.push(probs.get_lower_bound(t));
         }
         let horizon_delta = tm.slot_to_client_delta(horizon);
 
@@ -161,9 +148,10 @@ impl GreedyScheduler {
 
         for (index, &qindex) in q_in_p.iter().enumerate() {
             let mut row = matrix.row_mut(index);
+            // compute the probability of the query over future timestamps
+            let row_probs = probs.integrate_row(qindex, &deltas, horizon_delta);
             for (t, v) in row.indexed_iter_mut() {
-                // compute the probability of the query over future timestamps
-                *v = probs.integrate_over_range(qindex, deltas[t], horizon_delta, lows[t]);
+                *v = row_probs[t];
             }
             queries_ids[index] = qindex;
             if rest_index == qindex {
@@ -174,8 +162,9 @@ impl GreedyScheduler {
         // 
         if rest_index < total_queries {
             let mut row = matrix.row_mut(q_in_p.len());
+            let row_probs = probs.integrate_row(rest_index, &deltas, horizon_delta);
             for (t, v) in row.indexed_iter_mut() {
-                *v = probs.integrate_over_range(rest_index, deltas[t], horizon_delta, lows[t]);
+                *v = row_probs[t];
             }
             queries_ids[ q_in_p.len() ] = rest_index; 
         }
@@ -185,7 +174,7 @@ impl GreedyScheduler {
     }
     
     pub fn greedy_partition(&self, queries_ids: Array1<usize>, horizon: usize, prob_matrix: &mut Array2<f32>,
-                total_queries: usize, utility: &Array1<f32>,
+                total_queries: usize, utility: &Array2<f32>,
                 mut state: Array1<usize>) -> Vec<usize> {
         // state: for each query, how many blocks are scheduled
         // for each block slot in cache, which qid is filling the slot
@@ -203,7 +192,7 @@ impl GreedyScheduler {
                 let nblocks = state[qid];
                 
                 if nblocks < self.blocks_per_query[qid] {
-                    rewards[i] = utility[nblocks] * p_qids[i];
+                    rewards[i] = utility[[qid, nblocks]] * p_qids[i];
                     sum += rewards[i];
                 } else {
                     rewards[i] = 0.0;
@@ -236,7 +225,7 @@ impl GreedyScheduler {
 
             // if the qid is last one then pick randomly from all set of queries
             
-            if state[qid] < utility.len() {
+            if state[qid] < utility.cols() {
                 blocks.push(qid);
                 state[qid] += 1;
             } else {
@@ -308,25 +297,34 @@ impl GreedyScheduler {
         plan
     } 
 
-    pub fn greedy_p(&self, horizon: usize, prob_matrix: &mut Array2<f32>,
-                total_queries: usize, utility: &Array1<f32>,
+    /// plan blocks until @horizon_bytes are filled. prob_matrix is discretised
+    /// in slots of 'unit' bytes, a block is scored at the slot it starts in
+    /// and its reward is normalised by its size, i.e. utility per byte
+    pub fn greedy_p(&self, horizon_bytes: usize, prob_matrix: &mut Array2<f32>,
+                total_queries: usize, utility: &Array2<f32>,
                 mut state: Array1<usize>) -> Vec<usize> {
         // state: for each query, how many blocks are scheduled
         // for each block slot in cache, which qid is filling the slot
         let mut blocks: Vec<usize> = Vec::new();
         let mut rng = rand::thread_rng();
         let mut rewards: Array1<f32> = Array1::zeros(total_queries);
-        for t in 0..horizon {
+        let unit = self.block_sizes.unit();
+        let slots = prob_matrix.cols();
+        // bytes planned so far
+        let mut sent: usize = 0;
+        while sent < horizon_bytes {
             let mut sum = 0.0;
+            let t = std::cmp::min(sent / unit, slots - 1);
             // for each qid, at time t get their probabilities
             let p_qids = prob_matrix.slice_mut(s![..total_queries, t]);
             // get the reward for each query according to how many blocks
             
             for i in 0.. p_qids.len() {
                 let nblocks = state[i];
+                let size = self.block_sizes.get(i, nblocks);
                 
-                if nblocks < self.blocks_per_query[i] {
-                    rewards[i] = utility[nblocks] * p_qids[i];
+                if nblocks < self.blocks_per_query[i] && sent + size <= horizon_bytes {
+                    rewards[i] = utility[[i, nblocks]] * p_qids[i] * unit as f32 / std::cmp::max(size, 1) as f32;
                     sum += rewards[i];
                 } else {
                     rewards[i] = 0.0;
@@ -342,12 +340,13 @@ impl GreedyScheduler {
                 Ok(dist) => dist,
                 Err(e) => {
                     error!("{:?} Invalid weight: {:?}", e, p_qids);
-                    continue
+                    break
                 },
             };
 
             let qid = dist.sample(&mut rng);
-            if state[qid] < utility.len() {
+            if state[qid] < utility.cols() {
+                sent += self.block_sizes.get(qid, state[qid]);
                 blocks.push(qid);
                 state[qid] += 1;
             } else {
@@ -375,11 +374,13 @@ impl super::SchedulerTrait for GreedyScheduler {
         let total_queries = self.total_queries;
         // dist indexed using the same index in queries vector
         // get this from app? have one that the app and scheduler use to synchronise?
-        //let max_blocks_count = self.utility.len();
-        let horizon = std::cmp::min(self.cachesize - start_idx, self.batch);
-        //let horizon = self.cachesize - start_idx;
+        //let max_blocks_count = self.utility_matrix.cols();
+        // plan in bytes, probabilities are integrated over slots of average block size
+        let unit = self.block_sizes.unit();
+        let horizon_bytes = std::cmp::min(self.cachebytes.saturating_sub(start_idx), self.batch * unit);
+        let horizon = (horizon_bytes + unit - 1) / unit;
 
-        if total_queries == 0 {
+        if total_queries == 0 || horizon == 0 {
             return Vec::new();
         }
 
@@ -395,8 +396,8 @@ impl super::SchedulerTrait for GreedyScheduler {
             println!("integrate probs: {:?}", start.elapsed());
             let start = Instant::now();
             //let plan = self.sample_plan(&mut prob_matrix.view_mut(), self.utility_matrix.view(), horizon, total_queries, max_blocks_count, state);
-            let plan = self.greedy_p(horizon, &mut prob_matrix, total_queries, &self.utility, state);
-            //let plan = self.greedy_partition(queries_ids, horizon, &mut prob_matrix, total_queries, &self.utility, state);
+            let plan = self.greedy_p(horizon_bytes, &mut prob_matrix, total_queries, &self.utility_matrix, state);
+            //let plan = self.greedy_partition(queries_ids, horizon, &mut prob_matrix, total_queries, &self.utility_matrix, state);
             debug!("greedy: {:?}", start.elapsed());
             println!("greedy: {:?}", start.elapsed());
             plan
@@ -2,6 +2,8 @@
  * Scheduler Interface + common functions.
  * SchedulerType: available schedulers
  * SchedulerTrait: the minimumm interface a scheduler has to implement
+ * evaluate: expected utility of a plan, used to compare schedulers
+ * DecoderRegistry: decoders of the predictor models an app accepts
  *
  * The scheduler takes as input a utility function and a probability distribution
  * over future requests (default: uniform). 
@@ -17,12 +19,17 @@ pub mod ilp;
 pub mod topk;
 pub mod prob;
 pub mod decoders;
+pub mod evaluate;
+pub mod registry;
 
 use crate::ds;
+use crate::error::Error;
 
-pub use prob::{Prob};
+pub use prob::{Prob, Interpolation, InterpolationMode, Component, MixtureError};
 pub use decoders::*;
-use ndarray::{Array1};
+pub use evaluate::{evaluate, Evaluation};
+pub use registry::{PredictorDecoder, DecoderRegistry, DecodeError};
+use ndarray::{Array1, Array2};
 use serde_derive::{Deserialize, Serialize};
 use std::sync::{Arc,  RwLock};
 
@@ -34,12 +41,148 @@ pub enum SchedulerType {
 }
 
 
+/// Utility of receiving the first n blocks of a query, n = 1..
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UtilityCurves {
+    /// one curve shared by all queries
+    Shared(Vec<f32>),
+    /// curves for classes of queries (at least one), and the class of each query
+    /// indexed by query id. per-query curves are a class per query.
+    Classes {
+        curves: Vec<Vec<f32>>,
+        class_of: Vec<usize>,
+    },
+}
+
+impl UtilityCurves {
+    /// class of query `qid`, queries without a class use the first curve
+    fn class(&self, qid: usize) -> usize {
+        match self {
+            UtilityCurves::Shared(_) => 0,
+            UtilityCurves::Classes{curves, class_of} => {
+                match class_of.get(qid) {
+                    Some(&class) if class < curves.len() => class,
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    /// utility curve of query `qid`, empty if there are no curves
+    pub fn curve(&self, qid: usize) -> &Vec<f32> {
+        static EMPTY: Vec<f32> = Vec::new();
+        match self {
+            UtilityCurves::Shared(curve) => curve,
+            UtilityCurves::Classes{curves, ..} => curves.get(self.class(qid)).unwrap_or(&EMPTY),
+        }
+    }
+
+    /// schedulers need at least one curve, and no empty curve
+    pub fn validate(&self) -> Result<(), Error> {
+        let curves: Vec<&Vec<f32>> = match self {
+            UtilityCurves::Shared(curve) => vec![curve],
+            UtilityCurves::Classes{curves, ..} => curves.iter().collect(),
+        };
+
+        if curves.is_empty() {
+            return Err(Error::InvalidInput("expected at least one utility curve".to_owned()));
+        }
+        match curves.iter().position(|curve| curve.is_empty()) {
+            Some(class) => Err(Error::InvalidInput(format!("utility curve {} is empty", class))),
+            None => Ok(()),
+        }
+    }
+
+    /// utility of the first `nblocks` blocks of query `qid`
+    pub fn get(&self, qid: usize, nblocks: usize) -> f32 {
+        let curve = self.curve(qid);
+        match std::cmp::min(nblocks, curve.len()) {
+            0 => 0.0,
+            n => curve[n - 1],
+        }
+    }
+
+    /// curve used by schedulers that don't support per-query utility
+    pub fn default_curve(&self) -> &Vec<f32> {
+        self.curve(0)
+    }
+
+    /// marginal utility of the i-th block (column) of each query (row),
+    /// zero past the number of blocks of the query
+    pub fn utility_matrix(&self, blocks_per_query: &[usize]) -> Array2<f32> {
+        let total_queries = blocks_per_query.len();
+        let max_blocks_count = blocks_per_query.iter().cloned().max().unwrap_or_else(|| 0);
+        let mut utility_matrix: Array2<f32> = Array2::zeros((total_queries, max_blocks_count));
+
+        let discretised: Vec<Array1<f32>> = match self {
+            UtilityCurves::Shared(curve) => vec![discretise_utility(curve.clone(), max_blocks_count)],
+            UtilityCurves::Classes{curves, ..} => {
+                curves.iter().map(|curve| discretise_utility(curve.clone(), max_blocks_count)).collect()
+            }
+        };
+
+        for (qid, mut row) in utility_matrix.genrows_mut().into_iter().enumerate() {
+            let utility = match discretised.get(self.class(qid)) {
+                Some(utility) => utility,
+                None => continue,
+            };
+            for (i, v) in row.indexed_iter_mut() {
+                if i < blocks_per_query[qid] {
+                    *v = utility[i];
+                }
+            }
+        }
+
+        utility_matrix
+    }
+}
+
+/// Size in bytes of the blocks of each query. Schedulers plan in bytes, and
+/// discretise time in slots of 'unit' bytes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BlockSizes {
+    /// every block has the same size
+    Fixed(usize),
+    /// size of each block of each query, indexed by query id
+    PerBlock(Vec<Vec<usize>>),
+}
+
+impl BlockSizes {
+    /// size of the i-th block of query `qid`, blocks with unknown size are 'unit' bytes
+    pub fn get(&self, qid: usize, i: usize) -> usize {
+        match self {
+            BlockSizes::Fixed(size) => *size,
+            BlockSizes::PerBlock(sizes) => match sizes.get(qid).and_then(|s| s.get(i)) {
+                Some(&size) => size,
+                None => self.unit(),
+            },
+        }
+    }
+
+    /// average block size in bytes, at least 1
+    pub fn unit(&self) -> usize {
+        let unit = match self {
+            BlockSizes::Fixed(size) => *size,
+            BlockSizes::PerBlock(sizes) => {
+                let count: usize = sizes.iter().map(|s| s.len()).sum();
+                let total: usize = sizes.iter().map(|s| s.iter().sum::<usize>()).sum();
+                match count {
+                    0 => 0,
+                    _ => total / count,
+                }
+            }
+        };
+
+        std::cmp::max(unit, 1)
+    }
+}
+
 pub fn discretise_utility(utility: Vec<f32>, max_blocks_count: usize) -> Array1<f32> {
     let utility: Array1<f32> = (0..max_blocks_count).enumerate().map(|(i, _v)| {
-        if i == 0 {
-            utility[i]
-        } else if i >= utility.len() {
+        if i >= utility.len() {
             0.0
+        } else if i == 0 {
+            utility[i]
         } else {
             utility[i] - utility[i-1]
         }
@@ -48,8 +191,9 @@ pub fn discretise_utility(utility: Vec<f32>, max_blocks_count: usize) -> Array1<
     utility
 }
 
-pub fn new(&stype: &SchedulerType, batch: usize, cachesize: usize,
-            utility: Vec<f32>, blocks_per_query: Vec<usize>,
+/// cachebytes: client cache capacity in bytes, see `ds::AppState::cache_bytes`
+pub fn new(&stype: &SchedulerType, batch: usize, cachebytes: usize,
+            utility: UtilityCurves, blocks_per_query: Vec<usize>, block_sizes: BlockSizes,
             tm: Option<Arc<RwLock<ds::TimeManager>>>) -> Box<dyn SchedulerTrait> {
     
     let tm = match tm {
@@ -60,16 +204,28 @@ pub fn new(&stype: &SchedulerType, batch: usize, cachesize: usize,
     let max_blocks_count = blocks_per_query.iter().cloned().max().unwrap_or_else(|| 0);
     let total_queries = blocks_per_query.len();
     // init utility array function and the utility for the queries
-    let utility = discretise_utility(utility, max_blocks_count);
     match stype {
-        SchedulerType::Greedy => Box::new( greedy::new(batch, cachesize, utility, blocks_per_query, tm) ) as Box<dyn SchedulerTrait>,
-        SchedulerType::ILP => Box::new( ilp::new(cachesize, utility, total_queries, tm) ) as Box<dyn SchedulerTrait>,
+        SchedulerType::Greedy => {
+            let utility_matrix = utility.utility_matrix(&blocks_per_query);
+            Box::new( greedy::new(batch, cachebytes, utility_matrix, blocks_per_query, block_sizes, tm) ) as Box<dyn SchedulerTrait>
+        },
+        SchedulerType::ILP => {
+            if let UtilityCurves::Classes{..} = utility {
+                warn!("ILP scheduler uses a single utility curve for all queries");
+            }
+            let utility = discretise_utility(utility.default_curve().clone(), max_blocks_count);
+            // ILP plans in slots of one block
+            let cachesize = cachebytes / block_sizes.unit();
+            Box::new( ilp::new(cachesize, utility, total_queries, tm) ) as Box<dyn SchedulerTrait>
+        },
         SchedulerType::TopK => Box::new( topk::new(5) ) as Box<dyn SchedulerTrait>,
     }
 }
 
 pub trait SchedulerTrait: Send + Sync + SchedulerClone {
     /// dist: hashmap[key] -> probability
+    /// state: blocks per query in the client cache
+    /// start_idx: bytes in the client cache, see `CacheSimulator::get_state`
     /// returns hashmap[key] -> block counts
     fn run_scheduler(&mut self, probs: Prob,
                      state: Array1<usize>, start_idx: usize) -> Vec<usize>;
@@ -93,3 +249,23 @@ impl Clone for Box<dyn SchedulerTrait> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_utility_curves() {
+        let curves = UtilityCurves::Classes{curves: vec![], class_of: vec![0, 0]};
+        assert!(curves.validate().is_err());
+        assert!(UtilityCurves::Classes{curves: vec![vec![1.0], vec![]], class_of: vec![]}.validate().is_err());
+        assert!(UtilityCurves::Shared(vec![]).validate().is_err());
+        assert!(UtilityCurves::Shared(vec![0.5, 1.0]).validate().is_ok());
+
+        // invalid curves have no utility instead of panicking
+        assert!(curves.curve(0).is_empty());
+        assert_eq!(curves.get(1, 2), 0.0);
+        assert_eq!(curves.utility_matrix(&[2, 1]), Array2::<f32>::zeros((2, 2)));
+        assert_eq!(UtilityCurves::Shared(vec![]).utility_matrix(&[2]), Array2::<f32>::zeros((1, 2)));
+        assert_eq!(discretise_utility(vec![], 2), Array1::<f32>::zeros(2));
+    }
+}
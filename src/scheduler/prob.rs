@@ -1,8 +1,67 @@
 use std::collections::HashMap;
 use std::collections::BTreeSet;
-use std::ops::Bound::{Included, Excluded};
 use std::time::{Instant};
 use std::collections::HashSet;
+use serde_derive::{Deserialize, Serialize};
+
+/// How the probability of a query evolves between the deltas of the model
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum InterpolationMode {
+    /// hold the probability of a delta until the next one
+    Step,
+    /// linear between consecutive deltas
+    Linear,
+    /// from each delta, decay toward the prior with a half life in ms
+    Exponential { half_life: f64 },
+}
+
+/// server config: {"mode": "Linear" | "Step" | {"Exponential": {"half_life": ms}}, "horizon": ms}
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Interpolation {
+    pub mode: InterpolationMode,
+    /// ms after the last delta the model is extrapolated before falling back to the prior
+    pub horizon: usize,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation{mode: InterpolationMode::Linear, horizon: 500}
+    }
+}
+
+/// piece of the probability curve of a query
+#[derive(Clone, Copy, Debug)]
+enum Segment {
+    Const(f64),
+    /// line through (t0, p0) and (t1, p1)
+    Line { t0: f64, p0: f64, t1: f64, p1: f64 },
+    /// target + (p0 - target) * 2^(-(t - t0) / half_life)
+    Decay { t0: f64, p0: f64, target: f64, half_life: f64 },
+}
+
+impl Segment {
+    fn value(&self, t: f64) -> f64 {
+        match *self {
+            Segment::Const(p) => p,
+            Segment::Line{t0, p0, t1, p1} => p0 + (t - t0) * (p1 - p0) / (t1 - t0),
+            Segment::Decay{t0, p0, target, half_life} =>
+                target + (p0 - target) * (-(t - t0) * std::f64::consts::LN_2 / half_life).exp(),
+        }
+    }
+
+    /// closed form integral over [a, b]
+    fn integral(&self, a: f64, b: f64) -> f64 {
+        match *self {
+            Segment::Const(p) => p * (b - a),
+            Segment::Line{..} => (b - a) * (self.value(a) + self.value(b)) / 2.0,
+            Segment::Decay{t0, p0, target, half_life} => {
+                let k = std::f64::consts::LN_2 / half_life;
+                target * (b - a) + (p0 - target) / k * ((-k * (a - t0)).exp() - (-k * (b - t0)).exp())
+            },
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Prob {
@@ -11,8 +70,17 @@ pub struct Prob {
     probs_t: HashMap<usize, ProbInstance>,
     deltas_ms: BTreeSet<usize>,
     inf: f32,
+    /// probability after the extrapolation horizon, uniform without a prior
+    prior: Option<ProbInstance>,
+    interpolation: Interpolation,
     pub time: Instant,
-    point_dist: PointDist,
+    /// (weight, component) blended with the predicted distribution, which keeps
+    /// 1 - sum of the weights
+    mixture: Vec<(f32, Component)>,
+    /// client delta (ms) after which blocks of a query are no longer useful
+    deadlines: HashMap<usize, usize>,
+    /// queries always worth sending whatever their probability
+    pinned: BTreeSet<usize>,
 }
 
 #[derive(Clone, Debug)]
@@ -37,19 +105,126 @@ impl ProbInstance {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct PointDist {
-    pub alpha: f32,
-    pub q_index: usize,
+/// Component of the mixture blended with the predicted distribution
+#[derive(Clone, Debug, PartialEq)]
+pub enum Component {
+    /// all the mass on one query
+    Point(usize),
+    /// explicit probabilities of a few queries, normalised to sum to 1
+    Sparse(indexmap::IndexMap<usize, f32>),
+    /// the same probability for every query
+    Uniform,
 }
 
-impl PointDist {
-    pub fn get_prob(&self, key: usize) -> f32 {
-        match key == self.q_index {
-            true => 1.0,
-            _ => 0.0
+impl Component {
+    pub fn get_prob(&self, key: usize, total_queries: usize) -> f32 {
+        match self {
+            Component::Point(q) => if key == *q { 1.0 } else { 0.0 },
+            Component::Sparse(dist) => dist.get(&key).cloned().unwrap_or(0.0),
+            Component::Uniform => uniform(1.0, total_queries),
         }
     }
+
+    /// queries with an explicit probability
+    pub fn queries(&self) -> Vec<usize> {
+        match self {
+            Component::Point(q) => vec![*q],
+            Component::Sparse(dist) => dist.keys().cloned().collect(),
+            Component::Uniform => Vec::new(),
+        }
+    }
+
+    /// Components as sent by a predictor in its state:
+    /// [{"weight": 0.3, "point": q}, {"weight": 0.2, "sparse": {q: p, ...}}, {"weight": 0.1, "uniform": true}]
+    /// where q is the key of a query in `queries` or its index.
+    pub fn parse_components(components: &serde_json::Value, queries: &indexmap::IndexMap<String, usize>)
+                            -> Result<Vec<(f32, Component)>, String> {
+        let query = |q: &serde_json::Value| -> Result<usize, String> {
+            match (q.as_u64(), q.as_str()) {
+                (Some(index), _) => Ok(index as usize),
+                (None, Some(key)) => match queries.get_full(key) {
+                    Some((index, _, _)) => Ok(index),
+                    None => match key.parse::<usize>() {
+                        Ok(index) => Ok(index),
+                        Err(_) => Err(format!("unknown query {:?}", key)),
+                    },
+                },
+                _ => Err(format!("expected a query key or index, got {:?}", q)),
+            }
+        };
+
+        let components = match components.as_array() {
+            Some(components) => components,
+            None => return Err(format!("expected a list of components, got {:?}", components)),
+        };
+
+        components.iter().map(|c| {
+            let weight = match c["weight"].as_f64() {
+                Some(weight) => weight as f32,
+                None => return Err(format!("component without weight {:?}", c)),
+            };
+
+            let component = if let Some(q) = c.get("point") {
+                Component::Point(query(q)?)
+            } else if let Some(dist) = c.get("sparse").and_then(|d| d.as_object()) {
+                let mut sparse = indexmap::IndexMap::new();
+                for (q, p) in dist.iter() {
+                    let p = p.as_f64().ok_or_else(|| format!("invalid probability {:?} of {:?}", p, q))?;
+                    sparse.insert(query(&serde_json::json!(q))?, p as f32);
+                }
+                Component::Sparse(sparse)
+            } else if c["uniform"].as_bool() == Some(true) {
+                Component::Uniform
+            } else {
+                return Err(format!("expected a point, sparse or uniform component, got {:?}", c));
+            };
+
+            Ok((weight, component))
+        }).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MixtureError {
+    /// weights are finite and in [0, 1]
+    InvalidWeight(f32),
+    /// the weights of the components sum to more than 1
+    WeightsExceedOne(f32),
+    /// a component refers to a query the app doesn't have
+    QueryOutOfRange {
+        query: usize,
+        total_queries: usize,
+    },
+    /// sparse probabilities are finite, non negative and some are positive
+    InvalidSparse(indexmap::IndexMap<usize, f32>),
+    /// the app has no queries to spread the mass of a component over
+    NoQueries,
+}
+
+impl std::fmt::Display for MixtureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MixtureError::InvalidWeight(w) => write!(f, "mixture weight {} isn't in [0, 1]", w),
+            MixtureError::WeightsExceedOne(sum) => write!(f, "mixture weights sum to {} > 1", sum),
+            MixtureError::QueryOutOfRange{query, total_queries} =>
+                write!(f, "query {} is out of range {}", query, total_queries),
+            MixtureError::InvalidSparse(dist) => write!(f, "invalid sparse component {:?}", dist),
+            MixtureError::NoQueries => write!(f, "the app has no queries"),
+        }
+    }
+}
+
+impl std::error::Error for MixtureError {}
+
+/// tolerance on the sum of the mixture weights
+const WEIGHT_EPSILON: f32 = 1e-4;
+
+/// `mass` spread evenly over `total_queries`, none without queries
+fn uniform(mass: f32, total_queries: usize) -> f32 {
+    match total_queries {
+        0 => 0.0,
+        n => mass / n as f32,
+    }
 }
 
 impl Prob {
@@ -68,14 +243,14 @@ impl Prob {
         let deltas_ms = BTreeSet::new();
 
         // uniform probability
-        let inf: f32 = 1.0 / total_queries as f32;
+        let inf: f32 = uniform(1.0, total_queries);
 
         // to account for progress of time when quering for probabilites
         let time = Instant::now();
-        let point_dist = PointDist{ alpha: 1.0, q_index: 0 };
 
         Prob{total_queries: total_queries, probs_t: probs_t,
-            deltas_ms: deltas_ms, inf: inf, time: time, point_dist: point_dist}
+            deltas_ms: deltas_ms, inf: inf, prior: None, interpolation: Interpolation::default(),
+            time: time, mixture: Vec::new(), deadlines: HashMap::new(), pinned: BTreeSet::new()}
     }
 
 
@@ -86,10 +261,31 @@ impl Prob {
             all_queries = all_queries.union(&p.get_k()).map(|&k| k).collect();
         }
 
-        all_queries.insert(self.point_dist.q_index);
+        for (_, component) in self.mixture.iter() {
+            all_queries.extend(component.queries());
+        }
+        // queries with a deadline don't share the probability of the rest of the queries
+        all_queries.extend(self.deadlines.keys());
         all_queries
     }
 
+    /// Set the client delta (ms) after which blocks of `key` are no longer useful,
+    /// e.g. a game frame whose tick has passed.
+    pub fn set_deadline(&mut self, key: usize, delta: usize) {
+        self.deadlines.insert(key, delta);
+    }
+
+    pub fn get_deadline(&self, key: usize) -> Option<usize> {
+        self.deadlines.get(&key).cloned()
+    }
+
+    /// deadlines as absolute server time, for the sender to drop stale blocks
+    pub fn get_deadlines(&self) -> HashMap<usize, Instant> {
+        self.deadlines.iter()
+            .map(|(&k, &delta)| (k, self.time + std::time::Duration::from_millis(delta as u64)))
+            .collect()
+    }
+
     /// # Arguments
     ///
     /// * `dist` - {key: query index, value:  prob as f32}. queries not included are assigned
@@ -98,14 +294,145 @@ impl Prob {
     pub fn set_probs_at(&mut self, dist: indexmap::IndexMap<usize, f32>, delta: usize) {
         // probability of the res tof queries not included in `dist`
         let dist_sum: f32 = dist.values().sum();
-        let rest_dist: f32 = (1.0 - dist_sum) / self.total_queries as f32;
+        let rest_dist: f32 = uniform(1.0 - dist_sum, self.total_queries);
         self.probs_t.insert(delta, ProbInstance{rest_dist: rest_dist, dist: dist});
         self.deltas_ms.insert(delta);
     }
 
-    pub fn set_point_dist(&mut self, alpha: f64, index: usize) {
-        self.point_dist.alpha =alpha as f32;
-        self.point_dist.q_index = index;
+    /// Blend the distribution at every delta with a prior over queries, e.g. the
+    /// popularity of each query: p = (1 - weight) * p + weight * prior.
+    /// A distribution without any delta is uniform. After the extrapolation
+    /// horizon queries have their prior probability.
+    ///
+    /// # Arguments
+    ///
+    /// * `prior` - {key: query index, value: prob}, queries not included share the rest uniformly
+    /// * `weight` - in [0, 1], 1 to use the prior alone
+    pub fn blend_prior(&mut self, prior: &indexmap::IndexMap<usize, f32>, weight: f32) {
+        if weight <= 0.0 {
+            return;
+        }
+
+        let weight = weight.min(1.0);
+        if self.probs_t.is_empty() {
+            self.set_probs_at(indexmap::IndexMap::new(), 0);
+        }
+
+        let prior_sum: f32 = prior.values().sum();
+        // the rest goes to the queries missing from the prior
+        let missing = self.total_queries.saturating_sub(prior.len());
+        let prior_rest: f32 = match missing {
+            0 => 0.0,
+            missing => ((1.0 - prior_sum) / missing as f32).max(0.0),
+        };
+        for instance in self.probs_t.values_mut() {
+            let mut dist: indexmap::IndexMap<usize, f32> = indexmap::IndexMap::new();
+            for (&k, &p) in instance.dist.iter() {
+                let q = prior.get(&k).cloned().unwrap_or(prior_rest);
+                dist.insert(k, (1.0 - weight) * p + weight * q);
+            }
+            for (&k, &q) in prior.iter() {
+                if !dist.contains_key(&k) {
+                    dist.insert(k, (1.0 - weight) * instance.rest_dist + weight * q);
+                }
+            }
+
+            instance.rest_dist = (1.0 - weight) * instance.rest_dist + weight * prior_rest;
+            instance.dist = dist;
+        }
+
+        self.prior = Some(ProbInstance{rest_dist: prior_rest, dist: prior.clone()});
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    pub fn get_interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// probability of `key` once the model no longer applies
+    fn get_prior(&self, key: usize) -> f32 {
+        let p = match &self.prior {
+            Some(prior) => prior.get(key),
+            None => self.inf,
+        };
+
+        self.get_mixed_prob(key, p)
+    }
+
+    /// Replace the mixture blended with the predicted distribution, e.g. "likely
+    /// one of these three next clicks":
+    /// p = (1 - sum(weights)) * p + sum(weight * component).
+    /// Sparse components are normalised, the mixture is unchanged on error.
+    ///
+    /// # Arguments
+    ///
+    /// * `components` - (weight, component), weights in [0, 1] summing to at most 1
+    pub fn set_mixture(&mut self, components: Vec<(f32, Component)>) -> Result<(), MixtureError> {
+        let mut mixture = Vec::with_capacity(components.len());
+        let mut total: f32 = 0.0;
+        for (weight, component) in components {
+            if !weight.is_finite() || weight < 0.0 || weight > 1.0 {
+                return Err(MixtureError::InvalidWeight(weight));
+            }
+
+            if self.total_queries == 0 {
+                return Err(MixtureError::NoQueries);
+            }
+
+            if let Some(&query) = component.queries().iter().find(|&&q| q >= self.total_queries) {
+                return Err(MixtureError::QueryOutOfRange{query: query, total_queries: self.total_queries});
+            }
+
+            let component = match component {
+                Component::Sparse(dist) => {
+                    let sum: f32 = dist.values().sum();
+                    if dist.values().any(|p| !p.is_finite() || *p < 0.0) || !(sum > 0.0) {
+                        return Err(MixtureError::InvalidSparse(dist));
+                    }
+                    Component::Sparse(dist.into_iter().map(|(k, p)| (k, p / sum)).collect())
+                },
+                component => component,
+            };
+
+            total += weight;
+            mixture.push((weight, component));
+        }
+
+        if total > 1.0 + WEIGHT_EPSILON {
+            return Err(MixtureError::WeightsExceedOne(total));
+        }
+
+        self.mixture = mixture;
+        Ok(())
+    }
+
+    /// add a component to the mixture, see `set_mixture`
+    pub fn add_component(&mut self, weight: f32, component: Component) -> Result<(), MixtureError> {
+        let mut components = self.mixture.clone();
+        components.push((weight, component));
+        self.set_mixture(components)
+    }
+
+    pub fn get_mixture(&self) -> &[(f32, Component)] {
+        &self.mixture
+    }
+
+    /// always send `key` whatever its probability, e.g. the game frame without action.
+    /// False if the app has no such query
+    pub fn pin(&mut self, key: usize) -> bool {
+        if key >= self.total_queries {
+            return false;
+        }
+        self.pinned.insert(key);
+        true
+    }
+
+    /// pinned queries in increasing order
+    pub fn pinned(&self) -> Vec<usize> {
+        self.pinned.iter().cloned().collect()
     }
 
     /// use the given time to query the model
@@ -116,121 +443,259 @@ impl Prob {
             None => self.inf
         };
 
-        self.get_linear_prob(key, p)// interpolate between point and gaussian distribution
+        self.get_mixed_prob(key, p)
     }
 
-    pub fn get_linear_prob(&self, key: usize, p: f32) -> f32 {
-        self.point_dist.alpha * p + (1.0 - self.point_dist.alpha) * self.point_dist.get_prob(key)
+    /// blend `p`, the predicted probability of `key`, with the mixture
+    pub fn get_mixed_prob(&self, key: usize, p: f32) -> f32 {
+        let weight: f32 = self.mixture.iter().map(|(w, _)| w).sum();
+        let mixed: f32 = self.mixture.iter()
+            .map(|(w, component)| w * component.get_prob(key, self.total_queries))
+            .sum();
+
+        (1.0 - weight).max(0.0) * p + mixed
     }
 
-    /// get the probability for delta
-    /// interpolate between two deltas in the model
-    #[inline]
-    pub fn get(&self, key: usize, delta: usize) -> f32 {
-        let (low, up) = self.get_time_bounds(delta);
-        let p0 = self.get_probs_at(key, low);
-        let p1 =  self.get_probs_at(key, up);
-        let slop = (p1 - p0)  / (up - low) as f32;
-        let p = p0 + (delta - low) as f32 * slop;
-        p
-    }
-    
-    
-    /// get the lower and upper bounds for t
-    #[inline]
-   fn get_time_bounds(&self, delta: usize) -> (usize, usize) {
-        let next = delta+1;
-        let mut iter = self.deltas_ms.range(0..next).rev();
-        let low = iter.next().unwrap_or(&delta);
-        let mut iter = self.deltas_ms.range(next..);
-        let up = iter.next().unwrap_or(&next);
-
-        (*low, *up)
-    }
-    
-    /// assumption: i < j and (i, j) is within (low, up) range
-    /// compute the area under the line (i, py1),(j, py2) 
-    #[inline]
-    pub fn area_under_curve(&self, qid: usize, low: usize, up: usize, mut i: usize, mut j: usize) -> f32 {
+    /// curve of `key` as (start, end, segment), from delta 0 to infinity:
+    /// the first delta holds before it, then the segments between deltas
+    /// follow the interpolation mode, and the last delta is extrapolated toward
+    /// the prior until the horizon
+    fn segments(&self, key: usize) -> Vec<(f64, f64, Segment)> {
+        let prior = self.get_prior(key) as f64;
+        let knots: Vec<(f64, f64)> = self.deltas_ms.iter()
+            .map(|&delta| (delta as f64, self.get_probs_at(key, delta) as f64))
+            .collect();
+
+        let (first, last) = match (knots.first(), knots.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return vec![(0.0, std::f64::INFINITY, Segment::Const(prior))],
+        };
 
-        if i >= j || low > i || j > up || up < low {
-            //error!("XXX {} {} {} {}", i, j, low, up);
-            return 0.0;
+        let mut segments = Vec::with_capacity(knots.len() + 2);
+        if first.0 > 0.0 {
+            segments.push((0.0, first.0, Segment::Const(first.1)));
         }
 
-        let mut p0 = self.get_probs_at(qid, low).abs();
-        let mut pm = self.get_probs_at(qid, up).abs();
-        if p0 > pm {
-            // assumption: p0 < pm to correctly compute area of triang and rect
-            let temp = pm;
-            pm = p0;
-            p0 = temp;
+        for pair in knots.windows(2) {
+            segments.push((pair[0].0, pair[1].0, self.segment(pair[0], pair[1], prior)));
+        }
 
-            // flip i and j too to query the right part of triang
-            let temp = j;
-            j = up - (i - low);
-            i = up - (temp - low);
+        let end = last.0 + self.interpolation.horizon as f64;
+        if end > last.0 {
+            segments.push((last.0, end, self.segment(last, (end, prior), prior)));
         }
-        let slop: f32 = (pm - p0) / (up - low) as f32;
-        let base = (j - i) as f32;
-        // area between under linear curve from t to horizon
+        segments.push((end, std::f64::INFINITY, Segment::Const(prior)));
+
+        segments
+    }
 
-        //let px = slop * (j as f32 - low as f32) + p0;
-        //let py = slop * (i as f32 - low as f32) + p0;
-        //let rect = base * px;
-        //let triang = base * (py - px) / 2.0;
-        //let p = rect + triang;
-        
-        let p = base * (p0  + slop * ( (i as f32+ j as f32)/2.0 - low as f32) ) ;
-        if p < 0.0 {
-            error!("area is negative: {:?} {} {} {} {} {} {} {}", p, low, up, i, j, slop, p0, pm);
+    /// segment between the knots (t, p) `from` and `to`
+    fn segment(&self, from: (f64, f64), to: (f64, f64), prior: f64) -> Segment {
+        match self.interpolation.mode {
+            InterpolationMode::Step => Segment::Const(from.1),
+            InterpolationMode::Linear => Segment::Line{t0: from.0, p0: from.1, t1: to.0, p1: to.1},
+            InterpolationMode::Exponential{half_life} =>
+                Segment::Decay{t0: from.0, p0: from.1, target: prior, half_life: half_life.max(1e-3)},
         }
-        
-        p
     }
 
-    pub fn get_lower_bound(&self, delta_0: usize) -> usize {
-        let mut low = 0;
-        // delta_ms: for each state sent from client, delta_ms stores distribtions in x ms in the
-        // future
-        let mut iter = self.deltas_ms.range((Included(&low), Included(&delta_0))).rev();
+    /// get the probability for delta
+    /// interpolate between the deltas in the model
+    #[inline]
+    pub fn get(&self, key: usize, delta: usize) -> f32 {
+        let t = delta as f64;
+        let p = self.segments(key).iter()
+            .find(|(start, end, _)| *start <= t && t < *end)
+            .map(|(_, _, segment)| segment.value(t))
+            .unwrap_or(0.0);
 
-        low = *iter.next().unwrap_or(&delta_0);    
+        p as f32
+    }
 
-        low
+    /// the `k` queries with an explicit probability that are the most likely at `delta`,
+    /// sorted descending. Empty for a uniform distribution
+    pub fn top_queries(&self, k: usize, delta: usize) -> Vec<(usize, f32)> {
+        let mut queries: Vec<(usize, f32)> = self.get_k().into_iter()
+                                                 .filter(|&q| q < self.total_queries)
+                                                 .map(|q| (q, self.get(q, delta))).collect();
+        queries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(core::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+        queries.truncate(k);
+        queries
     }
 
     /// given a delta t0 (ms) in the future, compute
     /// the probability until delta tm for qid
     /// assumptopm: delta_m > delta_0
+    /// blocks arriving after the deadline of qid have no probability to be used
     #[inline]
-    pub fn integrate_over_range(&self, qid: usize, delta_0: usize, delta_m: usize, low: usize) -> f32 {
-        let mut p: f32 = 0.0;
+    pub fn integrate_over_range(&self, qid: usize, delta_0: usize, delta_m: usize) -> f32 {
+        self.integrate_segments(&self.segments(qid), qid, delta_0, delta_m)
+    }
+
+    /// `integrate_over_range` of qid from each of `deltas` until `delta_m`, the
+    /// curve of qid is built once for the whole row
+    pub fn integrate_row(&self, qid: usize, deltas: &[usize], delta_m: usize) -> Vec<f32> {
+        let segments = self.segments(qid);
+        deltas.iter().map(|&delta_0| self.integrate_segments(&segments, qid, delta_0, delta_m)).collect()
+    }
+
+    /// integral of `segments`, the curve of qid, see `integrate_over_range`
+    fn integrate_segments(&self, segments: &[(f64, f64, Segment)], qid: usize,
+                          delta_0: usize, delta_m: usize) -> f32 {
+        let delta_m = match self.deadlines.get(&qid) {
+            Some(&deadline) => std::cmp::min(delta_m, deadline),
+            None => delta_m,
+        };
         if delta_0 >= delta_m {
             return 0.0;
         }
 
-        let inf = delta_m + 500; // ms
-        let mut low = low;
-        let mut upper_delta = delta_m;
-        let mut lower_delta = delta_0;
-        for &up in  self.deltas_ms.range((Excluded(&delta_0), Included(&delta_m))) {
-            upper_delta = std::cmp::min(up, delta_m);
-            lower_delta = std::cmp::max(delta_0, low);
-            p += self.area_under_curve(qid, low, up, lower_delta, upper_delta);
-            low = up; 
+        let (a, b) = (delta_0 as f64, delta_m as f64);
+        let p: f64 = segments.iter()
+            .filter(|(start, end, _)| *start < b && *end > a)
+            .map(|(start, end, segment)| segment.integral(start.max(a), end.min(b)))
+            .sum();
 
-            if delta_m <= upper_delta {
-                break;
-            }
-        }
+        p.abs() as f32
+    }
+}
 
-        if low < delta_m {
-            p += self.area_under_curve(qid, low, inf, lower_delta, upper_delta);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blend_prior() {
+        let prior = indexmap!{0 => 0.5, 1 => 0.5};
+
+        // before any prediction the prior replaces the uniform distribution
+        let mut probs = Prob::new(4);
+        probs.blend_prior(&prior, 1.0);
+        assert_eq!(probs.get(0, 0), 0.5);
+        assert_eq!(probs.get(2, 0), 0.0);
+        assert!(probs.get_k().contains(&1));
+
+        // blended with a prediction
+        let mut probs = Prob::new(4);
+        probs.set_probs_at(indexmap!{2 => 1.0}, 0);
+        probs.blend_prior(&prior, 0.2);
+        assert!((probs.get(2, 0) - 0.8).abs() < 1e-6);
+        assert!((probs.get(0, 0) - 0.1).abs() < 1e-6);
+        assert_eq!(probs.get(3, 0), 0.0);
+        assert_eq!(probs.top_queries(2, 0).iter().map(|&(q, _)| q).collect::<Vec<_>>(), vec![2, 0]);
+        assert!(Prob::new(4).top_queries(2, 0).is_empty());
+
+        // a prior that doesn't sum to 1 leaves the rest to the other queries
+        let mut probs = Prob::new(4);
+        probs.blend_prior(&indexmap!{0 => 0.6}, 1.0);
+        assert!((probs.get(3, 0) - 0.4 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_interpolation_modes() {
+        let mut probs = Prob::new(4);
+        probs.set_probs_at(indexmap!{0 => 1.0}, 0);
+        probs.set_probs_at(indexmap!{1 => 1.0}, 100);
+
+        // linear by default, back to uniform 500ms after the last delta
+        assert!((probs.get(0, 50) - 0.5).abs() < 1e-6);
+        assert!((probs.integrate_over_range(0, 0, 100) - 50.0).abs() < 1e-3);
+        assert!((probs.get(1, 350) - 0.625).abs() < 1e-6);
+        assert!((probs.get(1, 700) - 0.25).abs() < 1e-6);
+
+        probs.set_interpolation(Interpolation{mode: InterpolationMode::Step, horizon: 200});
+        assert_eq!(probs.get(0, 99), 1.0);
+        assert!((probs.integrate_over_range(0, 0, 100) - 100.0).abs() < 1e-3);
+        // held until the horizon then uniform
+        assert!((probs.integrate_over_range(1, 100, 400) - (200.0 + 25.0)).abs() < 1e-3);
+        assert_eq!(probs.integrate_row(1, &[100, 400, 500], 400),
+                   vec![probs.integrate_over_range(1, 100, 400), 0.0, 0.0]);
+
+        probs.set_interpolation(Interpolation{mode: InterpolationMode::Exponential{half_life: 50.0}, horizon: 200});
+        assert!((probs.get(0, 50) - 0.625).abs() < 1e-6);
+        // the closed form matches a riemann sum
+        let value = |qid: usize, t: f64| probs.segments(qid).iter()
+            .find(|(start, end, _)| *start <= t && t < *end)
+            .map(|(_, _, segment)| segment.value(t)).unwrap();
+        for &(qid, low, up) in [(0, 0, 100), (0, 30, 250), (1, 0, 400), (2, 10, 1000)].iter() {
+            let sum: f64 = (low * 10..up * 10).map(|t| value(qid, t as f64 / 10.0 + 0.05) / 10.0).sum();
+            let exact = probs.integrate_over_range(qid, low, up) as f64;
+            assert!((sum - exact).abs() < 1e-2, "{} [{}, {}): {} {}", qid, low, up, sum, exact);
         }
 
-        p.abs()
+        // deadlines cut the integral
+        probs.set_deadline(0, 50);
+        assert_eq!(probs.integrate_over_range(0, 60, 100), 0.0);
     }
-}
 
+    #[test]
+    fn test_extrapolate_toward_prior() {
+        let mut probs = Prob::new(4);
+        probs.set_probs_at(indexmap!{0 => 1.0}, 0);
+        probs.blend_prior(&indexmap!{1 => 1.0}, 0.5);
+        probs.set_interpolation(Interpolation{mode: InterpolationMode::Exponential{half_life: 100.0}, horizon: 1000});
+
+        assert!((probs.get(0, 0) - 0.5).abs() < 1e-6);
+        assert!((probs.get(0, 100) - 0.25).abs() < 1e-6);
+        assert!((probs.get(1, 100) - 0.75).abs() < 1e-6);
+        assert_eq!(probs.get(1, 2000), 1.0);
+
+        let interpolation: Interpolation = serde_json::from_value(
+            serde_json::json!({"mode": {"Exponential": {"half_life": 100.0}}})).unwrap();
+        assert_eq!(interpolation.horizon, 500);
+    }
 
+    #[test]
+    fn test_mixture() {
+        let mut probs = Prob::new(10);
+        probs.set_probs_at(indexmap!{0 => 1.0}, 0);
+        // no component: only the predicted queries are explicit
+        assert_eq!(probs.get_k(), [0].iter().cloned().collect());
+
+        // likely one of three next clicks
+        probs.set_mixture(vec![(0.5, Component::Sparse(indexmap!{1 => 2.0, 2 => 1.0, 3 => 1.0})),
+                               (0.1, Component::Point(4)),
+                               (0.2, Component::Uniform)]).unwrap();
+        assert!((probs.get(0, 0) - (0.2 + 0.02)).abs() < 1e-6);
+        assert!((probs.get(1, 0) - (0.25 + 0.02)).abs() < 1e-6);
+        assert!((probs.get(4, 0) - (0.1 + 0.02)).abs() < 1e-6);
+        assert!((probs.get(9, 0) - 0.02).abs() < 1e-6);
+        assert!(probs.pinned().is_empty());
+        assert!(probs.pin(4) && !probs.pin(10));
+        assert_eq!(probs.pinned(), vec![4]);
+        assert_eq!(probs.get_k().len(), 5);
+        let total: f32 = (0..10).map(|k| probs.get(k, 0)).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+
+        // invalid mixtures leave it unchanged
+        assert!(matches!(probs.add_component(0.3, Component::Uniform), Err(MixtureError::WeightsExceedOne(_))));
+        assert_eq!(probs.set_mixture(vec![(-0.1, Component::Uniform)]), Err(MixtureError::InvalidWeight(-0.1)));
+        assert_eq!(probs.set_mixture(vec![(0.1, Component::Point(10))]),
+                   Err(MixtureError::QueryOutOfRange{query: 10, total_queries: 10}));
+        assert!(probs.set_mixture(vec![(0.1, Component::Sparse(indexmap!{1 => 0.0}))]).is_err());
+        assert_eq!(probs.get_mixture().len(), 3);
+
+        // as sent by a predictor, queries by key or index
+        let queries = indexmap!{"R1".to_owned() => 2, "R2".to_owned() => 2};
+        let components = serde_json::json!([{"weight": 0.3, "point": "R2"},
+                                            {"weight": 0.2, "sparse": {"R1": 1.0, "3": 1.0}},
+                                            {"weight": 0.1, "uniform": true}]);
+        assert_eq!(Component::parse_components(&components, &queries).unwrap(),
+                   vec![(0.3, Component::Point(1)), (0.2, Component::Sparse(indexmap!{0 => 1.0, 3 => 1.0})),
+                        (0.1, Component::Uniform)]);
+        assert!(Component::parse_components(&serde_json::json!([{"weight": 0.1, "point": "R9"}]), &queries).is_err());
+        assert!(Component::parse_components(&serde_json::json!([{"point": 1}]), &queries).is_err());
+    }
+
+    #[test]
+    fn test_no_queries() {
+        // an app without queries has an empty distribution
+        let mut probs = Prob::new(0);
+        assert_eq!(probs.get(0, 0), 0.0);
+        probs.set_probs_at(indexmap::IndexMap::new(), 0);
+        assert_eq!(probs.get(0, 0), 0.0);
+        assert_eq!(Component::Uniform.get_prob(0, 0), 0.0);
+        assert_eq!(probs.add_component(0.5, Component::Uniform), Err(MixtureError::NoQueries));
+        assert!(probs.get_mixture().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Registry of predictor decoders keyed by the model name in PredictorState.model.
+ *
+ * Apps register a decoder for each predictor model they accept; the state sent by
+ * the client is decoded into a Prob by the decoder registered for its model.
+ * States of models the app doesn't accept are reported back to the client
+ * instead of being decoded.
+ *
+ * Any state can also carry "components", mixed into the decoded distribution, see
+ * Component::parse_components.
+ */
+use crate::payload::Payload;
+use super::prob::{Prob, Component};
+use crate::ds;
+
+/// Decodes the data of a client side predictor into a distribution over queries
+pub trait PredictorDecoder: Send + Sync {
+    /// decode 'PredictorState.data', errors describe what is wrong with the data
+    fn decode(&mut self, data: &Payload) -> Result<Prob, String>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// no decoder is registered for the model
+    UnknownModel {
+        model: String,
+        accepted: Vec<String>,
+    },
+    /// the decoder rejected the data of the model
+    InvalidData {
+        model: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownModel{model, accepted} =>
+                write!(f, "unknown predictor model {:?}, accepted models: {:?}", model, accepted),
+            DecodeError::InvalidData{model, reason} =>
+                write!(f, "invalid data for predictor model {:?}: {}", model, reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Default)]
+pub struct DecoderRegistry {
+    decoders: indexmap::IndexMap<String, Box<dyn PredictorDecoder>>,
+    /// keys of the queries, components name queries by key or index
+    queries: indexmap::IndexMap<String, usize>,
+}
+
+impl DecoderRegistry {
+    pub fn new() -> Self {
+        DecoderRegistry{decoders: indexmap::IndexMap::new(), queries: indexmap::IndexMap::new()}
+    }
+
+    /// resolve the query keys of components with `queries`, e.g. the blocks per query
+    pub fn set_queries(&mut self, queries: indexmap::IndexMap<String, usize>) {
+        self.queries = queries;
+    }
+
+    /// accept states of `model`, replaces the decoder previously registered for it
+    pub fn register(&mut self, model: &str, decoder: Box<dyn PredictorDecoder>) {
+        self.decoders.insert(model.to_owned(), decoder);
+    }
+
+    /// names of the accepted models in registration order
+    pub fn models(&self) -> Vec<String> {
+        self.decoders.keys().cloned().collect()
+    }
+
+    pub fn accepts(&self, model: &str) -> bool {
+        self.decoders.contains_key(model.trim())
+    }
+
+    /// decode `state` with the decoder registered for its model, and mix in its components
+    ///
+    /// # Example
+    /// ```
+    /// use khameleon::{ds, scheduler};
+    ///
+    /// let blocks_per_query = indexmap::indexmap!{"R1".to_owned() => 1, "R2".to_owned() => 1};
+    /// let mut registry = scheduler::DecoderRegistry::new();
+    /// registry.register(scheduler::GAUSSIAN_MODEL, Box::new(scheduler::GaussianDecoder::new(blocks_per_query)));
+    ///
+    /// let state = ds::PredictorState::new("GM", serde_json::json!({
+    ///     "layout": {"R1": {"x": 0, "y": 0, "w": 10, "h": 10}, "R2": {"x": 10, "y": 0, "w": 10, "h": 10}},
+    ///     "dist": {"0": {"xmu": 5.0, "ymu": 5.0, "xsigma": 2.0, "ysigma": 2.0}},
+    /// }));
+    /// let probs = registry.decode(&state).unwrap();
+    /// assert!(probs.get(0, 0) > probs.get(1, 0));
+    /// ```
+    pub fn decode(&mut self, state: &ds::PredictorState) -> Result<Prob, DecodeError> {
+        let model = state.model.trim();
+        let accepted = self.models();
+        let invalid = |reason: String| DecodeError::InvalidData{model: model.to_owned(), reason: reason};
+        let mut probs = match self.decoders.get_mut(model) {
+            Some(decoder) => decoder.decode(&state.data).map_err(invalid)?,
+            None => return Err(DecodeError::UnknownModel{model: model.to_owned(), accepted: accepted}),
+        };
+
+        if let Some(components) = state.data.get("components") {
+            let components = Component::parse_components(&components, &self.queries).map_err(invalid)?;
+            probs.set_mixture(components).map_err(|e| invalid(e.to_string()))?;
+        }
+        Ok(probs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Uniform(usize);
+
+    impl PredictorDecoder for Uniform {
+        fn decode(&mut self, data: &Payload) -> Result<Prob, String> {
+            match data.to_json().is_object() {
+                true => Ok(Prob::new(self.0)),
+                false => Err("expected an object".to_owned()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_registry_decode() {
+        let mut registry = DecoderRegistry::new();
+        registry.register("U", Box::new(Uniform(2)));
+        assert!(registry.accepts(" U"));
+        assert_eq!(registry.models(), vec!["U".to_owned()]);
+
+        assert!(registry.decode(&ds::PredictorState::new("U", serde_json::json!({}))).is_ok());
+
+        registry.set_queries(indexmap!{"R1".to_owned() => 1, "R2".to_owned() => 1});
+        let state = ds::PredictorState::new("U", serde_json::json!({"components": [{"weight": 0.5, "point": "R2"}]}));
+        assert_eq!(registry.decode(&state).unwrap().get_mixture(), &[(0.5, Component::Point(1))][..]);
+        let state = ds::PredictorState::new("U", serde_json::json!({"components": [{"weight": 2.0, "uniform": true}]}));
+        assert!(registry.decode(&state).is_err());
+
+        match registry.decode(&ds::PredictorState::new("U", serde_json::json!([]))) {
+            Err(DecodeError::InvalidData{model, ..}) => assert_eq!(model, "U"),
+            res => panic!("expected invalid data {:?}", res.map(|_| ())),
+        }
+
+        let err = registry.decode(&ds::PredictorState::new("MM", serde_json::json!({}))).unwrap_err();
+        assert_eq!(err, DecodeError::UnknownModel{model: "MM".to_owned(), accepted: vec!["U".to_owned()]});
+        assert!(err.to_string().contains("MM"));
+    }
+}
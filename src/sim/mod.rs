@@ -0,0 +1,11 @@
+/*
+ * Offline trace-driven simulator.
+ *
+ * trace: load recorded predictor states and requests uploaded by the client.
+ * simulator: replay them against an app, a scheduler and a modelled network.
+ */
+pub mod trace;
+pub mod simulator;
+
+pub use trace::{TimedEvent, TraceEvent};
+pub use simulator::{run, Network, Report};
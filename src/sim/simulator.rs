@@ -0,0 +1,356 @@
+/*
+ * Replay a recorded session against an app, a scheduler and a modelled network.
+ *
+ * The simulator plays the role of both the scheduling/sender threads and the client:
+ * predictor states are decoded by the app and scheduled, blocks are put on a link with
+ * fixed bandwidth and latency, and arrive in a client cache that mirrors the
+ * CacheSimulator used by the scheduler. Requests are answered from the client cache.
+ */
+use super::trace::{TimedEvent, TraceEvent};
+use crate::apps;
+use crate::ds;
+use crate::manager::CacheSimulator;
+use crate::scheduler;
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Network {
+    /// megabits per second
+    pub bandwidth: f64,
+    /// round trip time in ms
+    pub latency: usize,
+}
+
+impl Network {
+    /// time in ms to put `bytes` on the link
+    pub fn transfer_ms(&self, bytes: usize) -> f64 {
+        let size_megabits = (bytes as f64 * 8.0) / (1024.0 * 1024.0);
+        (size_megabits / self.bandwidth) * 1000.0
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Report {
+    pub requests: usize,
+    pub hits: usize,
+    pub hit_rate: f64,
+    /// time from a request until the first block of the query is at the client
+    pub avg_latency_ms: f64,
+    /// utility of the cached blocks at request time, 0 on a miss
+    pub avg_utility: f64,
+    /// number of predictor states scheduled
+    pub rounds: usize,
+    /// number of predictor states the app couldn't decode
+    pub decode_errors: usize,
+    pub blocks_sent: usize,
+    /// mean of scheduler::evaluate over all plans
+    pub avg_expected_utility: f64,
+}
+
+/// map a request key to the query index used by the scheduler
+fn query_index(queries_blcount: &indexmap::IndexMap<String, usize>, key: &str) -> Option<usize> {
+    match queries_blcount.get_full(key) {
+        Some((index, _, _)) => Some(index),
+        None => match key.parse::<usize>() {
+            Ok(index) if index < queries_blcount.len() => Some(index),
+            _ => None,
+        },
+    }
+}
+
+/// Replay `events` and report cache hit rate, response latency and utility.
+///
+/// # Arguments
+///
+/// * `app` - app under test, used to decode predictor states and for its scheduler config.
+/// * `sched` - scheduler under test, should be created with the same `tm`.
+/// * `tm` - time manager shared with the scheduler.
+/// * `cachebytes` - client cache size in bytes, see `ds::AppState::cache_bytes`.
+/// * `network` - modelled link between server and client.
+/// * `prior_weight` - weight of the app's prior blended with each decoded distribution.
+/// * `interpolation` - interpolation of the decoded distributions, see `scheduler::Interpolation`.
+/// * `events` - recorded session sorted by time, see `sim::trace::load`.
+pub fn run(app: &mut dyn apps::AppTrait, mut sched: Box<dyn scheduler::SchedulerTrait>,
+           tm: Arc<RwLock<ds::TimeManager>>, cachebytes: usize, network: &Network,
+           prior_weight: f32, interpolation: scheduler::Interpolation,
+           events: &[TimedEvent]) -> Report {
+    let mut report = Report::default();
+    if events.is_empty() {
+        return report;
+    }
+
+    let (queries_blcount, _) = app.get_scheduler_config();
+    let blocks_per_query: Vec<usize> = queries_blcount.iter().map(|(_k, &v)| v).collect();
+    let total_queries = blocks_per_query.len();
+    let utility = app.get_utility_curves();
+    let utility_matrix = utility.utility_matrix(&blocks_per_query);
+
+    let block_sizes = app.get_block_sizes();
+    let block_size = block_sizes.unit();
+    let one_way_ms = network.latency as f64 / 2.0;
+    // a miss is fetched with a direct request for the first block
+    let direct_ms = |qid: usize| network.latency as f64 + network.transfer_ms(block_sizes.get(qid, 0));
+
+    match tm.write() {
+        Ok(mut tm) => {
+            tm.update_bandwidth(network.bandwidth);
+            tm.update_latency(network.latency);
+            tm.update_blocksize_megabits((block_size as f64 * 8.0) / (1024.0 * 1024.0));
+        }
+        Err(e) => error!("couldn't update time manager {:?}", e),
+    }
+
+    // cache as the scheduler sees it (updated when a block is sent)
+    // and as the client sees it (updated when a block arrives)
+    let mut server_cache = CacheSimulator::new(cachebytes, total_queries);
+    let mut client_cache = CacheSimulator::new(cachebytes, total_queries);
+    // (arrival time, qid, bytes) of blocks on the link
+    let mut in_flight: VecDeque<(f64, usize, usize)> = VecDeque::new();
+
+    // until the first predictor state, prefetch with the prior
+    let prior = app.get_prior();
+    let mut plan: Vec<usize> = match &prior {
+        Some(prior) => {
+            let mut probs = scheduler::Prob::new(total_queries);
+            probs.set_interpolation(interpolation);
+            probs.blend_prior(prior, 1.0);
+            let (cache_head, cache_state) = server_cache.get_state();
+            sched.run_scheduler(probs, cache_state, cache_head)
+        },
+        None => Vec::new(),
+    };
+    let mut plan_pos = 0;
+    // sim time after which blocks of a query are stale
+    let mut deadlines: HashMap<usize, f64> = HashMap::new();
+    // time the link is free to send the next block
+    let mut link_free: f64 = 0.0;
+
+    let mut latency_sum: f64 = 0.0;
+    let mut utility_sum: f64 = 0.0;
+    let mut expected_utility_sum: f64 = 0.0;
+
+    let t0 = events[0].time;
+    for e in events {
+        let now = (e.time - t0) as f64;
+
+        // 1) send the plan until the link catches up with the event
+        while link_free < now && plan_pos < plan.len() {
+            let qid = plan[plan_pos];
+            plan_pos += 1;
+            if qid >= total_queries || server_cache.get(qid) >= blocks_per_query[qid] {
+                continue;
+            }
+
+            let size = block_sizes.get(qid, server_cache.get(qid));
+            let transfer_ms = network.transfer_ms(size);
+            // the sender drops blocks that would arrive after the deadline
+            if let Some(&deadline) = deadlines.get(&qid) {
+                if link_free + transfer_ms + one_way_ms > deadline {
+                    continue;
+                }
+            }
+
+            server_cache.add(qid, size);
+            link_free += transfer_ms;
+            in_flight.push_back((link_free + one_way_ms, qid, size));
+            report.blocks_sent += 1;
+        }
+
+        if link_free < now {
+            // idle link
+            link_free = now;
+        }
+
+        // 2) deliver blocks that reached the client
+        while let Some(&(arrival, qid, size)) = in_flight.front() {
+            if arrival > now {
+                break;
+            }
+
+            client_cache.add(qid, size);
+            in_flight.pop_front();
+        }
+
+        // 3) replay the event
+        match &e.event {
+            TraceEvent::Predictor(state) => {
+                let decoded = app.decode_dist(state.clone());
+                // blocks of recycled ids belong to another frame
+                for qid in app.take_recycled_queries() {
+                    server_cache.forget(qid);
+                    client_cache.forget(qid);
+                    in_flight.retain(|&(_, q, _)| q != qid);
+                    deadlines.remove(&qid);
+                }
+                let probs = match decoded {
+                    Ok(mut probs) => {
+                        probs.set_interpolation(interpolation);
+                        if let Some(prior) = &prior {
+                            probs.blend_prior(prior, prior_weight);
+                        }
+                        probs
+                    },
+                    Err(err) => {
+                        error!("({}) {}", e.time, err);
+                        report.decode_errors += 1;
+                        continue;
+                    }
+                };
+                let (cache_head, cache_state) = server_cache.get_state();
+                let decision = sched.run_scheduler(probs.clone(), cache_state.clone(), cache_head);
+
+                let eval = scheduler::evaluate(&decision, &probs, &utility_matrix, &blocks_per_query,
+                                               &block_sizes, cache_state, &tm.read().unwrap());
+                debug!("({}) plan of {} blocks expected utility {:?}", e.time, decision.len(), eval.mean());
+                expected_utility_sum += eval.mean() as f64;
+                report.rounds += 1;
+
+                plan = decision;
+                plan_pos = 0;
+                deadlines = probs.get_k().into_iter()
+                    .filter_map(|qid| probs.get_deadline(qid).map(|d| (qid, now + d as f64)))
+                    .collect();
+            },
+            TraceEvent::Request(key) => {
+                report.requests += 1;
+                let qid = match query_index(&queries_blcount, key) {
+                    Some(qid) => qid,
+                    None => {
+                        error!("unknown query {:?}", key);
+                        latency_sum += network.latency as f64 + network.transfer_ms(block_size);
+                        continue;
+                    }
+                };
+
+                let incache = client_cache.get(qid);
+                if incache > 0 {
+                    report.hits += 1;
+                    utility_sum += utility.get(qid, incache) as f64;
+                } else {
+                    // wait for the first block on the link, or request it directly
+                    let latency = match in_flight.iter().find(|(_, q, _)| *q == qid) {
+                        Some(&(arrival, _, _)) => f64::min(arrival - now, direct_ms(qid)),
+                        None => direct_ms(qid),
+                    };
+                    latency_sum += latency;
+                }
+            },
+        }
+    }
+
+    if report.requests > 0 {
+        report.hit_rate = report.hits as f64 / report.requests as f64;
+        report.avg_latency_ms = latency_sum / report.requests as f64;
+        report.avg_utility = utility_sum / report.requests as f64;
+    }
+
+    if report.rounds > 0 {
+        report.avg_expected_utility = expected_utility_sum / report.rounds as f64;
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // all mass on query "a"
+    struct PointDecoder;
+
+    impl scheduler::PredictorDecoder for PointDecoder {
+        fn decode(&mut self, _data: &crate::payload::Payload) -> Result<scheduler::Prob, String> {
+            let mut prob = scheduler::Prob::new(2);
+            prob.set_probs_at(indexmap::indexmap!{0 => 1.0}, 0);
+            Ok(prob)
+        }
+    }
+
+    // two queries with two blocks each
+    struct PointApp {
+        decoders: scheduler::DecoderRegistry,
+        /// queries recycled at each decode
+        recycled: VecDeque<Vec<usize>>,
+    }
+
+    impl PointApp {
+        fn new() -> Self {
+            let mut decoders = scheduler::DecoderRegistry::new();
+            decoders.register("point", Box::new(PointDecoder));
+            PointApp{decoders: decoders, recycled: VecDeque::new()}
+        }
+    }
+
+    impl apps::AppTrait for PointApp {
+        fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+            (indexmap::indexmap!{"a".to_owned() => 2, "b".to_owned() => 2}, vec![0.5, 1.0])
+        }
+
+        fn get_decoders(&mut self) -> &mut scheduler::DecoderRegistry {
+            &mut self.decoders
+        }
+
+        fn take_recycled_queries(&mut self) -> Vec<usize> {
+            self.recycled.pop_front().unwrap_or_default()
+        }
+
+        fn get_block_size(&self) -> usize {
+            // 1 megabit
+            128 * 1024
+        }
+    }
+
+    #[test]
+    fn test_sim_replay() {
+        let tm = Arc::new(RwLock::new(ds::TimeManager::new(1, 0, 1.0)));
+        let cachebytes = 10 * 128 * 1024;
+        let sched = scheduler::new(&scheduler::SchedulerType::Greedy, 100, cachebytes,
+                                   scheduler::UtilityCurves::Shared(vec![0.5, 1.0]), vec![2, 2],
+                                   scheduler::BlockSizes::Fixed(128 * 1024), Some(tm.clone()));
+        // 100ms per block, 50ms one way
+        let network = Network{bandwidth: 10.0, latency: 100};
+        let state = ds::PredictorState::new("point", serde_json::json!({}));
+        let unknown = ds::PredictorState::new("GM", serde_json::json!({}));
+        let events = vec![
+            TimedEvent{time: 5000, event: TraceEvent::Predictor(state)},
+            // skipped, the plan keeps being sent
+            TimedEvent{time: 5005, event: TraceEvent::Predictor(unknown)},
+            // only the first block is on the link
+            TimedEvent{time: 5010, event: TraceEvent::Request("a".to_owned())},
+            // every block arrived
+            TimedEvent{time: 6000, event: TraceEvent::Request("a".to_owned())},
+        ];
+
+        let report = run(&mut PointApp::new(), sched, tm, cachebytes, &network, 0.0,
+                         scheduler::Interpolation::default(), &events);
+        assert_eq!(report.rounds, 1);
+        assert_eq!(report.decode_errors, 1);
+        assert_eq!(report.requests, 2);
+        assert_eq!(report.hits, 1);
+        assert_eq!(report.blocks_sent, 4);
+        assert_eq!(report.avg_utility, 0.5);
+        // the miss waits at most for a direct request
+        assert!(report.avg_latency_ms <= 100.0, "{:?}", report);
+
+        // "a" names another frame after the second state, its cached blocks don't count
+        let tm = Arc::new(RwLock::new(ds::TimeManager::new(1, 0, 1.0)));
+        let sched = scheduler::new(&scheduler::SchedulerType::Greedy, 100, cachebytes,
+                                   scheduler::UtilityCurves::Shared(vec![0.5, 1.0]), vec![2, 2],
+                                   scheduler::BlockSizes::Fixed(128 * 1024), Some(tm.clone()));
+        let state = ds::PredictorState::new("point", serde_json::json!({}));
+        let events = vec![
+            TimedEvent{time: 5000, event: TraceEvent::Predictor(state.clone())},
+            TimedEvent{time: 6000, event: TraceEvent::Request("a".to_owned())},
+            TimedEvent{time: 6100, event: TraceEvent::Predictor(state)},
+            TimedEvent{time: 6105, event: TraceEvent::Request("a".to_owned())},
+        ];
+        let mut app = PointApp::new();
+        app.recycled = vec![vec![], vec![0]].into_iter().collect();
+        let report = run(&mut app, sched, tm, cachebytes, &network, 0.0,
+                         scheduler::Interpolation::default(), &events);
+        assert_eq!((report.requests, report.hits), (2, 1));
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Load recorded sessions for the offline simulator.
+ *
+ * Two sources are supported, both as uploaded by the client and stored as the
+ * `data` of a backend::traces::TraceRecord (older files hold the data alone):
+ * session trace (/log/trace): list of {etype, e} events. "dist" events hold the
+ *                             predictor state sent to the server, "query" events
+ *                             hold the requests registered by the app.
+ * experiment events (/log/write): {"Query": [{query, dtime}, ...], ...}
+ */
+use crate::ds;
+
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TraceEvent {
+    /// predictor state received from the client
+    Predictor(ds::PredictorState),
+    /// request registered by the client for the query key
+    Request(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimedEvent {
+    /// client timestamp in ms
+    pub time: u64,
+    pub event: TraceEvent,
+}
+
+/// Parse a session trace written by the client's SystemLogger::writeTrace
+pub fn parse_session_trace(trace: &serde_json::Value) -> Result<Vec<TimedEvent>, String> {
+    let items = match trace.as_array() {
+        Some(items) => items,
+        None => return Err("session trace should be an array of events".to_owned()),
+    };
+
+    let mut events: Vec<TimedEvent> = Vec::new();
+    for item in items {
+        let etype = item["etype"].as_str().unwrap_or("");
+        let e = &item["e"];
+        match etype {
+            "dist" => {
+                let state: ds::PredictorState = match serde_json::from_value(e["data"].clone()) {
+                    Ok(state) => state,
+                    Err(err) => {
+                        error!("skip malformed predictor state {:?}: {:?}", e, err);
+                        continue;
+                    },
+                };
+                let time = e["time"].as_u64().unwrap_or(0);
+                events.push( TimedEvent{time: time, event: TraceEvent::Predictor(state)} );
+            },
+            "query" => {
+                match (e["query"].as_str(), e["time"].as_u64()) {
+                    (Some(query), Some(time)) => {
+                        events.push( TimedEvent{time: time, event: TraceEvent::Request(query.to_owned())} );
+                    },
+                    _ => error!("skip malformed query event {:?}", e),
+                }
+            },
+            // mouse points, layouts and app states are not needed for replay
+            _ => (),
+        }
+    }
+
+    Ok(events)
+}
+
+/// Parse the requests out of the experiment events written by the client's SystemLogger::writeEvents
+pub fn parse_experiment_events(events: &serde_json::Value) -> Result<Vec<TimedEvent>, String> {
+    let queries = match events["Query"].as_array() {
+        Some(queries) => queries,
+        None => return Err("experiment events don't contain any Query".to_owned()),
+    };
+
+    let mut requests: Vec<TimedEvent> = Vec::new();
+    for q in queries {
+        // queries are logged as strings by the apps, but allow numeric keys too
+        let query = match &q["query"] {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Number(n) => n.to_string(),
+            _ => {
+                error!("skip malformed query {:?}", q);
+                continue;
+            }
+        };
+
+        match q["dtime"].as_u64() {
+            Some(time) => requests.push( TimedEvent{time: time, event: TraceEvent::Request(query)} ),
+            None => error!("skip query without dtime {:?}", q),
+        }
+    }
+
+    Ok(requests)
+}
+
+/// the data of a stored record, the request body {data: ...}, or the data alone
+fn read_json(path: &str) -> Result<serde_json::Value, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+    let json: serde_json::Value = serde_json::from_reader(file).map_err(|e| format!("couldn't parse {}: {}", path, e))?;
+    match json.get("data") {
+        Some(data) => Ok(data.clone()),
+        None => Ok(json),
+    }
+}
+
+/// Load a session trace and, if it doesn't record queries, the requests from the
+/// experiment events of the same session into a single list of events sorted by time.
+pub fn load(trace_path: &str, events_path: Option<&str>) -> Result<Vec<TimedEvent>, String> {
+    let mut events = parse_session_trace(&read_json(trace_path)?)?;
+
+    // older traces don't record queries, use the experiment events instead
+    let has_requests = events.iter().any(|e| match e.event {
+        TraceEvent::Request(_) => true,
+        _ => false,
+    });
+
+    if let (Some(path), false) = (events_path, has_requests) {
+        let json = read_json(path)?;
+        events.append( &mut parse_experiment_events(&json)? );
+    }
+
+    // stable sort keeps the recorded order of events logged in the same ms
+    events.sort_by_key(|e| e.time);
+    Ok(events)
+}
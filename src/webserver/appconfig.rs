@@ -1,17 +1,21 @@
 use crate::manager;
+use crate::backend;
+use crate::ds;
+use crate::predictor;
+use crate::error::{Error, Result};
 
-use actix_web::{FromRequest, error, web, HttpRequest, HttpResponse, Result, Error};
+use actix_web::{FromRequest, web, HttpRequest, HttpResponse};
 use actix_session::{Session};
 use actix_web::http::{StatusCode};
 use actix_files as fs;
 use actix::prelude::*;
-use futures::{future::{ok as fut_ok}, Future};
+use futures::{future::{ok as fut_ok, result as fut_result}, Future};
 use actix_rt::spawn;
 use serde_derive::{Deserialize, Serialize};
 
 /// serve multi_index.html
 #[get("/")]
-fn index(session: Session, req: HttpRequest) -> Result<HttpResponse> {
+fn index(session: Session, req: HttpRequest) -> actix_web::Result<HttpResponse> {
     debug!("{:?}", req);
 
     let mut counter = 1;
@@ -30,22 +34,109 @@ fn index(session: Session, req: HttpRequest) -> Result<HttpResponse> {
 
 pub fn log_bandwidth_handle(srv: web::Data<Addr<manager::Manager>>,
                             msg: String) -> impl Future<Item = String, Error = Error> {
-    let stat: manager::SystemStat = serde_json::from_str(&msg).unwrap();
-    let actor_req = srv.send(stat);
-    actor_req.map_err(error::Error::from)
-             .and_then(|_| {
-                 fut_ok("done".to_owned())
-             })
+    fut_result(serde_json::from_str::<manager::SystemStat>(&msg))
+        .from_err()
+        .and_then(move |stat| srv.send(stat).from_err())
+        .and_then(|_| {
+            fut_ok("done".to_owned())
+        })
 }
 
 pub fn start_threads_handle(srv: web::Data<Addr<manager::Manager>>) -> impl Future<Item = String, Error = Error> {
     let actor_req = srv.send(manager::manager::StartThreads);
-    actor_req.map_err(error::Error::from)
+    actor_req.from_err()
+             .and_then(|res| res)
              .and_then(|_| {
                  fut_ok("done".to_owned())
              })
 }
 
+/// status of the scheduling and sender threads of the session, see manager::Supervisor
+pub fn pipeline_health_handle(srv: web::Data<Addr<manager::Manager>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::GetHealth)
+       .from_err()
+       .and_then(|health| {
+           let health = health.unwrap_or_default();
+           fut_ok( HttpResponse::Ok().json(serde_json::json!({
+               "healthy": health.healthy(),
+               "workers": health.workers,
+           })) )
+       })
+}
+
+
+/// how long /healthz and /readyz wait for the manager
+const STATUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// liveness: the manager actor answers, whatever the workers are doing
+pub fn healthz_handle(srv: web::Data<Addr<manager::Manager>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::Ping)
+       .timeout(STATUS_TIMEOUT)
+       .then(|res| match res {
+           Ok(app) => Ok( HttpResponse::Ok().json(serde_json::json!({"status": "ok", "app": app})) ),
+           Err(err) => {
+               error!("manager isn't responding: {}", err);
+               Ok( HttpResponse::ServiceUnavailable().json(serde_json::json!({"status": "unavailable",
+                                                                              "error": err.to_string()})) )
+           }
+       })
+}
+
+/// readiness: the manager answers, the app's backend is available and no thread failed
+/// for good. Ready before /initapp, the server then waits for an app
+pub fn readyz_handle(srv: web::Data<Addr<manager::Manager>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::GetStatus)
+       .timeout(STATUS_TIMEOUT)
+       .then(|res| match res {
+           Ok(status) if status.ready => Ok( HttpResponse::Ok().json(status) ),
+           Ok(status) => Ok( HttpResponse::ServiceUnavailable().json(status) ),
+           Err(err) => {
+               error!("manager isn't responding: {}", err);
+               Ok( HttpResponse::ServiceUnavailable().json(serde_json::json!({"ready": false,
+                                                                              "error": err.to_string()})) )
+           }
+       })
+}
+
+/// peer address and bearer token of an /admin request, see manager::admin::authorize.
+/// Requests forwarded by a proxy aren't local even if the proxy connects from loopback
+pub fn admin_caller(req: &HttpRequest) -> manager::admin::Caller {
+    let token = req.headers().get("Authorization")
+                   .and_then(|h| h.to_str().ok())
+                   .and_then(|h| if h.starts_with("Bearer ") { Some(h[7..].trim().to_owned()) } else { None });
+    let forwarded = req.headers().contains_key("X-Forwarded-For") || req.headers().contains_key("Forwarded");
+    let local = !forwarded && req.peer_addr().map_or(false, |addr| addr.ip().is_loopback());
+    manager::admin::Caller{local: local, token: token}
+}
+
+/// sessions with their app, cache model, plan and network, see manager::admin
+pub fn admin_sessions_handle(srv: web::Data<Addr<manager::Manager>>,
+                             req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::ListSessions{caller: admin_caller(&req)})
+       .from_err()
+       .and_then(|res| res)
+       .and_then(|sessions| fut_ok( HttpResponse::Ok().json(sessions) ))
+}
+
+pub fn admin_session_handle(srv: web::Data<Addr<manager::Manager>>, id: web::Path<usize>,
+                            req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::SteerSession{id: id.into_inner(), steer: manager::admin::Steer::default(),
+                                   caller: admin_caller(&req)})
+       .from_err()
+       .and_then(|res| res)
+       .and_then(|session| fut_ok( HttpResponse::Ok().json(session) ))
+}
+
+/// {"scheduler"?: "Greedy", "bandwidth_cap"?: 5.0, "paused"?: true}, see manager::admin::Steer
+pub fn admin_steer_handle(srv: web::Data<Addr<manager::Manager>>, id: web::Path<usize>,
+                          req: HttpRequest, msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
+    let (id, caller) = (id.into_inner(), admin_caller(&req));
+    fut_result(serde_json::from_str::<manager::admin::Steer>(&msg))
+        .from_err()
+        .and_then(move |steer| srv.send(manager::SteerSession{id: id, steer: steer, caller: caller}).from_err())
+        .and_then(|res| res)
+        .and_then(|session| fut_ok( HttpResponse::Ok().json(session) ))
+}
 
 pub fn log_latency_handle() -> Result<()> {
     Ok(())
@@ -53,47 +144,59 @@ pub fn log_latency_handle() -> Result<()> {
 
 pub fn direct_request(srv: web::Data<Addr<manager::Manager>>,
                       msg: String) -> impl Future<Item = String, Error = Error> {
-    let request: manager::Request = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("direct_request msg({:?}) error ({:?})", msg, err),
-    };
-    let actor_req = srv.send( request );
-
-    actor_req
-        .map_err(error::Error::from)
+    fut_result(serde_json::from_str::<manager::Request>(&msg))
+        .from_err()
+        .and_then(move |request| srv.send(request).from_err())
+        .and_then(|res| res)
         .and_then(|_| {
                 // get feedback from the app and pass it to the client
              fut_ok( "done".to_owned() )
         })
 }
 
+/// header of the /initapp response with the negotiated encoding of predictor states
+pub const ENCODING_HEADER: &str = "X-Predictor-Encoding";
+
 // todo: add a handler to handle layout updates
 pub fn init_app_handle(srv: web::Data<Addr<manager::Manager>>,
-                       msg: String) -> impl Future<Item = String, Error = Error> {
+                       msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
     // takes on msg as String and use Value to deserialize it
     let actor_req = srv.send(manager::InitApp{state: msg,});
     actor_req
-        .map_err(error::Error::from)
+        .from_err()
+        .and_then(|res| res)
         .and_then(|data| {
             info!("init app state {}", data.instance);
 
             if data.instance > 1 {
                 error!("one insrtance is already running: {}", data.instance);
                 // todo: find a way to clear and start without
-                // killing the app
-                System::current().stop();
-                panic!("one instance is already running");
+                // restarting the server
+                Err(Error::Conflict(format!("one instance is already running: {}", data.instance)))
             } else {
                 // get feedback from the app and pass it to the client
-                fut_ok( data.data )
+                Ok( HttpResponse::Ok()
+                    .header(ENCODING_HEADER, data.encoding.as_str())
+                    .body(data.data) )
             }
         })
 }
 
 /// https://docs.serde.rs/serde_json/enum.Value.html
 pub fn distribution_handle(srv: web::Data<Addr<manager::Manager>>, msg: String) -> Result<()> {
-    // takes on msg as String and use Value to deserialize it
-    let res = srv.send(manager::Distributions{data: msg,});
+    let state: ds::PredictorState = serde_json::from_str(&msg)?;
+    let res = srv.send(manager::DistUpdate{state: state,});
+    spawn(
+        res.map(|_| ()).map_err(|_| ()),
+    );
+
+    Ok(())
+}
+
+/// raw interaction events for sessions with a server side predictor, see predictor::Event
+pub fn events_handle(srv: web::Data<Addr<manager::Manager>>, msg: String) -> Result<()> {
+    predictor::Event::parse(&msg)?;
+    let res = srv.send(manager::Events{data: msg,});
     spawn(
         res.map(|_| ()).map_err(|_| ()),
     );
@@ -105,64 +208,73 @@ pub fn distribution_handle(srv: web::Data<Addr<manager::Manager>>, msg: String)
 struct TraceData {
     data: serde_json::Value,
     file: String,
+    #[serde(default)]
+    experiment: Option<String>,
 }
 
-pub fn logtrace_tofile_handle(msg: String) -> Result<HttpResponse> {
-    let datainfo: TraceData = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("logtrace_tofile_handle msg({:?}) error ({:?})", msg, err),
-    };
-
-    let path = "./traces/";
-
-    // create dir if it doesnt exist
-    std::fs::create_dir_all(&path)?;
-    
-    let path = format!("{}/{}", path, datainfo.file);
-
-    info!("log trace into: {:?}", path);
-
-    let path = std::path::Path::new( &path );
-    let display = path.display();
+/// store `data` under the experiment and the current session, see backend::traces
+fn save_trace(srv: web::Data<Addr<manager::Manager>>, store: web::Data<backend::traces::TraceStore>,
+              kind: backend::traces::TraceKind, experiment: Option<String>, name: String,
+              data: serde_json::Value) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::GetInstance)
+       .from_err()
+       .and_then(move |session| {
+           let experiment = experiment.unwrap_or_else(|| backend::traces::DEFAULT_EXPERIMENT.to_owned());
+           web::block(move || store.save(kind, &experiment, session, &name, data)).from_err()
+       })
+       .map(|entry| HttpResponse::Ok().json(entry))
+}
 
-    match std::fs::File::create(path) {
-        Ok(file) => {
-            serde_json::to_writer_pretty(file, &datainfo.data)?;
-            Ok(HttpResponse::Ok().finish())
-        },
-        Err(why) => {
-            error!("couldnt open {} {}", display, why);
-            Ok(HttpResponse::InternalServerError().body( format!("couldn't open {} {}", display, why) ))
-        }
-    }
+pub fn logtrace_tofile_handle(srv: web::Data<Addr<manager::Manager>>,
+                              store: web::Data<backend::traces::TraceStore>,
+                              msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
+    fut_result(serde_json::from_str::<TraceData>(&msg))
+        .from_err()
+        .and_then(move |datainfo| save_trace(srv, store, backend::traces::TraceKind::Trace,
+                                             datainfo.experiment, datainfo.file, datainfo.data))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ResultsData {
     data: serde_json::Value,
+    #[serde(default)]
+    experiment: Option<String>,
+    #[serde(default)]
+    file: Option<String>,
 }
 
-pub fn log_tofile_handle(msg: String) -> Result<HttpResponse> {
-    let datainfo: ResultsData = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("log_tofile_handle msg({:?}) error ({:?})", msg, err),
-    };
-
-    let path = "./log/exp_details.json";
-    info!("log_results {:?}", path);
-    let path = std::path::Path::new( &path );
-    let display = path.display();
-
-    match std::fs::File::create(path) {
-        Ok(file) => {
-            serde_json::to_writer_pretty(file, &datainfo.data)?;
-            Ok(HttpResponse::Ok().finish())
-        },
-        Err(why) => {
-            error!("couldnt open {} {}", display, why);
-            Ok(HttpResponse::InternalServerError().body( format!("couldn't open {} {}", display, why) ))
-        }
-    }
+pub fn log_tofile_handle(srv: web::Data<Addr<manager::Manager>>,
+                         store: web::Data<backend::traces::TraceStore>,
+                         msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
+    fut_result(serde_json::from_str::<ResultsData>(&msg))
+        .from_err()
+        .and_then(move |datainfo| {
+            let name = datainfo.file.unwrap_or_else(|| "exp_details".to_owned());
+            save_trace(srv, store, backend::traces::TraceKind::Events,
+                       datainfo.experiment, name, datainfo.data)
+        })
+}
+
+/// stored traces and events, see backend::traces
+pub fn traces_handle(srv: web::Data<Addr<manager::Manager>>, store: web::Data<backend::traces::TraceStore>,
+                     req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::AuthorizeAdmin{caller: admin_caller(&req)})
+       .from_err()
+       .and_then(|res| res)
+       .and_then(move |_| web::block(move || store.list()).from_err())
+       .map(|entries| HttpResponse::Ok().json(entries))
+}
+
+/// download a stored trace, as listed by /traces
+pub fn trace_handle(srv: web::Data<Addr<manager::Manager>>, store: web::Data<backend::traces::TraceStore>,
+                    path: web::Path<(String, usize, String)>,
+                    req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+    let (experiment, session, file) = path.into_inner();
+    srv.send(manager::AuthorizeAdmin{caller: admin_caller(&req)})
+       .from_err()
+       .and_then(|res| res)
+       .and_then(move |_| web::block(move || store.read(&experiment, session, &file)).from_err())
+       .map(|bytes| HttpResponse::Ok().content_type("application/json").body(bytes))
 }
 
 /// initialize handles
@@ -173,17 +285,23 @@ pub fn config_app(cfg: &mut web::ServiceConfig)
                   .data(String::configure(|g| {
                       g.limit(1024*1024*100)
                   }))
-                     .route(web::post().to(log_tofile_handle)))
+                     .route(web::post().to_async(log_tofile_handle)))
         .service(web::resource("/log/trace")
                   .data(String::configure(|g| {
                       g.limit(1024*1024*100)
                   }))
-                     .route(web::post().to(logtrace_tofile_handle)))
+                     .route(web::post().to_async(logtrace_tofile_handle)))
+        .service(web::resource("/traces")
+                     .route(web::get().to_async(traces_handle)))
+        .service(web::resource("/traces/{experiment}/{session}/{file}")
+                     .route(web::get().to_async(trace_handle)))
         .service(web::resource("/request")
                      .route(web::post().to_async(direct_request)))
         .service(index)
         .service(web::resource("/post_dist")
                      .route(web::post().to(distribution_handle)))
+        .service(web::resource("/post_events")
+                     .route(web::post().to(events_handle)))
         .service(web::resource("/initapp")
                   .data(String::configure(|g| {
                       g.limit(1024*1024*100)
@@ -195,6 +313,19 @@ pub fn config_app(cfg: &mut web::ServiceConfig)
                      .route(web::post().to_async(log_bandwidth_handle)))
         .service(web::resource("/start/threads")
                      .route(web::post().to_async(start_threads_handle)))
+        .service(web::resource("/healthz")
+                     .route(web::get().to_async(healthz_handle)))
+        .service(web::resource("/readyz")
+                     .route(web::get().to_async(readyz_handle)))
+        .service(web::resource("/health/pipeline")
+                     .route(web::get().to_async(pipeline_health_handle)))
+        .service(web::resource("/admin/sessions")
+                     .route(web::get().to_async(admin_sessions_handle)))
+        .service(web::resource("/admin/sessions/{id}")
+                     .route(web::get().to_async(admin_session_handle))
+                     .route(web::post().to_async(admin_steer_handle)))
+        .service(web::resource("/debug/ws/{session}")
+                     .route(web::get().to_async(super::debug::debug_ws_index)))
         .service(web::resource("/ws/")
                      .route(web::get().to(super::ws::ws_index)))
         .service(fs::Files::new("static", "client/static").show_files_listing());
@@ -0,0 +1,78 @@
+/// websocket streaming the debug events of a session as json text messages,
+/// see manager::debug and client/static/debug.html
+use crate::error;
+use crate::manager;
+
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use actix_web_actors::ws;
+// for the Actor primitive
+use actix::prelude::*;
+use futures::Future;
+
+pub struct DebugSocket {
+    pub addr: Addr<manager::Manager>,
+    pub session: usize,
+}
+
+impl Actor for DebugSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let addr = ctx.address();
+        self.addr.send(manager::SubscribeDebug{id: self.session, addr: addr.recipient()})
+                 .into_actor(self)
+                 .then(|res, act, ctx| {
+                     let err = match res {
+                         Ok(Ok(_)) => None,
+                         Ok(Err(err)) => Some(err.to_string()),
+                         Err(err) => Some(err.to_string()),
+                     };
+
+                     if let Some(err) = err {
+                         error!("couldn't subscribe to the debug events of session {}: {}", act.session, err);
+                         ctx.text(serde_json::json!({"error": err}).to_string());
+                         ctx.stop();
+                     }
+                     fut::ok(())
+                 }).wait(ctx);
+    }
+}
+
+impl Handler<manager::DebugMessage> for DebugSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: manager::DebugMessage, ctx: &mut Self::Context) {
+        match serde_json::to_string(&msg) {
+            Ok(text) => ctx.text(text),
+            Err(err) => error!("couldn't serialize debug event {:?}: {}", msg, err),
+        }
+
+        if msg.event == manager::DebugEvent::SessionEnded {
+            ctx.stop();
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for DebugSocket {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// /debug/ws/{session}, for the callers allowed under /admin, see manager::admin
+pub fn debug_ws_index(srv: web::Data<Addr<manager::Manager>>, session: web::Path<usize>,
+                      r: HttpRequest, stream: web::Payload) -> impl Future<Item = HttpResponse, Error = Error> {
+    let session = session.into_inner();
+    let caller = super::appconfig::admin_caller(&r);
+    srv.send(manager::AuthorizeDebug{id: session, caller: caller})
+       .map_err(|e| Error::from(error::Error::from(e)))
+       .and_then(|res| res.map_err(Error::from))
+       .and_then(move |_| {
+           info!("debug websocket for session {}", session);
+           ws::start(DebugSocket{addr: srv.get_ref().clone(), session: session}, &r, stream)
+       })
+}
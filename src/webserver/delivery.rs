@@ -0,0 +1,208 @@
+/*
+ * Block delivery logs: one csv row per block acked by the client, written by the
+ * websocket under {dir}/{experiment}/{session}/blocks-{n}.csv.
+ *
+ * Rows are buffered until the next tick, which the websocket runs every second. The
+ * log moves to the next file when the session changes, when the file reaches
+ * `max_bytes` or once it is older than `max_secs`, checked at each row and each tick;
+ * files of an earlier run with the same session id are never overwritten.
+ */
+use crate::backend::traces::{sanitize, DEFAULT_EXPERIMENT};
+use crate::error::Error;
+
+use serde_derive::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// files tried before giving up on a session directory
+const MAX_FILES: usize = 10000;
+
+/// block sent on the websocket and acked by the client, times in ms since the epoch
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeliveryRecord {
+    /// sequence number of the block
+    pub bid: u32,
+    pub key: String,
+    pub block_id: u32,
+    pub nblocks: u32,
+    /// size on the wire
+    pub bytes: usize,
+    /// "requested" for a /request, "scheduled" otherwise
+    pub source: &'static str,
+    /// sent
+    pub t1: u128,
+    /// acked
+    pub t2: u128,
+    pub delay: u128,
+    /// client time of the ack, 0 if the client didn't send it
+    pub client: u128,
+}
+
+/// config: {delivery_log_dir?: "./log/delivery", delivery_log_max_bytes?: 10 MiB,
+///          delivery_log_max_secs?: 3600}, 0 disables a limit
+#[derive(Clone, Debug)]
+pub struct DeliveryLogConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub max_secs: u64,
+}
+
+impl DeliveryLogConfig {
+    pub fn from_config(config: &serde_json::Value) -> Self {
+        let dir = config["delivery_log_dir"].as_str().unwrap_or("./log/delivery");
+        let max_bytes = match config["delivery_log_max_bytes"].as_u64() {
+            Some(max_bytes) => max_bytes,
+            None => 10 * 1024 * 1024,
+        };
+        let max_secs = match config["delivery_log_max_secs"].as_u64() {
+            Some(max_secs) => max_secs,
+            None => 3600,
+        };
+
+        DeliveryLogConfig{dir: dir.into(), max_bytes: max_bytes, max_secs: max_secs}
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    session: usize,
+    opened: Instant,
+    file: std::io::BufWriter<std::fs::File>,
+    /// written so far, header included
+    bytes: u64,
+}
+
+impl LogFile {
+    fn expired(&self, max_secs: u64) -> bool {
+        max_secs > 0 && self.opened.elapsed().as_secs() >= max_secs
+    }
+}
+
+/// delivery log of a websocket, opened at its first row
+pub struct DeliveryLog {
+    config: DeliveryLogConfig,
+    experiment: String,
+    current: Option<LogFile>,
+}
+
+impl DeliveryLog {
+    pub fn new(config: DeliveryLogConfig, experiment: Option<&str>) -> Self {
+        let experiment = sanitize(experiment.unwrap_or(DEFAULT_EXPERIMENT), DEFAULT_EXPERIMENT);
+        DeliveryLog{config: config, experiment: experiment, current: None}
+    }
+
+    /// file being written, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|f| f.path.as_path())
+    }
+
+    pub fn write(&mut self, session: usize, record: &DeliveryRecord) -> Result<(), Error> {
+        let rotate = match &self.current {
+            Some(f) => f.session != session
+                       || (self.config.max_bytes > 0 && f.bytes >= self.config.max_bytes)
+                       || f.expired(self.config.max_secs),
+            None => true,
+        };
+        if rotate {
+            self.rotate(session)?;
+        }
+
+        match &mut self.current {
+            Some(f) => {
+                // the header goes before the first row of the file
+                let mut writer = csv::WriterBuilder::new().has_headers(f.bytes == 0).from_writer(Vec::new());
+                writer.serialize(record).map_err(std::io::Error::from)?;
+                let row = writer.into_inner().map_err(|e| e.into_error())?;
+                f.file.write_all(&row)?;
+                f.bytes += row.len() as u64;
+                Ok(())
+            },
+            None => Err(Error::Internal("no delivery log is open".to_owned())),
+        }
+    }
+
+    /// write out the buffered rows, and close the file once it is older than
+    /// `max_secs` so that an idle session rotates too
+    pub fn tick(&mut self) -> Result<(), Error> {
+        match &mut self.current {
+            Some(f) if f.expired(self.config.max_secs) => self.close(),
+            Some(f) => Ok(f.file.flush()?),
+            None => Ok(()),
+        }
+    }
+
+    /// write out the buffered rows and close the file, the next row opens a new one
+    pub fn close(&mut self) -> Result<(), Error> {
+        match self.current.take() {
+            Some(mut f) => Ok(f.file.flush()?),
+            None => Ok(()),
+        }
+    }
+
+    /// close the current file and open the next free one of `session`
+    fn rotate(&mut self, session: usize) -> Result<(), Error> {
+        self.close()?;
+
+        let dir = self.config.dir.join(&self.experiment).join(session.to_string());
+        std::fs::create_dir_all(&dir)?;
+        for n in 0..MAX_FILES {
+            let path = dir.join(format!("blocks-{}.csv", n));
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    info!("log block delivery into {:?}", path);
+                    self.current = Some(LogFile{path: path, session: session, opened: Instant::now(),
+                                                file: std::io::BufWriter::new(file), bytes: 0});
+                    return Ok(());
+                },
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+
+        Err(Error::Conflict(format!("too many delivery logs in {:?}", dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_log_rotation() {
+        let dir = std::env::temp_dir().join(format!("khameleon-delivery-{}", std::process::id()));
+        let config = DeliveryLogConfig{dir: dir.clone(), max_bytes: 200, max_secs: 0};
+        let record = DeliveryRecord{bid: 1, key: "R1".to_owned(), block_id: 0, nblocks: 2, bytes: 1024,
+                                    source: "scheduled", t1: 10, t2: 25, delay: 15, client: 0};
+
+        let mut log = DeliveryLog::new(config.clone(), Some("exp 1"));
+        log.write(1, &record).unwrap();
+        assert_eq!(log.path(), Some(dir.join("exp_1").join("1").join("blocks-0.csv").as_path()));
+        // the header and 5 rows reach 200 bytes
+        for _ in 0..4 {
+            log.write(1, &record).unwrap();
+        }
+        log.write(1, &record).unwrap();
+        assert_eq!(log.path(), Some(dir.join("exp_1").join("1").join("blocks-1.csv").as_path()));
+        log.write(2, &record).unwrap();
+        assert_eq!(log.path(), Some(dir.join("exp_1").join("2").join("blocks-0.csv").as_path()));
+        // a tick writes the rows out and keeps the file open
+        log.tick().unwrap();
+        let rows = std::fs::read_to_string(dir.join("exp_1").join("2").join("blocks-0.csv")).unwrap();
+        assert_eq!(rows.lines().count(), 2);
+        assert!(log.path().is_some());
+        log.close().unwrap();
+        assert_eq!(log.path(), None);
+
+        let rows = std::fs::read_to_string(dir.join("exp_1").join("1").join("blocks-0.csv")).unwrap();
+        assert_eq!(rows.lines().next(), Some("bid,key,block_id,nblocks,bytes,source,t1,t2,delay,client"));
+        assert_eq!(rows.lines().nth(1), Some("1,R1,0,2,1024,scheduled,10,25,15,0"));
+
+        // a new run with the same session id keeps the earlier logs
+        let mut log = DeliveryLog::new(config, Some("exp 1"));
+        log.write(2, &record).unwrap();
+        assert_eq!(log.path(), Some(dir.join("exp_1").join("2").join("blocks-1.csv").as_path()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod appconfig;
 pub mod ws;
+pub mod delivery;
+pub mod debug;
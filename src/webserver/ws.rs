@@ -1,10 +1,11 @@
 /// local imports
 use crate::ds;
 use crate::manager;
+use crate::payload;
+use super::delivery;
 
 /// public lib
-use serde_derive::{Serialize};
-use csv::Writer;
+use serde_derive::Deserialize;
 use std::collections::HashMap;
 use actix_web::{web, HttpRequest, HttpResponse, Error, Result};
 use actix_web_actors::ws;
@@ -13,13 +14,98 @@ use crossbeam_utils::atomic::AtomicCell;
 // for the Actor primitive
 use actix::prelude::*;
 
-#[derive(Serialize)]
-struct BlockDelays {
-    bid: u32,
-    delay: u128,
-    t1: u128,
-    t2: u128,
-    client: u128,
+/// block sent on the websocket, waiting for its ack
+pub struct SentBlock {
+    pub time: u128,
+    pub key: String,
+    pub block_id: u32,
+    pub nblocks: u32,
+    pub bytes: usize,
+    pub requested: bool,
+}
+
+/// query of /ws/: ?experiment=..
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    #[serde(default)]
+    pub experiment: Option<String>,
+}
+
+/// typed messages the client sends on the websocket instead of posting them.
+///
+/// text: {"type": "Dist", "model": ..., "data": ...} or {"type": "Stats", "bw": ..., "latency": ...}
+/// binary, little endian: a kind byte then
+///   1 (Dist): u16 model length, model, predictor data as json
+///   2 (Stats): f64 bandwidth, u32 latency
+///   3 (Dist): u16 model length, model, predictor data encoded with payload
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// predictor state, same as POST /post_dist
+    Dist(ds::PredictorState),
+    /// network conditions, same as POST /log/bandwidth
+    Stats(manager::SystemStat),
+}
+
+/// how often buffered delivery rows are written out
+const DELIVERY_LOG_TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// kinds of binary client messages
+const BINARY_DIST: u8 = 1;
+const BINARY_STATS: u8 = 2;
+const BINARY_DIST_COMPACT: u8 = 3;
+
+impl ClientMessage {
+    /// text messages typed "Dist" or "Stats", other json messages are interaction events
+    pub fn parse(text: &str) -> Option<Result<ClientMessage, serde_json::Error>> {
+        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+        match value["type"].as_str() {
+            Some("Dist") | Some("Stats") => Some(serde_json::from_value(value)),
+            _ => None,
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ClientMessage, String> {
+        let truncated = || format!("truncated message of {} bytes", bytes.len());
+        match bytes.first() {
+            Some(&kind) if kind == BINARY_DIST || kind == BINARY_DIST_COMPACT => {
+                if bytes.len() < 3 {
+                    return Err(truncated());
+                }
+                let model_len = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+                if bytes.len() < 3 + model_len {
+                    return Err(truncated());
+                }
+                let model = std::str::from_utf8(&bytes[3..3 + model_len]).map_err(|e| e.to_string())?;
+                let data = &bytes[3 + model_len..];
+                let data = match kind {
+                    BINARY_DIST => payload::Payload::Json(serde_json::from_slice(data).map_err(|e| e.to_string())?),
+                    _ => payload::decode_payload(data)?,
+                };
+                Ok(ClientMessage::Dist(ds::PredictorState::new(model, data)))
+            },
+            Some(&BINARY_STATS) => {
+                if bytes.len() < 13 {
+                    return Err(truncated());
+                }
+                let mut bw = [0u8; 8];
+                bw.copy_from_slice(&bytes[1..9]);
+                let latency = u32::from_le_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]);
+                Ok(ClientMessage::Stats(manager::SystemStat{bw: f64::from_le_bytes(bw), latency: latency}))
+            },
+            Some(kind) => Err(format!("unknown message kind {}", kind)),
+            None => Err(truncated()),
+        }
+    }
+}
+
+impl WebSocket {
+    fn forward(&self, msg: ClientMessage) {
+        match msg {
+            ClientMessage::Dist(state) => self.addr.do_send(manager::DistUpdate{state: state}),
+            ClientMessage::Stats(stat) => self.addr.do_send(stat),
+        }
+    }
 }
 
 impl Handler<ds::StreamBlock> for WebSocket {
@@ -27,11 +113,9 @@ impl Handler<ds::StreamBlock> for WebSocket {
 
     fn handle(&mut self, block: ds::StreamBlock, ctx: &mut Self::Context) {
         match block {
-            ds::StreamBlock::Binary(x) => {
-                let mut bytebuffer: Vec<u8> = Vec::new();
-                
-                // metadata attached to each block to help track their rrt
-                let bid: u32 = {
+            ds::StreamBlock::Block(mut block) => {
+                // sequence number of the block to help track their rrt
+                block.header.seq = {
                     let timestamp: u128 = {
                         let now = std::time::SystemTime::now();
                         let since_the_epoch = now.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
@@ -41,15 +125,25 @@ impl Handler<ds::StreamBlock> for WebSocket {
                     self.block_counter += 1;
 
                     self.last_timestamp = timestamp;
-                    self.blocks_tracker.insert( self.block_counter, timestamp );
+                    self.blocks_tracker.insert( self.block_counter, SentBlock{time: timestamp, key: block.header.key.clone(),
+                                                                              block_id: block.header.block_id,
+                                                                              nblocks: block.header.nblocks,
+                                                                              bytes: block.len(),
+                                                                              requested: block.requested} );
                     self.block_counter
                 };
-                let mut bid = bincode::serialize(&bid).unwrap();
-                
-                bytebuffer.append(&mut bid);
-                bytebuffer.extend( x );
-                ctx.binary(bytebuffer)
+
+                if let Some(debug) = &self.debug {
+                    debug.emit(|| manager::DebugEvent::BlockSent{seq: block.header.seq, key: block.header.key.clone(),
+                                                                block_id: block.header.block_id,
+                                                                nblocks: block.header.nblocks, bytes: block.len()});
+                }
+                ctx.binary(block.encode())
+            },
+            ds::StreamBlock::Error(msg) => {
+                ctx.text(serde_json::json!({"error": msg}).to_string())
             },
+            ds::StreamBlock::Session(session) => self.session = session,
             ds::StreamBlock::Stop => ctx.stop()
         }
 
@@ -60,10 +154,14 @@ pub struct WebSocket {
     /// Stream Server address
     pub addr: Addr<manager::Manager>,
     pub block_counter: u32,
-    pub blocks_tracker: HashMap<u32, u128>,
-    pub writer: Writer<std::fs::File>,
+    pub blocks_tracker: HashMap<u32, SentBlock>,
+    pub delivery: delivery::DeliveryLog,
     pub congestion: Arc<AtomicCell<u128>>,
     pub last_timestamp: u128,
+    /// debug events of the session, set once connected to the manager
+    pub debug: Option<manager::DebugChannel>,
+    /// session of the manager, for the delivery log
+    pub session: usize,
 }
 
 impl Actor for WebSocket {
@@ -74,18 +172,34 @@ impl Actor for WebSocket {
         let addr = ctx.address();
         self.addr.send(manager::Connect{ws_addr: addr.recipient(), congestion: self.congestion.clone()})
                  .into_actor(self)
-                 .then(|res, _, ctx| {
+                 .then(|res, act, ctx| {
                      // pass on the laten
                      match res {
-                          Ok(_) => info!("successfully initialized ws"),
+                          Ok(connected) => {
+                              info!("successfully initialized ws");
+                              act.debug = Some(connected.debug);
+                              act.session = connected.session;
+                          },
                           // something is wrong with server
-                          _ => {
+                          Err(err) => {
+                              error!("websocket initialization error: {}", err);
                               ctx.stop();
-                              panic!("websocket initialization error");
                           }
                      }
                      fut::ok(())
                  }).wait(ctx);
+
+        ctx.run_interval(DELIVERY_LOG_TICK, |act, _| {
+            if let Err(e) = act.delivery.tick() {
+                error!("couldn't write the delivery log: {}", e);
+            }
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Err(e) = self.delivery.close() {
+            error!("couldn't write the delivery log: {}", e);
+        }
     }
 }
 
@@ -98,44 +212,51 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocket {
                 ctx.pong(&msg);
             }
             ws::Message::Pong(_) => (),
+            // predictor states and stats, or raw interaction events for the server side predictor
+            ws::Message::Text(ref text) if text.starts_with('{') || text.starts_with('[') => {
+                match ClientMessage::parse(text) {
+                    Some(Ok(msg)) => self.forward(msg),
+                    Some(Err(err)) => error!("invalid message {:?}: {}", text, err),
+                    None => self.addr.do_send(manager::Events{data: text.clone()}),
+                }
+            },
             ws::Message::Text(text) => {
-                let lines = text.split_whitespace();
-                let nums: Vec<&str> = lines.collect();
-                let bid = nums[0];
-                let client_timestamp = { 
-                    match nums.len() == 2 {
-                        true => nums[1].parse::<u128>().unwrap(),
-                        false => 0
-                    }
-                };
-
-                match bid.parse::<u32>() {
-                    Ok(n) => {
-                        match self.blocks_tracker.get( &n ) {
-                            Some(&t1) => {
+                match parse_ack(&text) {
+                    Some((n, client_timestamp)) => {
+                        match self.blocks_tracker.remove( &n ) {
+                            Some(sent) => {
+                                let t1 = sent.time;
                                 let t2: u128 = {
                                     let now = std::time::SystemTime::now();
                                     let since_the_epoch = now.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
                                     since_the_epoch.as_millis() as u128
                                 };
 
-                                let delay = t2 - t1;
+                                // the clocks may disagree
+                                let delay = t2.saturating_sub(t1);
                                 self.congestion.store( delay );
-                                match self.writer.serialize( BlockDelays {bid: n, delay: delay, t1: t1, t2: t2, client: client_timestamp} ) {
-                                    Ok(_) => (),
-                                    Err(e) => println!("writing to writer: {:?}", e),
+                                if let Some(debug) = &self.debug {
+                                    debug.emit(|| manager::DebugEvent::Ack{seq: n, rtt_ms: delay});
+                                }
+                                let record = delivery::DeliveryRecord{bid: n, key: sent.key, block_id: sent.block_id,
+                                                                      nblocks: sent.nblocks, bytes: sent.bytes,
+                                                                      source: if sent.requested { "requested" } else { "scheduled" },
+                                                                      t1: t1, t2: t2, delay: delay, client: client_timestamp};
+                                if let Err(e) = self.delivery.write(self.session, &record) {
+                                    error!("couldn't write the delivery log: {}", e);
                                 }
-                                let _ = self.writer.flush();
                             },
                             None => error!("no matching timestamp in blocks tracker {:?}", n),
                         }
                     },
-                    Err(_) => error!("something wrong with the received block index {:?}", bid),
+                    None => error!("invalid ack {:?}", text),
                 }
-                
             },
             ws::Message::Binary(bin) => {
-                info!("Received bin: {:?}", bin);
+                match ClientMessage::decode(&bin) {
+                    Ok(msg) => self.forward(msg),
+                    Err(err) => error!("invalid binary message: {}", err),
+                }
             },
             ws::Message::Close(_) => {
                 ctx.stop();
@@ -145,17 +266,78 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocket {
     }
 }
 
-pub fn ws_index(srv: web::Data<Addr<manager::Manager>>, r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+pub fn ws_index(srv: web::Data<Addr<manager::Manager>>, logs: web::Data<delivery::DeliveryLogConfig>,
+                params: web::Query<WsParams>, r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
     info!("Initialize websocket header: {:?}", r);
     
-    let fname = format!("./log/block_details.csv");
-    let wtr = Writer::from_path(fname).unwrap();
+    let log = delivery::DeliveryLog::new(logs.get_ref().clone(), params.experiment.as_ref().map(|e| e.as_str()));
     let congestion = Arc::new(AtomicCell::new(0));
     let websocket = WebSocket{ addr: srv.get_ref().clone() , block_counter: 0,
                                blocks_tracker: HashMap::new(),
-                               writer: wtr, congestion: congestion, last_timestamp: 0};
+                               delivery: log, congestion: congestion, last_timestamp: 0, debug: None, session: 0};
     let res = ws::start(websocket, &r, stream);
 
-    info!("ws session header response: {:?}", res.as_ref().unwrap());
+    info!("ws session header response: {:?}", res);
     res
 }
+
+/// ack of a block: "{seq}" or "{seq} {client time in ms}", None if malformed
+fn parse_ack(text: &str) -> Option<(u32, u128)> {
+    let mut fields = text.split_whitespace();
+    let seq = fields.next()?.parse::<u32>().ok()?;
+    let client_timestamp = match fields.next() {
+        Some(t) => t.parse::<u128>().ok()?,
+        None => 0,
+    };
+    match fields.next() {
+        Some(_) => None,
+        None => Some((seq, client_timestamp)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ack() {
+        assert_eq!(parse_ack("12"), Some((12, 0)));
+        assert_eq!(parse_ack("12 1600000000000"), Some((12, 1600000000000)));
+        assert_eq!(parse_ack(""), None);
+        assert_eq!(parse_ack("   "), None);
+        assert_eq!(parse_ack("garbage"), None);
+        assert_eq!(parse_ack("12 garbage"), None);
+        assert_eq!(parse_ack("-1 5"), None);
+        assert_eq!(parse_ack("12 5 7"), None);
+    }
+
+    #[test]
+    fn test_client_messages() {
+        let msg = ClientMessage::parse(r#"{"type": "Dist", "model": "GM", "data": {"dist": {}}}"#);
+        assert_eq!(msg.unwrap().unwrap(),
+                   ClientMessage::Dist(ds::PredictorState::new("GM", serde_json::json!({"dist": {}}))));
+        let msg = ClientMessage::parse(r#"{"type": "Stats", "bw": 10.0, "latency": 50, "payload_size": 0}"#);
+        assert_eq!(msg.unwrap().unwrap(), ClientMessage::Stats(manager::SystemStat{bw: 10.0, latency: 50}));
+        assert!(ClientMessage::parse(r#"{"type": "Dist"}"#).unwrap().is_err());
+        // interaction events
+        assert!(ClientMessage::parse(r#"{"type": "Mouse", "x": 1, "y": 2, "time": 3}"#).is_none());
+
+        let mut bytes = vec![BINARY_DIST, 2, 0];
+        bytes.extend_from_slice(b"MM{\"tick\": 3}");
+        assert_eq!(ClientMessage::decode(&bytes),
+                   Ok(ClientMessage::Dist(ds::PredictorState::new("MM", serde_json::json!({"tick": 3})))));
+
+        let mut bytes = vec![BINARY_STATS];
+        bytes.extend_from_slice(&2.5f64.to_le_bytes());
+        bytes.extend_from_slice(&80u32.to_le_bytes());
+        assert_eq!(ClientMessage::decode(&bytes), Ok(ClientMessage::Stats(manager::SystemStat{bw: 2.5, latency: 80})));
+        assert!(ClientMessage::decode(&bytes[..5]).is_err());
+
+        let mut bytes = vec![BINARY_DIST_COMPACT, 2, 0];
+        bytes.extend_from_slice(b"MM");
+        bytes.extend_from_slice(&payload::encode_payload(&[("tick", payload::Field::Int(3))]));
+        assert_eq!(ClientMessage::decode(&bytes),
+                   Ok(ClientMessage::Dist(ds::PredictorState::new("MM", serde_json::json!({"tick": 3})))));
+        assert!(ClientMessage::decode(&[9]).is_err());
+    }
+}